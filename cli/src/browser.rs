@@ -0,0 +1,204 @@
+use crate::playlist::{self, Format};
+use crossterm::event::KeyCode;
+use std::{
+    cmp::Ordering,
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use walkdir::{DirEntry, WalkDir};
+
+/// File browser listing directories, LILAC files and playlists
+pub struct Browser {
+    pub dir: PathBuf,
+    pub entries: Vec<BrowserEntry>,
+    pub selected: usize,
+}
+pub struct BrowserEntry {
+    pub name: String,
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+pub enum BrowserInput {
+    Ignored,
+    Handled,
+    Close,
+    /// Entries to add to the queue, see `expand`
+    Enqueue(Vec<PathBuf>),
+}
+
+impl Browser {
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        let mut browser = Self {
+            dir,
+            entries: Vec::new(),
+            selected: 0,
+        };
+        browser.read()?;
+        Ok(browser)
+    }
+
+    /// Handles a key press, `Enqueue` being returned for files and directories to add
+    ///
+    /// Entries aren't expanded here since walking large directories would block the caller.
+    pub fn input(&mut self, code: KeyCode) -> io::Result<BrowserInput> {
+        match code {
+            KeyCode::Esc | KeyCode::Char('b') => return Ok(BrowserInput::Close),
+            KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down => {
+                self.selected = (self.selected + 1).min(self.entries.len().saturating_sub(1))
+            }
+            KeyCode::Left | KeyCode::Backspace => {
+                if let Some(parent) = self.dir.parent().map(Path::to_owned) {
+                    let previous = self.dir.clone();
+                    self.dir = parent;
+                    self.read()?;
+                    self.selected = self
+                        .entries
+                        .iter()
+                        .position(|e| e.path == previous)
+                        .unwrap_or(0);
+                }
+            }
+            KeyCode::Right | KeyCode::Enter => match self.entries.get(self.selected) {
+                Some(e) if e.is_dir => {
+                    self.dir = e.path.clone();
+                    self.read()?;
+                    self.selected = 0;
+                }
+                Some(e) if code == KeyCode::Enter => {
+                    return Ok(BrowserInput::Enqueue(vec![e.path.clone()]))
+                }
+                _ => (),
+            },
+            KeyCode::Char('a') => {
+                if let Some(e) = self.entries.get(self.selected) {
+                    return Ok(BrowserInput::Enqueue(vec![e.path.clone()]));
+                }
+            }
+            _ => return Ok(BrowserInput::Ignored),
+        }
+        Ok(BrowserInput::Handled)
+    }
+
+    fn read(&mut self) -> io::Result<()> {
+        self.dir = self.dir.canonicalize()?;
+        self.entries = list(&self.dir)?
+            .into_iter()
+            .map(|(path, is_dir)| BrowserEntry {
+                name: path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                path,
+                is_dir,
+            })
+            .collect();
+        Ok(())
+    }
+}
+
+/// Files to enqueue for an entry, directories being walked recursively
+///
+/// Symbolic links are followed, except the ones leading back to a parent directory.
+pub fn expand(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if path.is_dir() {
+        let mut files = Vec::new();
+        let entries = WalkDir::new(path)
+            .follow_links(true)
+            .sort_by(|a, b| order((a.path(), is_dir(a)), (b.path(), is_dir(b))))
+            .into_iter()
+            .filter_entry(|e| e.depth() == 0 || !is_hidden(e.file_name()));
+        for e in entries {
+            let e = match e {
+                Ok(e) => e,
+                Err(e) if e.loop_ancestor().is_some() => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if e.file_type().is_file() && is_lilac(e.path()) {
+                files.push(e.into_path());
+            }
+        }
+        Ok(files)
+    } else if Format::from_path(path).is_some() {
+        playlist::read(path)
+            .map(|entries| entries.into_iter().map(|e| e.path).collect())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:#}", e)))
+    } else {
+        Ok(vec![path.to_owned()])
+    }
+}
+
+/// Sorted directories and playable files, hidden ones excluded
+fn list(dir: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+    let mut entries = Vec::new();
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        let path = e.path();
+        if is_hidden(&e.file_name()) {
+            continue;
+        }
+
+        let is_dir = path.is_dir();
+        if is_dir || is_lilac(&path) || Format::from_path(&path).is_some() {
+            entries.push((path, is_dir));
+        }
+    }
+
+    entries.sort_by(|(a, a_dir), (b, b_dir)| order((a, *a_dir), (b, *b_dir)));
+    Ok(entries)
+}
+
+/// Directories first, then by name
+fn order(a: (&Path, bool), b: (&Path, bool)) -> Ordering {
+    b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0))
+}
+
+fn is_dir(e: &DirEntry) -> bool {
+    e.file_type().is_dir()
+}
+
+fn is_hidden(name: &OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+fn is_lilac(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("lilac"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_directories() {
+        let dir = std::env::temp_dir().join(format!("lilac-browser-{}", std::process::id()));
+        for d in &["b", "a/c", ".hidden"] {
+            fs::create_dir_all(dir.join(d)).unwrap();
+        }
+        for f in &[
+            "z.lilac",
+            "b/y.LILAC",
+            "a/c/x.lilac",
+            ".hidden/w.lilac",
+            "a/v.m3u",
+            "a/u.txt",
+        ] {
+            fs::write(dir.join(f), "").unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("a/c/loop")).unwrap();
+
+        let files = expand(&dir).unwrap();
+        let expected: Vec<_> = ["a/c/x.lilac", "b/y.LILAC", "z.lilac"]
+            .iter()
+            .map(|f| dir.join(f))
+            .collect();
+        assert_eq!(files, expected);
+        assert_eq!(expand(&dir.join("z.lilac")).unwrap(), [dir.join("z.lilac")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
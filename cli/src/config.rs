@@ -0,0 +1,450 @@
+use crate::{
+    pattern::{Pattern, Sanitize},
+    transcode::Target,
+};
+use anyhow::{bail, Context};
+use crossterm::event::KeyCode;
+use lilac::GainMode;
+use serde::{de::IntoDeserializer, Deserialize};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tui::style::Color;
+
+/// Crossfade lengths the player cycles through, in seconds
+pub const CROSSFADES: &[u64] = &[0, 2, 5, 10];
+pub const MAX_PREAMP: f32 = 15.0;
+/// Fade length on pause and skip when fading is enabled
+pub const FADE: Duration = Duration::from_millis(300);
+
+/// CLI configuration, read from `lilac/config.toml` in the user configuration directory
+///
+/// Every field is optional and missing ones take their default value.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub player: Player,
+    pub theme: Theme,
+    /// Key for each action name, like `play-pause = "p"`
+    pub keys: HashMap<String, Key>,
+    pub transcode: Transcode,
+    pub library: Library,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Player {
+    /// Interface refresh period in milliseconds
+    pub tick_rate: u64,
+    /// Initial volume, between 0 and 100
+    pub volume: u16,
+    pub gain: Mode,
+    /// ReplayGain preamp in dB
+    pub preamp: f32,
+    /// Crossfade length in seconds, one of `CROSSFADES`
+    pub crossfade: u64,
+    pub fade: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Theme {
+    /// Emphasized text, like titles and the play state
+    pub accent: Colour,
+    /// Gauges and played parts of the timeline
+    pub text: Colour,
+    /// Unplayed parts of the timeline
+    pub dim: Colour,
+    /// Background of selected items
+    pub selection: Colour,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Transcode {
+    pub pattern: Pattern,
+    pub format: Target,
+    /// Characters replaced in tags substituted into the pattern
+    pub sanitize: Sanitize,
+    /// Character replacing sanitized ones
+    pub replacement: char,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Library {
+    /// Directories indexed on every scan, `~` being the home directory
+    pub paths: Vec<PathBuf>,
+}
+
+/// Interactive player command bound to a key
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    PlayPause,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown,
+    GainMode,
+    PreampUp,
+    PreampDown,
+    Shuffle,
+    Repeat,
+    Crossfade,
+    Fade,
+    Equalizer,
+    Visualizer,
+    Browser,
+    Search,
+    Focus,
+    SaveQueue,
+    Quit,
+}
+
+/// Key name, like `space`, `left`, `f1` or a single character
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Key(pub KeyCode);
+
+/// Colour name, `#rrggbb` or a 256 colours palette index
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Colour(pub Color);
+
+/// ReplayGain mode, parsed like the `--gain` flag
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Mode(pub GainMode);
+
+impl Config {
+    /// Reads the configuration file
+    ///
+    /// A missing file is only an error when its path is explicitly given.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, explicit) = match path {
+            Some(p) => (p.to_owned(), true),
+            None => match dirs::config_dir() {
+                Some(d) => (d.join("lilac").join("config.toml"), false),
+                None => return Ok(Self::default()),
+            },
+        };
+        if !explicit && !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read `{}`", path.display()))?;
+        let config: Self = toml::from_str(&text)
+            .with_context(|| format!("Invalid configuration in `{}`", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid configuration in `{}`", path.display()))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let p = &self.player;
+        if !(10..=1000).contains(&p.tick_rate) {
+            bail!("player.tick-rate must be between 10 and 1000 milliseconds");
+        }
+        if p.volume > 100 {
+            bail!("player.volume must be between 0 and 100");
+        }
+        if !(-MAX_PREAMP..=MAX_PREAMP).contains(&p.preamp) {
+            bail!("player.preamp must be between -{0} and {0} dB", MAX_PREAMP);
+        }
+        if !CROSSFADES.contains(&p.crossfade) {
+            bail!("player.crossfade must be one of {:?} seconds", CROSSFADES);
+        }
+        if Sanitize::Portable.reserves(self.transcode.replacement) {
+            bail!("transcode.replacement can't be a path separator or reserved character");
+        }
+        self.keymap()?;
+        Ok(())
+    }
+
+    /// Key bindings, defaults overridden by the configured ones
+    pub fn keymap(&self) -> anyhow::Result<HashMap<KeyCode, Action>> {
+        let mut bindings: HashMap<Action, KeyCode> = Action::defaults().iter().copied().collect();
+        for (name, &Key(code)) in &self.keys {
+            let action = Action::deserialize(name.as_str().into_deserializer()).map_err(
+                |_: serde::de::value::Error| anyhow::anyhow!("keys: unknown action `{}`", name),
+            )?;
+            bindings.insert(action, code);
+        }
+
+        let mut keymap = HashMap::new();
+        for (action, code) in bindings {
+            if let Some(other) = keymap.insert(code, action) {
+                let (mut a, mut b) = (format!("{:?}", action), format!("{:?}", other));
+                if a > b {
+                    std::mem::swap(&mut a, &mut b);
+                }
+                bail!("keys: {:?} is bound to both {} and {}", code, a, b);
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+impl Player {
+    pub fn tick_rate(&self) -> Duration {
+        Duration::from_millis(self.tick_rate)
+    }
+}
+
+impl Action {
+    fn defaults() -> &'static [(Action, KeyCode)] {
+        &[
+            (Action::PlayPause, KeyCode::Char(' ')),
+            (Action::Next, KeyCode::Right),
+            (Action::Previous, KeyCode::Left),
+            (Action::VolumeUp, KeyCode::Up),
+            (Action::VolumeDown, KeyCode::Down),
+            (Action::GainMode, KeyCode::Char('g')),
+            (Action::PreampUp, KeyCode::Char(']')),
+            (Action::PreampDown, KeyCode::Char('[')),
+            (Action::Shuffle, KeyCode::Char('s')),
+            (Action::Repeat, KeyCode::Char('r')),
+            (Action::Crossfade, KeyCode::Char('c')),
+            (Action::Fade, KeyCode::Char('f')),
+            (Action::Equalizer, KeyCode::Char('e')),
+            (Action::Visualizer, KeyCode::Char('v')),
+            (Action::Browser, KeyCode::Char('b')),
+            (Action::Search, KeyCode::Char('/')),
+            (Action::Focus, KeyCode::Tab),
+            (Action::SaveQueue, KeyCode::Char('w')),
+            (Action::Quit, KeyCode::Esc),
+        ]
+    }
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self {
+            tick_rate: 100,
+            volume: 100,
+            gain: Mode(GainMode::Track),
+            preamp: 0.0,
+            crossfade: 0,
+            fade: true,
+        }
+    }
+}
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            accent: Colour(Color::Reset),
+            text: Colour(Color::White),
+            dim: Colour(Color::DarkGray),
+            selection: Colour(Color::White),
+        }
+    }
+}
+impl Default for Transcode {
+    fn default() -> Self {
+        Self {
+            pattern: Pattern::default(),
+            format: Target::Auto,
+            sanitize: Sanitize::default(),
+            replacement: '_',
+        }
+    }
+}
+
+impl Library {
+    /// Configured directories with `~` expanded
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.paths
+            .iter()
+            .map(|p| match (p.strip_prefix("~"), dirs::home_dir()) {
+                (Ok(rest), Some(home)) => home.join(rest),
+                _ => p.clone(),
+            })
+            .collect()
+    }
+}
+
+impl TryFrom<String> for Key {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut chars = s.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match s.to_lowercase().as_str() {
+                "space" => KeyCode::Char(' '),
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "enter" => KeyCode::Enter,
+                "esc" | "escape" => KeyCode::Esc,
+                "tab" => KeyCode::Tab,
+                "backspace" => KeyCode::Backspace,
+                "delete" => KeyCode::Delete,
+                "insert" => KeyCode::Insert,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                f => match f.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                    Some(n) if (1..=12).contains(&n) => KeyCode::F(n),
+                    _ => return Err(format!("unknown key `{}`", s)),
+                },
+            },
+        };
+        Ok(Key(code))
+    }
+}
+
+impl TryFrom<String> for Colour {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("unknown colour `{}`", s);
+        let color = match s.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "default" | "reset" => Color::Reset,
+            "black" => Color::Black,
+            "red" => Color::Red,
+            "green" => Color::Green,
+            "yellow" => Color::Yellow,
+            "blue" => Color::Blue,
+            "magenta" => Color::Magenta,
+            "cyan" => Color::Cyan,
+            "gray" | "grey" => Color::Gray,
+            "darkgray" | "darkgrey" => Color::DarkGray,
+            "lightred" => Color::LightRed,
+            "lightgreen" => Color::LightGreen,
+            "lightyellow" => Color::LightYellow,
+            "lightblue" => Color::LightBlue,
+            "lightmagenta" => Color::LightMagenta,
+            "lightcyan" => Color::LightCyan,
+            "white" => Color::White,
+            c if c.starts_with('#') && c.len() == 7 => {
+                let channel = |i| u8::from_str_radix(&c[i..i + 2], 16).map_err(|_| invalid());
+                Color::Rgb(channel(1)?, channel(3)?, channel(5)?)
+            }
+            c => Color::Indexed(c.parse().map_err(|_| invalid())?),
+        };
+        Ok(Colour(color))
+    }
+}
+
+impl TryFrom<String> for Mode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        GainMode::from_str(&s).map(Mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> anyhow::Result<Config> {
+        let config: Config = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("`{}` should be invalid", text),
+            Err(e) => format!("{:#}", e),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = parse("").unwrap();
+        assert_eq!(config.player.tick_rate, 100);
+        assert_eq!(config.keymap().unwrap().len(), Action::defaults().len());
+    }
+
+    #[test]
+    fn parses_values() {
+        let config = parse(
+            r##"
+            [player]
+            tick-rate = 50
+            volume = 80
+            gain = "album"
+            preamp = -6.5
+            crossfade = 5
+
+            [theme]
+            accent = "light-blue"
+            text = "#ff8000"
+            dim = "242"
+
+            [keys]
+            play-pause = "p"
+            quit = "F10"
+
+            [transcode]
+            pattern = "%A/%B/%T.%e"
+            sanitize = "separators"
+            replacement = "-"
+
+            [library]
+            paths = ["~/Music"]
+            "##,
+        )
+        .unwrap();
+
+        assert_eq!(config.player.gain, Mode(GainMode::Album));
+        assert_eq!(config.player.tick_rate(), Duration::from_millis(50));
+        assert_eq!(config.theme.accent, Colour(Color::LightBlue));
+        assert_eq!(config.theme.text, Colour(Color::Rgb(255, 128, 0)));
+        assert_eq!(config.theme.dim, Colour(Color::Indexed(242)));
+        assert_eq!(config.transcode.sanitize, Sanitize::Separators);
+
+        let keymap = config.keymap().unwrap();
+        assert_eq!(keymap[&KeyCode::Char('p')], Action::PlayPause);
+        assert_eq!(keymap[&KeyCode::F(10)], Action::Quit);
+        assert!(!keymap.contains_key(&KeyCode::Char(' ')));
+        assert!(!keymap.contains_key(&KeyCode::Esc));
+
+        if let Some(home) = dirs::home_dir() {
+            assert_eq!(config.library.paths(), [home.join("Music")]);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        assert!(error("[player]\ntick-rate = 5").contains("tick-rate"));
+        assert!(error("[player]\nvolume = 101").contains("volume"));
+        assert!(error("[player]\npreamp = 15.5").contains("preamp"));
+        assert!(error("[player]\ncrossfade = 3").contains("crossfade"));
+        assert!(error("[transcode]\nreplacement = \"/\"").contains("replacement"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(error("[player]\nunknown = 1").contains("unknown field"));
+        assert!(error("[player]\ngain = \"loud\"").contains("loud"));
+        assert!(error("[theme]\naccent = \"#12345g\"").contains("unknown colour"));
+        assert!(error("[theme]\naccent = \"256\"").contains("unknown colour"));
+        assert!(error("[keys]\nquit = \"f13\"").contains("unknown key"));
+        assert!(error("[keys]\nfly = \"x\"").contains("unknown action `fly`"));
+        assert!(error("[transcode]\npattern = \"%T]\"").contains("unexpected `]`"));
+    }
+
+    #[test]
+    fn rejects_conflicting_keys() {
+        assert_eq!(
+            error("[keys]\nnext = \"s\""),
+            "keys: Char('s') is bound to both Next and Shuffle"
+        );
+        // Swapping two bindings is fine
+        assert!(parse("[keys]\nnext = \"s\"\nshuffle = \"right\"").is_ok());
+    }
+}
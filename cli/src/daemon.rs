@@ -0,0 +1,671 @@
+use crate::{
+    config::{Config, FADE},
+    edit,
+    eq::Presets,
+    player::{Player, Status},
+    playlist,
+    queue::{self, Queue, QueueEl, Repeat},
+};
+use anyhow::{bail, Context};
+use lilac::{DspHandle, GainMode, Lilac};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fs::{self, Permissions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+use structopt::{clap::AppSettings, StructOpt};
+
+#[derive(StructOpt)]
+pub struct DaemonOpt {
+    /// Files and playlists to queue on startup
+    #[structopt(name = "FILES")]
+    files: Vec<PathBuf>,
+    /// Control socket, defaults to lilac.sock in the user runtime directory
+    /// or lilac-<uid>.sock in the temporary one
+    #[structopt(short, long, name = "SOCKET")]
+    socket: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct CtlOpt {
+    /// Control socket, defaults to lilac.sock in the user runtime directory
+    /// or lilac-<uid>.sock in the temporary one
+    #[structopt(short, long, name = "SOCKET")]
+    socket: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(StructOpt)]
+enum CtlCommand {
+    /// Resumes playback
+    Play,
+    /// Pauses playback
+    Pause,
+    /// Toggles between playing and paused
+    Toggle,
+    /// Skips to the next song
+    Next,
+    /// Goes back to the previous song, or restarts the current one
+    Prev,
+    /// Moves in the current song
+    ///
+    /// Either seconds or [HH:]MM:SS with optional fractional seconds,
+    /// relative to the current position when prefixed with + or -
+    #[structopt(setting = AppSettings::AllowNegativeNumbers)]
+    Seek {
+        #[structopt(name = "POSITION")]
+        position: String,
+    },
+    /// Sets the volume between 0 and 100
+    ///
+    /// Relative to the current volume when prefixed with + or -
+    #[structopt(setting = AppSettings::AllowNegativeNumbers)]
+    Volume {
+        #[structopt(name = "VOLUME")]
+        volume: String,
+    },
+    /// Adds files and playlists to the queue
+    Enqueue {
+        #[structopt(name = "FILES", required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Prints the current song and position
+    Status {
+        /// Print the raw JSON status
+        #[structopt(short, long)]
+        json: bool,
+    },
+    /// Stops the daemon
+    Quit,
+}
+
+/// Command sent to the daemon, one JSON object per line
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum Request {
+    Play,
+    Pause,
+    Toggle,
+    Next,
+    Prev,
+    /// Position in seconds
+    Seek {
+        position: f64,
+        #[serde(default)]
+        relative: bool,
+    },
+    /// Volume in percents
+    Volume {
+        volume: f64,
+        #[serde(default)]
+        relative: bool,
+    },
+    /// Absolute paths of files and playlists
+    Enqueue {
+        files: Vec<PathBuf>,
+    },
+    Status,
+    Quit,
+}
+
+/// Reply to a request, on a single line
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Response {
+    Ok,
+    Error(String),
+    Status(Box<DaemonStatus>),
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DaemonStatus {
+    playing: bool,
+    path: Option<PathBuf>,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    /// Position in the current song in seconds
+    position: f64,
+    /// Duration of the current song in seconds
+    duration: f64,
+    volume: u16,
+    /// Index of the current song in the queue
+    index: Option<usize>,
+    length: usize,
+    shuffle: bool,
+    repeat: Repeat,
+}
+
+/// Player state owned by the daemon
+struct Daemon {
+    player: Player,
+    queue: Option<Queue>,
+    playing: bool,
+    volume: u16,
+    gain: GainMode,
+    preamp: f32,
+    played_advances: u64,
+}
+
+pub fn daemon(opt: DaemonOpt, config: &Config) -> crate::Result {
+    let device = rodio::default_output_device().context("No audio output device")?;
+    let dsp = DspHandle::new(Presets::load()?.chain());
+    let p = &config.player;
+    let mut daemon = Daemon {
+        player: Player::new(&device, dsp)?,
+        queue: None,
+        playing: false,
+        volume: p.volume,
+        gain: p.gain.0,
+        preamp: p.preamp,
+        played_advances: 0,
+    };
+    daemon.player.set_volume(f32::from(daemon.volume) / 100.0);
+    daemon
+        .player
+        .set_crossfade(Duration::from_secs(p.crossfade));
+    daemon
+        .player
+        .set_fade(if p.fade { FADE } else { Duration::new(0, 0) });
+    if !opt.files.is_empty() {
+        daemon.enqueue(&opt.files)?;
+    }
+
+    let socket = opt.socket.unwrap_or_else(socket);
+    check_owner(&socket)?;
+    if socket.exists() {
+        if UnixStream::connect(&socket).is_ok() {
+            bail!("A daemon is already listening on `{}`", socket.display());
+        }
+        fs::remove_file(&socket)?;
+    }
+    let listener =
+        bind(&socket).with_context(|| format!("Failed to listen on `{}`", socket.display()))?;
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let tx = tx.clone();
+            thread::spawn(move || serve(stream, tx).ok());
+        }
+    });
+    eprintln!("Listening on `{}`", socket.display());
+
+    loop {
+        match rx.recv_timeout(p.tick_rate()) {
+            Ok((Request::Quit, _)) => break,
+            Ok((request, reply)) => {
+                let response = match daemon.handle(request) {
+                    Ok(r) => r,
+                    Err(e) => Response::Error(format!("{:#}", e)),
+                };
+                reply.send(response).ok();
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        daemon.tick();
+    }
+
+    if daemon.playing {
+        daemon.player.set_playing(false);
+        thread::sleep(daemon.player.fade());
+    }
+    fs::remove_file(&socket).ok();
+    crate::OK
+}
+
+pub fn ctl(opt: CtlOpt) -> crate::Result {
+    let (request, json) = match opt.command {
+        CtlCommand::Play => (Request::Play, false),
+        CtlCommand::Pause => (Request::Pause, false),
+        CtlCommand::Toggle => (Request::Toggle, false),
+        CtlCommand::Next => (Request::Next, false),
+        CtlCommand::Prev => (Request::Prev, false),
+        CtlCommand::Seek { position } => {
+            let (relative, sign, time) = signed(&position);
+            let position = edit::parse_time(time)?.as_secs_f64() * sign;
+            (Request::Seek { position, relative }, false)
+        }
+        CtlCommand::Volume { volume } => {
+            let (relative, sign, level) = signed(&volume);
+            let volume: f64 = level
+                .parse()
+                .with_context(|| format!("Invalid volume `{}`", volume))?;
+            (
+                Request::Volume {
+                    volume: volume * sign,
+                    relative,
+                },
+                false,
+            )
+        }
+        CtlCommand::Enqueue { files } => {
+            let files = files
+                .iter()
+                .map(|f| {
+                    f.canonicalize()
+                        .with_context(|| format!("Failed to read `{}`", f.display()))
+                })
+                .collect::<anyhow::Result<_>>()?;
+            (Request::Enqueue { files }, false)
+        }
+        CtlCommand::Status { json } => (Request::Status, json),
+        CtlCommand::Quit => (Request::Quit, false),
+    };
+
+    let socket = opt.socket.unwrap_or_else(socket);
+    check_owner(&socket)?;
+    let mut stream = UnixStream::connect(&socket).with_context(|| {
+        format!(
+            "Failed to connect to `{}`, is the daemon running?",
+            socket.display()
+        )
+    })?;
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match serde_json::from_str(&line).context("Invalid response from the daemon")? {
+        Response::Ok => (),
+        Response::Error(e) => bail!("{}", e),
+        Response::Status(s) if json => println!("{}", serde_json::to_string(&s)?),
+        Response::Status(s) => println!("{}", s),
+    }
+    crate::OK
+}
+
+/// Handles the requests of a client, forwarding them to the main loop
+fn serve(stream: UnixStream, tx: Sender<(Request, Sender<Response>)>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (response, quit) = match serde_json::from_str(&line) {
+            // Answered here since the daemon exits right away
+            Ok(Request::Quit) => (Response::Ok, true),
+            Ok(request) => {
+                let (reply, response) = mpsc::channel();
+                if tx.send((request, reply)).is_err() {
+                    break;
+                }
+                match response.recv() {
+                    Ok(r) => (r, false),
+                    Err(_) => break,
+                }
+            }
+            Err(e) => (Response::Error(format!("Invalid request: {}", e)), false),
+        };
+        let mut out = serde_json::to_string(&response)?;
+        out.push('\n');
+        writer.write_all(out.as_bytes())?;
+
+        if quit {
+            let (reply, _) = mpsc::channel();
+            tx.send((Request::Quit, reply)).ok();
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl Daemon {
+    fn handle(&mut self, request: Request) -> anyhow::Result<Response> {
+        match request {
+            Request::Play | Request::Pause | Request::Toggle => {
+                self.playing = match request {
+                    Request::Play => true,
+                    Request::Pause => false,
+                    _ => !self.playing,
+                } && self.queue.is_some();
+                self.player.set_playing(self.playing);
+            }
+            Request::Next => {
+                if let Some(q) = &mut self.queue {
+                    if q.next() {
+                        self.load();
+                    }
+                }
+            }
+            Request::Prev => {
+                let position = self.player.status().position;
+                if let Some(q) = &mut self.queue {
+                    if position < Duration::from_secs(2) {
+                        q.prev();
+                    }
+                    self.load();
+                }
+            }
+            Request::Seek { position, relative } => {
+                let q = self.queue.as_ref().context("The queue is empty")?;
+                let lilac = q.current().lilac;
+                let mut position = match relative {
+                    true => self.player.status().position.as_secs_f64() + position,
+                    false => position,
+                };
+                if !position.is_finite() {
+                    bail!("Invalid position");
+                }
+                position = position.clamp(0.0, lilac.duration().as_secs_f64());
+                self.player.seek(lilac, Duration::from_secs_f64(position));
+            }
+            Request::Volume { volume, relative } => {
+                let volume = match relative {
+                    true => f64::from(self.volume) + volume,
+                    false => volume,
+                };
+                if !volume.is_finite() {
+                    bail!("Invalid volume");
+                }
+                self.volume = volume.round().clamp(0.0, 100.0) as u16;
+                self.player.set_volume(f32::from(self.volume) / 100.0);
+            }
+            Request::Enqueue { files } => self.enqueue(&files)?,
+            Request::Status => return Ok(Response::Status(Box::new(self.status()))),
+            Request::Quit => (),
+        }
+        Ok(Response::Ok)
+    }
+
+    /// Reads files and playlists into the queue, starting it if it was empty
+    fn enqueue(&mut self, files: &[PathBuf]) -> crate::Result {
+        let mut paths = Vec::new();
+        for f in files {
+            if playlist::Format::from_path(f).is_some() {
+                paths.extend(playlist::read(f)?.into_iter().map(|e| e.path));
+            } else {
+                paths.push(f.clone());
+            }
+        }
+
+        match &mut self.queue {
+            Some(q) => {
+                let (songs, failed) = queue::read(&paths);
+                q.append(songs);
+                self.preload();
+                if let Some((p, e)) = failed.first() {
+                    bail!("`{}`: {}", p.display(), e);
+                }
+            }
+            None => {
+                let queue = Queue::new(&paths)?;
+                if queue.is_empty() {
+                    bail!("No playable files");
+                }
+                self.queue = Some(queue);
+                self.load();
+            }
+        }
+        crate::OK
+    }
+
+    /// Moves on when the player advanced or ran out of songs
+    fn tick(&mut self) {
+        let Status {
+            advances, ended, ..
+        } = self.player.status();
+        if let Some(q) = &mut self.queue {
+            if advances != self.played_advances {
+                for _ in self.played_advances..advances {
+                    q.advance();
+                }
+                self.played_advances = advances;
+                self.preload();
+            } else if ended {
+                q.restart();
+                self.playing = false;
+                self.player.set_playing(false);
+                self.load();
+            }
+        }
+    }
+
+    fn load(&mut self) {
+        if let Some(q) = &self.queue {
+            let QueueEl { id, lilac, .. } = q.current();
+            self.player.load(id, lilac, self.factor(lilac));
+        }
+        self.preload();
+    }
+    fn preload(&self) {
+        let q = match &self.queue {
+            Some(q) => q,
+            None => return self.player.unload_next(),
+        };
+        match q.upcoming() {
+            Some(QueueEl { id, lilac, .. }) => {
+                self.player
+                    .preload(q.current().id, id, lilac, self.factor(lilac))
+            }
+            None => self.player.unload_next(),
+        }
+    }
+    fn factor(&self, lilac: &Lilac) -> f32 {
+        lilac.gain(self.gain, self.preamp)
+    }
+
+    fn status(&self) -> DaemonStatus {
+        let current = self.queue.as_ref().map(Queue::current);
+        DaemonStatus {
+            playing: self.playing,
+            path: current.as_ref().map(|c| c.path.to_owned()),
+            title: current.as_ref().and_then(|c| c.lilac.title.clone()),
+            artist: current.as_ref().and_then(|c| c.lilac.artist.clone()),
+            album: current.as_ref().and_then(|c| c.lilac.album.clone()),
+            position: self.player.status().position.as_secs_f64(),
+            duration: current
+                .as_ref()
+                .map_or(0.0, |c| c.lilac.duration().as_secs_f64()),
+            volume: self.volume,
+            index: current.as_ref().map(|c| c.idx),
+            length: self.queue.as_ref().map_or(0, Queue::len),
+            shuffle: self.queue.as_ref().is_some_and(|q| q.shuffle),
+            repeat: self.queue.as_ref().map_or(Repeat::Off, |q| q.repeat),
+        }
+    }
+}
+
+impl std::fmt::Display for DaemonStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = |secs: f64| {
+            let secs = secs as u64;
+            format!("{}:{:02}", secs / 60, secs % 60)
+        };
+        let path = match &self.path {
+            Some(p) => p,
+            None => return write!(f, "Stopped"),
+        };
+
+        write!(f, "{} ", if self.playing { "Playing" } else { "Paused" })?;
+        match &self.title {
+            Some(t) => write!(f, "{} - {}", self.artist.as_deref().unwrap_or("Unknown"), t)?,
+            None => write!(f, "{}", path.display())?,
+        }
+        write!(f, " [{}/{}]", time(self.position), time(self.duration))
+    }
+}
+
+/// Splits an optional `+` or `-` prefix, returning whether there was one and its sign
+fn signed(s: &str) -> (bool, f64, &str) {
+    match (s.strip_prefix('+'), s.strip_prefix('-')) {
+        (Some(rest), _) => (true, 1.0, rest),
+        (_, Some(rest)) => (true, -1.0, rest),
+        _ => (false, 1.0, s),
+    }
+}
+
+/// Default socket path, in the runtime directory when there is one
+///
+/// The temporary directory is shared, so the socket name includes the user ID there.
+fn socket() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(d) => d.join("lilac.sock"),
+        None => env::temp_dir().join(format!("lilac-{}.sock", uid())),
+    }
+}
+
+/// Fails when the socket belongs to another user, who would control or impersonate the daemon
+fn check_owner(socket: &Path) -> crate::Result {
+    match fs::symlink_metadata(socket) {
+        Ok(m) if m.uid() != uid() => {
+            bail!("`{}` belongs to another user", socket.display())
+        }
+        _ => crate::OK,
+    }
+}
+
+/// Listens on a socket only the user can connect to
+///
+/// The umask is restricted while binding, otherwise the socket would
+/// briefly be created with the default permissions.
+fn bind(socket: &Path) -> io::Result<UnixListener> {
+    // SAFETY: umask has no preconditions and can't fail
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    unsafe { libc::umask(umask) };
+
+    let listener = listener?;
+    fs::set_permissions(socket, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn uid() -> u32 {
+    // SAFETY: getuid has no preconditions and can't fail
+    unsafe { libc::getuid() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Serialize + for<'de> Deserialize<'de>>(value: &T, json: &str) -> T {
+        assert_eq!(serde_json::to_string(value).unwrap(), json);
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn status() -> DaemonStatus {
+        DaemonStatus {
+            playing: true,
+            path: Some(PathBuf::from("/music/song.lilac")),
+            title: Some("Song".to_owned()),
+            artist: None,
+            album: None,
+            position: 65.5,
+            duration: 3725.0,
+            volume: 80,
+            index: Some(0),
+            length: 3,
+            shuffle: false,
+            repeat: Repeat::All,
+        }
+    }
+
+    #[test]
+    fn serializes_requests() {
+        let requests = [
+            (Request::Toggle, r#"{"command":"toggle"}"#),
+            (
+                Request::Seek {
+                    position: -10.0,
+                    relative: true,
+                },
+                r#"{"command":"seek","position":-10.0,"relative":true}"#,
+            ),
+            (
+                Request::Volume {
+                    volume: 50.0,
+                    relative: false,
+                },
+                r#"{"command":"volume","volume":50.0,"relative":false}"#,
+            ),
+            (
+                Request::Enqueue {
+                    files: vec![PathBuf::from("/a.lilac")],
+                },
+                r#"{"command":"enqueue","files":["/a.lilac"]}"#,
+            ),
+            (Request::Quit, r#"{"command":"quit"}"#),
+        ];
+        for (request, json) in &requests {
+            assert_eq!(&round_trip(request, json), request);
+        }
+
+        let seek: Request = serde_json::from_str(r#"{"command":"seek","position":3}"#).unwrap();
+        assert_eq!(
+            seek,
+            Request::Seek {
+                position: 3.0,
+                relative: false
+            }
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"command":"rewind"}"#).is_err());
+    }
+
+    #[test]
+    fn serializes_responses() {
+        assert_eq!(round_trip(&Response::Ok, r#""ok""#), Response::Ok);
+        let error = Response::Error("No song".to_owned());
+        assert_eq!(round_trip(&error, r#"{"error":"No song"}"#), error);
+
+        let status = Response::Status(Box::new(status()));
+        let json = serde_json::to_string(&status).unwrap();
+        assert!(json.starts_with(r#"{"status":{"playing":true,"path":"/music/song.lilac""#));
+        assert!(json.contains(r#""repeat":"all""#));
+        assert_eq!(round_trip(&status, &json), status);
+    }
+
+    #[test]
+    fn splits_signs() {
+        assert_eq!(signed("+5"), (true, 1.0, "5"));
+        assert_eq!(signed("-1:30"), (true, -1.0, "1:30"));
+        assert_eq!(signed("10"), (false, 1.0, "10"));
+        assert_eq!(signed(""), (false, 1.0, ""));
+    }
+
+    #[test]
+    fn displays_status() {
+        let mut status = status();
+        assert_eq!(status.to_string(), "Playing Unknown - Song [1:05/62:05]");
+
+        status.playing = false;
+        status.artist = Some("Artist".to_owned());
+        assert_eq!(status.to_string(), "Paused Artist - Song [1:05/62:05]");
+
+        status.title = None;
+        assert_eq!(status.to_string(), "Paused /music/song.lilac [1:05/62:05]");
+
+        status.path = None;
+        assert_eq!(status.to_string(), "Stopped");
+    }
+
+    #[test]
+    fn binds_private_socket() {
+        let dir = env::temp_dir().join(format!("lilac-daemon-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("lilac.sock");
+
+        let _listener = bind(&socket).unwrap();
+        let mode = fs::metadata(&socket).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(check_owner(&socket).is_ok());
+        UnixStream::connect(&socket).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+use crate::{config, format, pattern::Sanitize};
+use anyhow::{bail, Context};
+use lilac::Lilac;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct CutOpt {
+    /// Input LILAC file
+    #[structopt(name = "INPUT")]
+    input: PathBuf,
+    /// Output LILAC file
+    #[structopt(name = "OUTPUT")]
+    output: PathBuf,
+    /// Start of the kept range, defaults to the beginning
+    ///
+    /// Either seconds or [HH:]MM:SS with optional fractional seconds
+    #[structopt(short, long, name = "START")]
+    start: Option<String>,
+    /// End of the kept range, defaults to the end
+    ///
+    /// Either seconds or [HH:]MM:SS with optional fractional seconds
+    #[structopt(short, long, name = "END")]
+    end: Option<String>,
+    /// Interpret start and end as frame indices
+    #[structopt(short, long)]
+    frames: bool,
+    /// Overwrite the output if it exists
+    #[structopt(long)]
+    force: bool,
+}
+
+#[derive(StructOpt)]
+pub struct ConcatOpt {
+    /// Input LILAC files, in playback order
+    #[structopt(name = "FILES", required = true, min_values = 2)]
+    files: Vec<PathBuf>,
+    /// Output LILAC file
+    #[structopt(short, long, name = "OUTPUT")]
+    output: PathBuf,
+    /// Convert inputs to the format of the first one
+    /// instead of failing when they differ
+    #[structopt(short, long)]
+    convert: bool,
+    /// Overwrite the output if it exists
+    #[structopt(long)]
+    force: bool,
+}
+
+#[derive(StructOpt)]
+pub struct SplitOpt {
+    /// Input LILAC file
+    #[structopt(name = "INPUT")]
+    input: PathBuf,
+    /// Output files naming pattern
+    ///
+    /// %F is replaced with the input filename without extension,
+    /// %n with the zero padded part number,
+    /// %T with the part title,
+    /// %A with the part artist,
+    /// %a with the part album.
+    /// The pattern must contain %n so that every part gets its own file.
+    #[structopt(name = "PATTERN", default_value = "%F %n.lilac")]
+    output: String,
+    /// Characters replaced in tags substituted into the pattern
+    ///
+    /// One of portable, separators or none, see the transcode subcommand.
+    /// Defaults to the configured mode, or portable
+    #[structopt(long, name = "MODE")]
+    sanitize: Option<Sanitize>,
+    /// Character replacing sanitized ones
+    ///
+    /// Defaults to the configured character, or _
+    #[structopt(long, name = "CHAR")]
+    replacement: Option<char>,
+    /// Overwrite existing files
+    #[structopt(long)]
+    force: bool,
+    /// Split at this time, can be repeated
+    ///
+    /// Either seconds or [HH:]MM:SS with optional fractional seconds
+    #[structopt(
+        long,
+        name = "TIME",
+        number_of_values = 1,
+        required_unless = "on-silence"
+    )]
+    at: Vec<String>,
+    /// Split on silences, dropping them from the parts
+    #[structopt(long, conflicts_with = "TIME")]
+    on_silence: bool,
+    /// Level under which audio is considered silent, in dBFS
+    #[structopt(long, name = "DB", default_value = "-60", allow_hyphen_values = true)]
+    threshold: f32,
+    /// Minimum length of a silence separating two parts
+    #[structopt(long, name = "DURATION", default_value = "2")]
+    min_silence: String,
+    /// Title template for the parts
+    ///
+    /// %T is replaced with the original title,
+    /// %A with the original artist,
+    /// %a with the original album,
+    /// %n with the part number
+    /// and %N with the number of parts.
+    #[structopt(long, name = "TITLE", default_value = "%T %n")]
+    title: String,
+    /// Album template for the parts, see the title template
+    #[structopt(long, name = "ALBUM", default_value = "%a")]
+    album: String,
+}
+
+pub fn cut(opt: CutOpt) -> crate::Result {
+    refuse_overwrite(&opt.output, opt.force)?;
+    let lilac = Lilac::read_file(&opt.input)
+        .with_context(|| format!("Failed to read `{}`", opt.input.display()))?;
+
+    let position = |p: &Option<String>, default: usize| -> anyhow::Result<usize> {
+        match p {
+            Some(p) if opt.frames => p.parse().context("Invalid frame index"),
+            Some(p) => Ok(lilac.frame_at(parse_time(p)?)),
+            None => Ok(default),
+        }
+    };
+    let start = position(&opt.start, 0)?;
+    let end = position(&opt.end, lilac.frames())?.min(lilac.frames());
+
+    format::write_atomic(&lilac.slice_frames(start..end)?, &opt.output)?;
+    println!("`{}` -> `{}`", opt.input.display(), opt.output.display());
+    crate::OK
+}
+
+pub fn concat(opt: ConcatOpt) -> crate::Result {
+    refuse_overwrite(&opt.output, opt.force)?;
+    let mut files = opt.files.iter();
+    let first = files.next().unwrap();
+    let mut lilac =
+        Lilac::read_file(first).with_context(|| format!("Failed to read `{}`", first.display()))?;
+
+    for f in files {
+        let other =
+            Lilac::read_file(f).with_context(|| format!("Failed to read `{}`", f.display()))?;
+        if opt.convert {
+            lilac.append_converted(&other)
+        } else {
+            lilac.append(&other)
+        }
+        .with_context(|| format!("Failed to append `{}`", f.display()))?;
+    }
+
+    format::write_atomic(&lilac, &opt.output)?;
+    println!("{} files -> `{}`", opt.files.len(), opt.output.display());
+    crate::OK
+}
+
+pub fn split(opt: SplitOpt, config: &config::Transcode) -> crate::Result {
+    if !opt.output.contains("%n") {
+        bail!(
+            "The output pattern must contain %n, otherwise every part overwrites the previous one"
+        );
+    }
+    let sanitize = opt.sanitize.unwrap_or(config.sanitize);
+    let replacement = opt.replacement.unwrap_or(config.replacement);
+    if Sanitize::Portable.reserves(replacement) {
+        bail!(
+            "`{}` can't replace reserved characters",
+            replacement.escape_debug()
+        );
+    }
+    let tag = |t: &str| sanitize.apply(t, replacement);
+    let lilac = Lilac::read_file(&opt.input)
+        .with_context(|| format!("Failed to read `{}`", opt.input.display()))?;
+
+    let parts = if opt.on_silence {
+        lilac.split_on_silence(opt.threshold, parse_time(&opt.min_silence)?)
+    } else {
+        let mut cuts = vec![0];
+        for t in &opt.at {
+            cuts.push(lilac.frame_at(parse_time(t)?).min(lilac.frames()));
+        }
+        cuts.push(lilac.frames());
+        cuts.sort_unstable();
+        cuts.dedup();
+
+        cuts.windows(2)
+            .map(|c| lilac.slice_frames(c[0]..c[1]))
+            .collect::<Result<_, _>>()?
+    };
+
+    let stem = opt.input.file_stem().context("Invalid filename")?;
+    let total = parts.len();
+    let width = total.to_string().len();
+    let mut outputs = Vec::with_capacity(total);
+    for (i, mut part) in parts.into_iter().enumerate() {
+        let n = i + 1;
+        let fill = |template: &str| {
+            template
+                .replace("%n", &format!("{:0w$}", n, w = width))
+                .replace("%N", &total.to_string())
+                .replace("%T", lilac.title())
+                .replace("%A", lilac.artist())
+                .replace("%a", lilac.album())
+        };
+        part.title = Some(fill(&opt.title));
+        part.album = Some(fill(&opt.album));
+        part.track = Some(n as u32);
+
+        let output = opt
+            .output
+            .replace("%F", &stem.to_string_lossy())
+            .replace("%n", &format!("{:0w$}", n, w = width))
+            .replace("%T", &tag(part.title()))
+            .replace("%A", &tag(part.artist()))
+            .replace("%a", &tag(part.album()));
+        let outfile = opt
+            .input
+            .parent()
+            .map(|p| p.join(&output))
+            .unwrap_or_else(|| PathBuf::from(output));
+
+        refuse_overwrite(&outfile, opt.force)?;
+        outputs.push((part, outfile));
+    }
+
+    // Every output is checked before writing any, so that nothing is left half split
+    for (part, outfile) in outputs {
+        format::write_atomic(&part, &outfile)?;
+        println!("`{}` -> `{}`", opt.input.display(), outfile.display());
+    }
+    crate::OK
+}
+
+fn refuse_overwrite(path: &Path, force: bool) -> anyhow::Result<()> {
+    if path.exists() && !force {
+        bail!(
+            "`{}` already exists, use --force to overwrite it",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Parses seconds or `[HH:]MM:SS` with optional fractional seconds
+pub fn parse_time(s: &str) -> anyhow::Result<Duration> {
+    let mut secs = 0.0;
+    for part in s.split(':') {
+        let part: f64 = part
+            .parse()
+            .with_context(|| format!("Invalid time `{}`", s))?;
+        secs = secs * 60.0 + part;
+    }
+    if !secs.is_finite() || secs < 0.0 || s.split(':').count() > 3 {
+        anyhow::bail!("Invalid time `{}`", s);
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_time("1:30").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_time("1:00:01.5").unwrap(),
+            Duration::from_millis(3_601_500)
+        );
+        assert!(parse_time("1:2:3:4").is_err());
+        assert!(parse_time("-1").is_err());
+        assert!(parse_time("a:10").is_err());
+        assert!(parse_time("").is_err());
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lilac-edit-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, samples: &str) {
+        let json = format!(
+            r#"{{"title":"AC/DC: Live","channels":1,"sampleRate":10,"bitDepth":16,"samples":[{}]}}"#,
+            samples
+        );
+        Lilac::read(json.as_bytes())
+            .unwrap()
+            .write_file(path)
+            .unwrap();
+    }
+
+    fn frames(path: &Path) -> usize {
+        Lilac::read_file(path).unwrap().frames()
+    }
+
+    #[test]
+    fn cut_refuses_to_overwrite() {
+        let dir = dir("cut");
+        let (input, output) = (dir.join("in.lilac"), dir.join("out.lilac"));
+        write(&input, "1,2,3,4");
+        write(&output, "9");
+
+        let args = |force: &[&str]| {
+            let mut args = vec!["cut", input.to_str().unwrap(), output.to_str().unwrap()];
+            args.extend_from_slice(&["--frames", "-s", "1", "-e", "3"]);
+            args.extend_from_slice(force);
+            CutOpt::from_iter_safe(args).unwrap()
+        };
+        let error = cut(args(&[])).err().unwrap();
+        assert!(error.to_string().contains("--force"), "{}", error);
+        assert_eq!(frames(&output), 1);
+
+        cut(args(&["--force"])).unwrap();
+        assert_eq!(frames(&output), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concat_refuses_to_overwrite() {
+        let dir = dir("concat");
+        let (a, b) = (dir.join("a.lilac"), dir.join("b.lilac"));
+        write(&a, "1,2");
+        write(&b, "3");
+
+        let args = |force: &[&str]| {
+            let mut args = vec!["concat", a.to_str().unwrap(), b.to_str().unwrap()];
+            args.extend_from_slice(&["-o", a.to_str().unwrap()]);
+            args.extend_from_slice(force);
+            ConcatOpt::from_iter_safe(args).unwrap()
+        };
+        assert!(concat(args(&[])).is_err());
+        assert_eq!(frames(&a), 2);
+
+        concat(args(&["--force"])).unwrap();
+        assert_eq!(frames(&a), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn split_sanitizes_tags() {
+        let dir = dir("split");
+        let input = dir.join("in.lilac");
+        write(&input, "1,2,3,4");
+
+        let run = |extra: &[&str], config: &config::Transcode| {
+            let mut args = vec![
+                "split",
+                input.to_str().unwrap(),
+                "%n %T.lilac",
+                "--at",
+                "0.2",
+            ];
+            args.extend_from_slice(extra);
+            split(SplitOpt::from_iter_safe(args).unwrap(), config)
+        };
+        run(&[], &config::Transcode::default()).unwrap();
+        assert_eq!(frames(&dir.join("1 AC_DC_ Live 1.lilac")), 2);
+        assert_eq!(frames(&dir.join("2 AC_DC_ Live 2.lilac")), 2);
+
+        let config = config::Transcode {
+            sanitize: Sanitize::Separators,
+            replacement: '-',
+            ..config::Transcode::default()
+        };
+        run(&[], &config).unwrap();
+        assert!(dir.join("1 AC-DC: Live 1.lilac").exists());
+        run(&["--sanitize", "portable"], &config).unwrap();
+        assert!(dir.join("1 AC-DC- Live 1.lilac").exists());
+        assert!(run(&["--replacement", "/"], &config).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::Context;
+use lilac::{Chain, Filter, FilterKind, Limiter};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+/// Equalizer presets, saved in the user configuration directory
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Presets {
+    pub enabled: bool,
+    pub selected: usize,
+    pub presets: Vec<Preset>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Preset {
+    pub name: String,
+    #[serde(flatten)]
+    pub chain: Chain,
+}
+
+impl Presets {
+    /// Loads the user presets, falling back to the built-in ones
+    pub fn load() -> anyhow::Result<Self> {
+        match path() {
+            Some(p) if p.exists() => Self::read(&p),
+            _ => Ok(Self {
+                enabled: false,
+                selected: 0,
+                presets: builtin(),
+            }),
+        }
+    }
+    pub fn save(&self) -> anyhow::Result<()> {
+        self.write(&path().context("No configuration directory")?)
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let mut presets: Self = serde_json::from_reader(BufReader::new(File::open(path)?))
+            .with_context(|| format!("Invalid EQ presets in `{}`", path.display()))?;
+        if presets.presets.is_empty() {
+            presets.presets = builtin();
+        }
+        presets.selected = presets.selected.min(presets.presets.len() - 1);
+        Ok(presets)
+    }
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(p) = path.parent() {
+            fs::create_dir_all(p)?;
+        }
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    pub fn current(&self) -> &Preset {
+        &self.presets[self.selected]
+    }
+    pub fn current_mut(&mut self) -> &mut Preset {
+        &mut self.presets[self.selected]
+    }
+    pub fn cycle(&mut self) {
+        self.selected = (self.selected + 1) % self.presets.len();
+    }
+
+    /// Chain to apply, flat when disabled
+    pub fn chain(&self) -> Chain {
+        if self.enabled {
+            self.current().chain.clone()
+        } else {
+            Chain::default()
+        }
+    }
+}
+
+fn path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("lilac").join("eq.json"))
+}
+
+fn builtin() -> Vec<Preset> {
+    let limiter = Some(Limiter::new(-0.3, 100.0));
+    let preset = |name: &str, preamp, filters| Preset {
+        name: name.to_owned(),
+        chain: Chain {
+            preamp,
+            filters,
+            limiter,
+        },
+    };
+
+    vec![
+        preset(
+            "Graphic",
+            0.0,
+            [60.0, 250.0, 1000.0, 4000.0, 12000.0]
+                .iter()
+                .map(|&f| Filter::new(FilterKind::Peaking, f, 0.0, 1.0))
+                .collect(),
+        ),
+        preset(
+            "Bass boost",
+            -3.0,
+            vec![Filter::new(FilterKind::LowShelf, 100.0, 6.0, 0.707)],
+        ),
+        preset(
+            "Treble boost",
+            -3.0,
+            vec![Filter::new(FilterKind::HighShelf, 8000.0, 6.0, 0.707)],
+        ),
+        preset(
+            "Loudness",
+            -4.0,
+            vec![
+                Filter::new(FilterKind::LowShelf, 80.0, 6.0, 0.707),
+                Filter::new(FilterKind::HighShelf, 10000.0, 4.0, 0.707),
+            ],
+        ),
+        preset(
+            "Vocal",
+            -2.0,
+            vec![
+                Filter::new(FilterKind::HighPass, 80.0, 0.0, 0.707),
+                Filter::new(FilterKind::Peaking, 2500.0, 3.0, 1.0),
+            ],
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("lilac-eq-{}-{}.json", std::process::id(), name))
+    }
+
+    #[test]
+    fn round_trips_presets() {
+        let path = file("round-trip");
+        let mut presets = Presets {
+            enabled: true,
+            selected: 1,
+            presets: builtin(),
+        };
+        presets.current_mut().chain.preamp = -1.5;
+        presets.current_mut().chain.limiter = None;
+        presets.write(&path).unwrap();
+
+        let read = Presets::read(&path).unwrap();
+        assert!(read.enabled);
+        assert_eq!(read.selected, 1);
+        assert_eq!(read.presets.len(), presets.presets.len());
+        for (a, b) in read.presets.iter().zip(&presets.presets) {
+            assert_eq!(a.name, b.name);
+            assert_eq!(a.chain, b.chain);
+        }
+        assert_eq!(read.chain(), presets.chain());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_builtin() {
+        let path = file("empty");
+        fs::write(&path, r#"{"enabled":false,"selected":3,"presets":[]}"#).unwrap();
+
+        let read = Presets::read(&path).unwrap();
+        assert_eq!(read.presets.len(), builtin().len());
+        assert_eq!(read.selected, 3);
+        assert_eq!(read.chain(), Chain::default());
+
+        fs::write(&path, r#"{"enabled":true,"selected":9,"presets":[{"name":"Flat","preamp":0.0,"filters":[],"limiter":null}]}"#).unwrap();
+        let read = Presets::read(&path).unwrap();
+        assert_eq!(read.selected, 0);
+        assert_eq!(read.current().name, "Flat");
+
+        fs::write(&path, "{").unwrap();
+        assert!(Presets::read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::Context;
+use lilac::Lilac;
+use std::{
+    ffi::OsString,
+    fmt::{self, Display, Formatter},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static MP3_MAGIC_NUMBERS: &[&[u8]] = &[&[0xFF, 0xFB], &[0xFF, 0xF3], &[0xFF, 0xF2], b"ID3"];
+static FLAC_MAGIC_NUMBER: &[u8] = b"fLaC";
+static OGG_MAGIC_NUMBER: &[u8] = b"OggS";
+static WAV_MAGIC_NUMBER: &[u8] = b"WAVE";
+const WAV_MAGIC_NUMBER_OFFSET: usize = 8;
+
+/// Counter making temporary file names unique within the process
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Importable file format
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    Lilac,
+    Mp3,
+    Flac,
+    Ogg,
+    Wav,
+}
+
+impl Format {
+    /// Infers the format from the file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "lilac" => Some(Format::Lilac),
+            "mp3" => Some(Format::Mp3),
+            "flac" => Some(Format::Flac),
+            "ogg" => Some(Format::Ogg),
+            "wav" => Some(Format::Wav),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Lilac => "lilac",
+            Format::Mp3 => "mp3",
+            Format::Flac => "flac",
+            Format::Ogg => "ogg",
+            Format::Wav => "wav",
+        }
+    }
+
+    /// Encoding of the file contents, given the file read
+    pub fn encoding(self, lilac: &Lilac) -> String {
+        match self {
+            Format::Lilac => lilac.encoding().to_string(),
+            Format::Mp3 => "mpeg layer 3".to_owned(),
+            Format::Flac => "flac".to_owned(),
+            Format::Ogg => "vorbis".to_owned(),
+            Format::Wav => "pcm".to_owned(),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// Reads a file of any importable format
+///
+/// The format is inferred from the extension, or from the contents
+/// when the extension is missing or unknown.
+pub fn read(path: &Path) -> anyhow::Result<(Lilac, Format)> {
+    let reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open `{}`", path.display()))?,
+    );
+
+    let result = match Format::from_path(path) {
+        Some(Format::Lilac) => (Lilac::read(reader)?, Format::Lilac),
+        Some(Format::Mp3) => (Lilac::from_mp3(reader)?, Format::Mp3),
+        Some(Format::Flac) => (Lilac::from_flac(reader)?, Format::Flac),
+        Some(Format::Ogg) => (Lilac::from_ogg(reader)?, Format::Ogg),
+        Some(Format::Wav) => (Lilac::from_wav(reader)?, Format::Wav),
+        None => detect(reader)?,
+    };
+    Ok(result)
+}
+
+/// Replaces a LILAC file without ever leaving it partially written
+pub fn write_atomic(lilac: &Lilac, path: &Path) -> anyhow::Result<()> {
+    replace(path, |temp| lilac.write_file(temp))
+}
+
+/// Writes a file through a temporary file renamed over it once complete
+///
+/// The temporary file is next to the destination so that the rename is atomic,
+/// and an interrupted write leaves any previous file intact.
+/// Errors returned by `write`, like a failed verification of the temporary file,
+/// leave the destination untouched.
+pub fn replace<F, E>(path: &Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&Path) -> Result<(), E>,
+    E: Into<anyhow::Error>,
+{
+    let temp = temp(path).with_context(|| format!("Failed to write `{}`", path.display()))?;
+    let result = write(&temp)
+        .map_err(Into::into)
+        .and_then(|_| fs::rename(&temp, path).map_err(Into::into));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.with_context(|| format!("Failed to write `{}`", path.display()))
+}
+
+/// Creates an empty temporary file next to a path, never reusing an existing one
+///
+/// It is hidden so that directory walks, like mirroring, leave it alone.
+fn temp(path: &Path) -> anyhow::Result<PathBuf> {
+    let name = path.file_name().context("Invalid filename")?;
+    loop {
+        let mut temp = OsString::from(".");
+        temp.push(name);
+        temp.push(format!(
+            ".{}-{}.tmp",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp = path.with_file_name(temp);
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(_) => return Ok(temp),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn detect<R: Read + Seek>(mut reader: R) -> anyhow::Result<(Lilac, Format)> {
+    let magic_numer_len = MP3_MAGIC_NUMBERS
+        .iter()
+        .fold(0, |max, n| max.max(n.len()))
+        .max(FLAC_MAGIC_NUMBER.len())
+        .max(OGG_MAGIC_NUMBER.len())
+        .max(WAV_MAGIC_NUMBER_OFFSET + WAV_MAGIC_NUMBER.len());
+    let mut magic_number = vec![0; magic_numer_len];
+
+    reader.read_exact(&mut magic_number)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let result = if MP3_MAGIC_NUMBERS
+        .iter()
+        .any(|n| &magic_number[..n.len()] == *n)
+    {
+        (Lilac::from_mp3(reader)?, Format::Mp3)
+    } else if FLAC_MAGIC_NUMBER == &magic_number[..FLAC_MAGIC_NUMBER.len()] {
+        (Lilac::from_flac(reader)?, Format::Flac)
+    } else if OGG_MAGIC_NUMBER == &magic_number[..OGG_MAGIC_NUMBER.len()] {
+        (Lilac::from_ogg(reader)?, Format::Ogg)
+    } else if WAV_MAGIC_NUMBER
+        == &magic_number[WAV_MAGIC_NUMBER_OFFSET..WAV_MAGIC_NUMBER_OFFSET + WAV_MAGIC_NUMBER.len()]
+    {
+        (Lilac::from_wav(reader)?, Format::Wav)
+    } else {
+        (Lilac::read(reader)?, Format::Lilac)
+    };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lilac-format-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn replace_writes_through_unique_temp() {
+        let dir = dir("replace");
+        let path = dir.join("a.lilac");
+        let unrelated = dir.join("a.lilac.tmp");
+        fs::write(&unrelated, "keep").unwrap();
+
+        let mut used = None;
+        replace(&path, |temp| {
+            used = Some(temp.to_owned());
+            fs::write(temp, "new")
+        })
+        .unwrap();
+        assert_ne!(used.as_deref(), Some(unrelated.as_path()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&unrelated).unwrap(), "keep");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_replace_keeps_destination() {
+        let dir = dir("failed");
+        let path = dir.join("a.wav");
+        fs::write(&path, "old").unwrap();
+
+        let result = replace(&path, |temp| {
+            fs::write(temp, "partial")?;
+            Err(anyhow::anyhow!("verification failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(Format::from_path(Path::new("a.FLAC")), Some(Format::Flac));
+        assert_eq!(Format::from_path(Path::new("a.lilac")), Some(Format::Lilac));
+        assert_eq!(Format::from_path(Path::new("a")), None);
+        assert_eq!(Format::from_path(Path::new("a.txt")), None);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::format;
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::{fs, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Opt {
+    /// Files to describe, in any importable format
+    #[structopt(name = "FILES", required = true)]
+    files: Vec<PathBuf>,
+    /// Print a JSON array instead, one object per file
+    #[structopt(short, long)]
+    json: bool,
+}
+
+/// File metadata and stream properties
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Info {
+    path: PathBuf,
+    format: &'static str,
+    encoding: String,
+    size: u64,
+
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    genre: Option<String>,
+    year: Option<i32>,
+    track: Option<u32>,
+    disc: Option<u32>,
+
+    channels: u16,
+    sample_rate: u32,
+    bit_depth: u32,
+    frames: usize,
+    /// Duration in seconds
+    duration: f64,
+}
+
+pub fn main(opt: Opt) -> crate::Result {
+    let mut infos = Vec::with_capacity(opt.files.len());
+    let mut failed = 0;
+    for path in opt.files {
+        match info(path) {
+            Ok(i) => infos.push(i),
+            Err(e) => {
+                eprintln!("{:#}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&infos)?);
+    } else {
+        for (n, i) in infos.iter().enumerate() {
+            if n > 0 {
+                println!();
+            }
+            print(i);
+        }
+    }
+
+    if failed > 0 {
+        bail!("Failed to read {} files", failed);
+    }
+    crate::OK
+}
+
+fn info(path: PathBuf) -> anyhow::Result<Info> {
+    let size = fs::metadata(&path)
+        .with_context(|| format!("Failed to read `{}`", path.display()))?
+        .len();
+    let (lilac, format) =
+        format::read(&path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+
+    Ok(Info {
+        format: format.extension(),
+        encoding: format.encoding(&lilac),
+        size,
+        frames: lilac.frames(),
+        duration: lilac.duration().as_secs_f64(),
+        channels: lilac.channels,
+        sample_rate: lilac.sample_rate,
+        bit_depth: lilac.bit_depth,
+        title: lilac.title,
+        artist: lilac.artist,
+        album: lilac.album,
+        album_artist: lilac.album_artist,
+        genre: lilac.genre,
+        year: lilac.year,
+        track: lilac.track,
+        disc: lilac.disc,
+        path,
+    })
+}
+
+fn print(i: &Info) {
+    let unknown = |s: &Option<String>| s.clone().unwrap_or_else(|| "Unknown".to_owned());
+    let millis = (i.duration * 1000.0).round() as u64;
+
+    println!("{}", i.path.display());
+    println!("  Title        {}", unknown(&i.title));
+    println!("  Artist       {}", unknown(&i.artist));
+    println!("  Album        {}", unknown(&i.album));
+    if let Some(a) = &i.album_artist {
+        println!("  Album artist {}", a);
+    }
+    if let Some(g) = &i.genre {
+        println!("  Genre        {}", g);
+    }
+    if let Some(y) = i.year {
+        println!("  Year         {}", y);
+    }
+    if let Some(n) = i.track {
+        println!("  Track        {}", n);
+    }
+    if let Some(d) = i.disc {
+        println!("  Disc         {}", d);
+    }
+    println!("  Channels     {}", i.channels);
+    println!("  Sample rate  {} Hz", i.sample_rate);
+    println!("  Bit depth    {} bits", i.bit_depth);
+    println!(
+        "  Duration     {}:{:02}.{:03}",
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    );
+    println!("  Frames       {}", i.frames);
+    println!("  Format       {} ({})", i.format, i.encoding);
+    println!("  Size         {}", size(i.size));
+}
+
+fn size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {} ({} B)", value, UNITS[unit], bytes)
+}
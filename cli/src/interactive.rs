@@ -1,3 +1,17 @@
+#[cfg(feature = "mpris")]
+use crate::mpris;
+use crate::{
+    browser::{self, Browser, BrowserInput},
+    config::{self, Action, Config, CROSSFADES, FADE, MAX_PREAMP},
+    eq::Presets,
+    library::{Library, Search, SearchInput},
+    overview::Overview,
+    player::{Player, Recent, Status},
+    playlist,
+    queue::{self, Queue, QueueEl, Repeat},
+    session::Session,
+    visualizer::{self, Analyzer, Mode},
+};
 use anyhow::Context;
 use crossterm::event::KeyCode;
 use crossterm::{
@@ -5,14 +19,15 @@ use crossterm::{
     terminal::{EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
-use lilac::Lilac;
-use rayon::prelude::*;
-use rodio::{Sink, Source};
+use lilac::{DspHandle, FilterKind, GainMode, Lilac};
 use std::{
-    io::{self, Write},
-    path::{Path, PathBuf},
+    env, io, mem,
+    path::PathBuf,
     process,
-    sync::mpsc::{self, Sender},
+    sync::{
+        mpsc::{self, Sender},
+        OnceLock,
+    },
     thread,
     time::{Duration, Instant},
 };
@@ -20,129 +35,85 @@ use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout, Rect},
     style::{self, Color, Style},
-    widgets, Frame, Terminal,
+    symbols, widgets, Frame, Terminal,
 };
 
-const TICK_RATE: Duration = Duration::from_millis(100);
-
-static BOLD: Style = Style::new().modifier(style::Modifier::BOLD);
-static WHITE: Style = Style::new().fg(Color::White);
-
-struct Queue {
-    songs: Vec<(Lilac, PathBuf)>,
-    cursor: usize,
-}
-struct QueueEl<'a> {
-    idx: usize,
-    lilac: &'a Lilac,
-}
-
-impl Queue {
-    fn new<'a, P>(files: &'a [P]) -> Result<Self, lilac::Error>
-    where
-        P: AsRef<Path> + Sync,
-        &'a [P]: IntoParallelIterator<Item = &'a P>,
-    {
-        Ok(Self {
-            songs: files
-                .par_iter()
-                .filter_map(|f| match Lilac::read_file(&f) {
-                    Ok(l) => Some((l, f.as_ref().to_owned())),
-                    Err(e) => {
-                        io::stderr().lock().write_fmt(format_args!("{}", e)).ok();
-                        None
-                    }
-                })
-                .collect(),
-            cursor: 0,
-        })
-    }
-    fn is_empty(&self) -> bool {
-        self.songs.is_empty()
-    }
+const MAX_EQ_GAIN: f32 = 24.0;
+/// Frames shown by the oscilloscope
+const SCOPE_FRAMES: usize = 1024;
+/// Playlist the queue is saved to, unless a single playlist was loaded
+const QUEUE_PLAYLIST: &str = "queue.m3u8";
 
-    fn current(&self) -> QueueEl {
-        let (l, _) = &self.songs[self.cursor];
-        QueueEl {
-            idx: self.cursor,
-            lilac: l,
-        }
-    }
-    fn files(&self) -> Vec<&str> {
-        self.songs
-            .iter()
-            .map(|(_, p)| p.file_stem().unwrap().to_str().unwrap())
-            .collect()
-    }
+static STYLES: OnceLock<Styles> = OnceLock::new();
 
-    fn next(&mut self) -> bool {
-        if self.cursor == self.songs.len() - 1 {
-            return false;
-        }
-
-        self.cursor += 1;
-        true
-    }
-    fn prev(&mut self) -> bool {
-        if self.cursor == 0 {
-            return false;
-        }
-
-        self.cursor -= 1;
-        true
-    }
+/// Styles derived from the configured theme
+struct Styles {
+    bold: Style,
+    text: Style,
+    unplayed: Style,
+    selected: Style,
 }
 
-struct Stopwatch {
-    time: Duration,
-    started: Instant,
-    running: bool,
+fn styles() -> &'static Styles {
+    STYLES.get_or_init(|| Styles::new(&config::Theme::default()))
 }
 
-impl Stopwatch {
-    fn new() -> Self {
+impl Styles {
+    fn new(theme: &config::Theme) -> Self {
         Self {
-            time: Duration::new(0, 0),
-            started: Instant::now(),
-            running: false,
+            bold: Style::new()
+                .fg(theme.accent.0)
+                .modifier(style::Modifier::BOLD),
+            text: Style::new().fg(theme.text.0),
+            unplayed: Style::new().fg(theme.dim.0),
+            selected: Style::new().fg(Color::Black).bg(theme.selection.0),
         }
     }
+}
 
-    fn start(&mut self) {
-        if self.running {
-            return;
-        }
-        self.running = true;
-        self.started = Instant::now();
-    }
-    fn stop(&mut self) {
-        if !self.running {
-            return;
-        }
-        self.running = false;
-        self.time += self.started.elapsed();
-    }
+pub fn main(files: Vec<String>, config: &Config, resume: bool) -> crate::Result {
+    let keymap = config.keymap()?;
+    STYLES.get_or_init(|| Styles::new(&config.theme));
 
-    fn reset(&mut self) {
-        self.time = Duration::new(0, 0);
-        self.started = Instant::now();
-    }
+    // A damaged session file only matters when resuming it
+    let session = if resume {
+        Some(Session::load()?.context("No saved session to resume")?)
+    } else {
+        None
+    };
 
-    fn time(&self) -> Duration {
-        if self.running {
-            self.time + self.started.elapsed()
+    println!("Loading...");
+    let mut paths = Vec::new();
+    if let Some(s) = &session {
+        paths.extend(s.files.iter().cloned());
+    }
+    let mut playlists = Vec::new();
+    for f in files.iter().map(PathBuf::from) {
+        if playlist::Format::from_path(&f).is_some() {
+            paths.extend(playlist::read(&f)?.into_iter().map(|e| e.path));
+            playlists.push(f);
         } else {
-            self.time
+            paths.push(f);
         }
     }
-}
+    let save_path = match (playlists.len(), files.len()) {
+        (1, 1) => playlists.remove(0),
+        _ => PathBuf::from(QUEUE_PLAYLIST),
+    };
 
-pub fn main(files: Vec<String>) -> crate::Result {
-    println!("Loading...");
-    let mut queue = Queue::new(&files)?;
+    let mut queue = Queue::new(&paths)?;
     if queue.is_empty() {
         return crate::OK;
     }
+    let mut position = Duration::new(0, 0);
+    if let Some(s) = &session {
+        queue.set_repeat(s.repeat);
+        queue.set_shuffle(s.shuffle);
+        if let Some(idx) = queue.paths().iter().position(|&p| p == s.current) {
+            queue.resume(idx);
+            position = Duration::from_secs_f64(s.position);
+        }
+    }
     let device = rodio::default_output_device().context("No audio output device")?;
 
     crossterm::terminal::enable_raw_mode()?;
@@ -154,118 +125,411 @@ pub fn main(files: Vec<String>) -> crate::Result {
     terminal.clear()?;
 
     let (tx, rx) = mpsc::channel();
+    let loader = tx.clone();
+    let tick_rate = config.player.tick_rate();
+    #[cfg(feature = "mpris")]
+    let mpris = {
+        let tx = tx.clone();
+        mpris::spawn(move |c| tx.send(Event::Remote(c)).is_ok())
+    };
     thread::spawn(move || {
-        if let Err(e) = poll(tx) {
+        if let Err(e) = poll(tx, tick_rate) {
             eprintln!("{:#}", e);
             process::exit(1);
         }
     });
 
-    let mut stopwatch = Stopwatch::new();
-
-    let source = queue.current().lilac.clone().source();
-    let mut sink = Sink::new(&device);
+    let presets = Presets::load()?;
+    let dsp = DspHandle::new(presets.chain());
+    let player = Player::new(&device, dsp.clone())?;
 
     let mut state = State {
         controls: ControlsState {
             playback: PlaybackState {
                 playing: false,
                 played: Duration::new(0, 0),
-                duration: source.total_duration().unwrap(),
+                duration: queue.current().lilac.duration(),
+                overview: None,
+            },
+            volume: VolumeState(session.as_ref().map_or(config.player.volume, |s| s.volume)),
+            gain: GainState {
+                mode: config.player.gain.0,
+                preamp: config.player.preamp,
+            },
+            transition: TransitionState {
+                crossfade: CROSSFADES
+                    .iter()
+                    .position(|&c| c == config.player.crossfade)
+                    .unwrap_or(0),
+                fade: config.player.fade,
+            },
+            order: OrderState {
+                shuffle: queue.shuffle,
+                repeat: queue.repeat,
             },
-            volume: VolumeState(100),
         },
         info: InfoState::read(&queue),
+        panes: PanesState {
+            focus: Focus::Player,
+            selected: 0,
+            browser: None,
+            library: None,
+            search: None,
+            enqueue: Vec::new(),
+            message: None,
+        },
+        eq: EqState {
+            presets,
+            open: false,
+            row: 0,
+            message: None,
+        },
+        visualizer: VisualizerState {
+            mode: Mode::Off,
+            analyzer: Analyzer::new(),
+            bands: Vec::new(),
+            scope: Vec::new(),
+        },
     };
 
-    sink.set_volume(state.controls.volume.0 as f32 / 100.0);
-    sink.append(source);
-    sink.pause();
+    player.set_volume(state.controls.sink_volume());
+    player.set_crossfade(state.controls.transition.crossfade());
+    player.set_fade(state.controls.transition.fade());
 
-    macro_rules! reset {
+    macro_rules! preload {
         () => {{
-            sink.stop();
-            sink = Sink::new(&device);
+            match queue.upcoming() {
+                Some(QueueEl { id, lilac, .. }) => player.preload(
+                    queue.current().id,
+                    id,
+                    lilac,
+                    state.controls.gain.factor(lilac),
+                ),
+                None => player.unload_next(),
+            }
+        }};
+    }
+    macro_rules! overview {
+        ($path:expr, $lilac:expr) => {{
+            state.controls.playback.overview = None;
+            let (tx, path, lilac) = (loader.clone(), $path.to_owned(), $lilac.clone());
+            thread::spawn(move || {
+                let overview = Overview::load(&path, &lilac);
+                tx.send(Event::Overview(path, overview)).ok()
+            });
+        }};
+    }
+    macro_rules! load {
+        () => {{
+            let QueueEl {
+                id, lilac, path, ..
+            } = queue.current();
+            player.load(id, lilac, state.controls.gain.factor(lilac));
+            preload!();
 
-            let source = queue.current().lilac.clone().source();
             state.controls.playback.played = Duration::new(0, 0);
-            state.controls.playback.duration = source.total_duration().unwrap();
+            state.controls.playback.duration = lilac.duration();
+            overview!(path, lilac);
             state.info = InfoState::read(&queue);
-
-            sink.set_volume(state.controls.volume.0 as f32 / 100.0);
-            sink.append(source);
-            if state.controls.playback.playing {
-                sink.play();
-            } else {
-                sink.pause();
+        }};
+    }
+    macro_rules! regain {
+        () => {{
+            let QueueEl { id, lilac, .. } = queue.current();
+            player.set_gain(id, state.controls.gain.factor(lilac));
+            if let Some(QueueEl { id, lilac, .. }) = queue.upcoming() {
+                player.set_gain(id, state.controls.gain.factor(lilac));
             }
+        }};
+    }
+
+    #[cfg(feature = "mpris")]
+    let mpris = match mpris {
+        Ok(m) => Some(m),
+        Err(e) => {
+            state.panes.message = Some(format!("MPRIS is unavailable: {}", e));
+            None
+        }
+    };
 
-            stopwatch.reset()
+    macro_rules! seek {
+        ($position:expr) => {{
+            let position = $position;
+            player.seek(queue.current().lilac, position);
+            state.controls.playback.played = position;
+            #[cfg(feature = "mpris")]
+            if let Some(m) = &mpris {
+                m.seeked(position);
+            }
         }};
     }
 
+    load!();
+    if position > Duration::new(0, 0) {
+        seek!(position);
+    }
+    let mut played_advances = 0;
+
     loop {
         terminal.draw(|mut f| draw(&mut f, &state))?;
-        match rx.recv()? {
-            Event::Input(KeyEvent { code, .. }) => match code {
-                KeyCode::Char(' ') => {
-                    state.controls.playback.playing = !state.controls.playback.playing;
-                    if state.controls.playback.playing {
-                        sink.play();
-                        stopwatch.start();
-                    } else {
-                        sink.pause();
-                        stopwatch.stop();
+        #[cfg(feature = "mpris")]
+        if let Some(m) = &mpris {
+            m.update(snapshot(&queue, &state.controls));
+        }
+        let action = match rx.recv()? {
+            Event::Input(KeyEvent { code, .. }) if state.eq.open && state.eq.input(code) => {
+                dsp.set_chain(state.eq.presets.chain());
+                continue;
+            }
+            Event::Input(KeyEvent { code, .. })
+                if (state.panes.focus == Focus::Browser && state.panes.browse(code))
+                    || (state.panes.focus == Focus::Search && state.panes.search(code)) =>
+            {
+                let entries = mem::take(&mut state.panes.enqueue);
+                if !entries.is_empty() {
+                    state.panes.message = Some("Adding songs…".to_owned());
+                    let tx = loader.clone();
+                    thread::spawn(move || tx.send(load(&entries)).ok());
+                }
+                continue;
+            }
+            Event::Overview(path, overview) => {
+                if queue.current().path == path {
+                    state.controls.playback.overview = Some(overview);
+                }
+                continue;
+            }
+            Event::Loaded(songs, failed) => {
+                let added = songs.len();
+                queue.append(songs);
+                preload!();
+                state.info = InfoState::read(&queue);
+                state.panes.message = Some(match failed.first() {
+                    None => format!("Added {} songs", added),
+                    Some((p, e)) => format!("Added {} songs, `{}`: {}", added, p.display(), e),
+                });
+                continue;
+            }
+            Event::Input(KeyEvent { code, .. })
+                if state.panes.focus == Focus::Queue && state.panes.is_queue_key(code) =>
+            {
+                state.panes.message = None;
+                let selected = state.panes.selected;
+                match code {
+                    KeyCode::Up => state.panes.selected = selected.saturating_sub(1),
+                    KeyCode::Down => state.panes.selected = (selected + 1).min(queue.len() - 1),
+                    KeyCode::Enter => {
+                        queue.jump(selected);
+                        load!();
                     }
+                    KeyCode::Char('d') | KeyCode::Delete => {
+                        if queue.remove(selected) {
+                            state.panes.selected = selected.min(queue.len() - 1);
+                            preload!();
+                            state.info = InfoState::read(&queue);
+                        } else {
+                            state.panes.message = Some("Can't remove the current song".to_owned());
+                        }
+                    }
+                    KeyCode::Char('K') | KeyCode::Char('J') => {
+                        state.panes.selected = queue.shift(selected, code == KeyCode::Char('K'));
+                        preload!();
+                        state.info = InfoState::read(&queue);
+                    }
+                    _ => state.panes.focus = Focus::Player,
                 }
+                continue;
+            }
+            Event::Input(KeyEvent { code, .. }) => match keymap.get(&code) {
+                Some(&action) => action,
+                None => continue,
+            },
+            Event::Tick => {
+                let Status {
+                    position,
+                    advances,
+                    ended,
+                } = player.status();
+                if advances != played_advances {
+                    for _ in played_advances..advances {
+                        queue.advance();
+                    }
+                    played_advances = advances;
+                    preload!();
+
+                    let QueueEl { lilac, path, .. } = queue.current();
+                    state.controls.playback.duration = lilac.duration();
+                    overview!(path, lilac);
+                    state.info = InfoState::read(&queue);
+                } else if ended {
+                    queue.restart();
 
-                KeyCode::Right => {
-                    if !queue.next() {
+                    state.controls.playback.playing = false;
+                    player.set_playing(false);
+                    load!();
+                }
+                state.controls.playback.played = position;
+                if state.visualizer.mode != Mode::Off {
+                    state.visualizer.update(player.recent());
+                }
+                continue;
+            }
+            #[cfg(feature = "mpris")]
+            Event::Remote(command) => match command {
+                mpris::Command::PlayPause => Action::PlayPause,
+                mpris::Command::Play | mpris::Command::Pause => {
+                    let play = matches!(command, mpris::Command::Play);
+                    if play == state.controls.playback.playing {
                         continue;
                     }
-                    reset!();
+                    Action::PlayPause
                 }
-                KeyCode::Left => {
-                    if stopwatch.time() < Duration::from_secs(2) {
-                        queue.prev();
-                    }
-                    reset!();
+                mpris::Command::Stop => {
+                    state.controls.playback.playing = false;
+                    player.set_playing(false);
+                    seek!(Duration::new(0, 0));
+                    continue;
                 }
-
-                KeyCode::Up => {
-                    if state.controls.volume.0 < 100 {
-                        state.controls.volume.0 += 1;
-                        sink.set_volume(state.controls.volume.0 as f32 / 100.0);
+                mpris::Command::Next => Action::Next,
+                mpris::Command::Previous => Action::Previous,
+                mpris::Command::Seek(offset) => {
+                    let position = state.controls.playback.played.as_micros() as i64 + offset;
+                    let position = Duration::from_micros(position.max(0) as u64);
+                    if position > state.controls.playback.duration {
+                        Action::Next
+                    } else {
+                        seek!(position);
+                        continue;
                     }
                 }
-                KeyCode::Down => {
-                    if state.controls.volume.0 > 0 {
-                        state.controls.volume.0 -= 1;
-                        sink.set_volume(state.controls.volume.0 as f32 / 100.0);
+                mpris::Command::SetPosition(id, position) => {
+                    let position = Duration::from_micros(position.max(0) as u64);
+                    if id == queue.current().id && position <= state.controls.playback.duration {
+                        seek!(position);
                     }
+                    continue;
                 }
-
-                KeyCode::Esc => break,
-                _ => continue,
+                mpris::Command::Volume(volume) => {
+                    state.controls.volume.0 = (volume * 100.0).round() as u16;
+                    player.set_volume(state.controls.sink_volume());
+                    continue;
+                }
+                mpris::Command::Quit => Action::Quit,
             },
+        };
 
-            Event::Tick => {
-                state.controls.playback.played = stopwatch.time();
-                if state.controls.playback.played >= state.controls.playback.duration
-                    && state.controls.playback.playing
-                {
-                    if queue.next() {
-                        reset!();
-                    } else {
-                        while queue.prev() {}
+        match action {
+            Action::PlayPause => {
+                state.controls.playback.playing = !state.controls.playback.playing;
+                player.set_playing(state.controls.playback.playing);
+            }
+
+            Action::Next => {
+                if !queue.next() {
+                    continue;
+                }
+                load!();
+            }
+            Action::Previous => {
+                if state.controls.playback.played < Duration::from_secs(2) {
+                    queue.prev();
+                }
+                load!();
+            }
+
+            Action::VolumeUp => {
+                if state.controls.volume.0 < 100 {
+                    state.controls.volume.0 += 1;
+                    player.set_volume(state.controls.sink_volume());
+                }
+            }
+            Action::VolumeDown => {
+                if state.controls.volume.0 > 0 {
+                    state.controls.volume.0 -= 1;
+                    player.set_volume(state.controls.sink_volume());
+                }
+            }
+
+            Action::GainMode => {
+                state.controls.gain.mode = state.controls.gain.mode.next();
+                regain!();
+            }
+            Action::PreampUp => {
+                if state.controls.gain.preamp < MAX_PREAMP {
+                    state.controls.gain.preamp += 1.0;
+                    regain!();
+                }
+            }
+            Action::PreampDown => {
+                if state.controls.gain.preamp > -MAX_PREAMP {
+                    state.controls.gain.preamp -= 1.0;
+                    regain!();
+                }
+            }
+
+            Action::Shuffle => {
+                queue.set_shuffle(!queue.shuffle);
+                state.controls.order.shuffle = queue.shuffle;
+                preload!();
+            }
+            Action::Repeat => {
+                queue.set_repeat(queue.repeat.next());
+                state.controls.order.repeat = queue.repeat;
+                preload!();
+            }
 
-                        state.controls.playback.playing = false;
-                        sink.pause();
-                        stopwatch.stop();
-                        reset!();
+            Action::Crossfade => {
+                let t = &mut state.controls.transition;
+                t.crossfade = (t.crossfade + 1) % CROSSFADES.len();
+                player.set_crossfade(t.crossfade());
+            }
+            Action::Fade => {
+                let t = &mut state.controls.transition;
+                t.fade = !t.fade;
+                player.set_fade(t.fade());
+            }
+
+            Action::Equalizer => state.eq.open = true,
+            Action::Focus => state.panes.cycle(queue.current().idx),
+            Action::Browser => {
+                if state.panes.browser.is_none() {
+                    let dir = env::current_dir()?;
+                    state.panes.browser = Some(Browser::open(dir)?);
+                }
+                state.panes.focus = Focus::Browser;
+            }
+            Action::Search => {
+                if state.panes.library.is_none() {
+                    state.panes.library = Some(Library::load()?);
+                }
+                if let Some(l) = &state.panes.library {
+                    if l.tracks.is_empty() {
+                        state.panes.message =
+                            Some("The library is empty, see `lilac library scan`".to_owned());
+                    } else {
+                        state.panes.search = Some(Search::new(l));
+                        state.panes.focus = Focus::Search;
                     }
                 }
             }
+            Action::SaveQueue => {
+                state.panes.message = Some(match queue.save(&save_path) {
+                    Ok(()) => format!("Saved queue to {}", save_path.display()),
+                    Err(e) => format!("{:#}", e),
+                });
+            }
+            Action::Visualizer => {
+                state.visualizer.mode = state.visualizer.mode.next();
+                state.visualizer.update(player.recent());
+            }
+
+            Action::Quit => {
+                if state.controls.playback.playing {
+                    player.set_playing(false);
+                    thread::sleep(player.fade());
+                }
+                break;
+            }
         }
     }
 
@@ -274,53 +538,143 @@ pub fn main(files: Vec<String>) -> crate::Result {
 
     crossterm::terminal::disable_raw_mode()?;
 
-    crate::OK
+    Session::new(
+        &queue,
+        state.controls.playback.played,
+        state.controls.volume.0,
+    )
+    .save()
 }
 
 enum Event<T> {
     Input(T),
     Tick,
+    /// Songs read for the queue, and the entries that failed
+    Loaded(Vec<(Lilac, PathBuf)>, Vec<(PathBuf, String)>),
+    /// Waveform overview computed for a track
+    Overview(PathBuf, Overview),
+    #[cfg(feature = "mpris")]
+    Remote(mpris::Command),
+}
+
+/// Expands and reads entries picked in the browser or search, off the UI thread
+fn load<T>(entries: &[PathBuf]) -> Event<T> {
+    let mut files = Vec::new();
+    let mut failed = Vec::new();
+    for e in entries {
+        match browser::expand(e) {
+            Ok(f) => files.extend(f),
+            Err(err) => failed.push((e.clone(), err.to_string())),
+        }
+    }
+
+    let (songs, errors) = queue::read(&files);
+    failed.extend(errors.into_iter().map(|(p, e)| (p, e.to_string())));
+    Event::Loaded(songs, failed)
 }
 
-fn poll(tx: Sender<Event<KeyEvent>>) -> crate::Result {
+/// Sends terminal and tick events until the receiving end is dropped
+fn poll(tx: Sender<Event<KeyEvent>>, tick_rate: Duration) -> crate::Result {
     let mut last_tick = Instant::now();
     loop {
-        if event::poll(TICK_RATE - last_tick.elapsed())? {
+        if event::poll(
+            tick_rate
+                .checked_sub(last_tick.elapsed())
+                .unwrap_or_default(),
+        )? {
             if let TerminalEvent::Key(k) = event::read()? {
-                tx.send(Event::Input(k))?;
-                if let KeyEvent {
-                    code: KeyCode::Esc, ..
-                } = k
-                {
+                if tx.send(Event::Input(k)).is_err() {
                     break crate::OK;
                 }
             }
         }
-        if last_tick.elapsed() >= TICK_RATE {
-            tx.send(Event::Tick)?;
+        if last_tick.elapsed() >= tick_rate {
+            if tx.send(Event::Tick).is_err() {
+                break crate::OK;
+            }
             last_tick = Instant::now();
         }
     }
 }
 
+#[cfg(feature = "mpris")]
+fn snapshot(queue: &Queue, controls: &ControlsState) -> mpris::Snapshot {
+    let QueueEl {
+        id, lilac, path, ..
+    } = queue.current();
+    mpris::Snapshot {
+        playing: controls.playback.playing,
+        volume: controls.sink_volume() as f64,
+        position: controls.playback.played,
+        song: Some(mpris::Song {
+            id,
+            path: path.to_owned(),
+            title: lilac.title.clone(),
+            artist: lilac.artist.clone(),
+            album: lilac.album.clone(),
+            track: lilac.track,
+            duration: lilac.duration(),
+        }),
+    }
+}
+
 struct State {
     controls: ControlsState,
     info: InfoState,
+    panes: PanesState,
+    eq: EqState,
+    visualizer: VisualizerState,
 }
 struct ControlsState {
     playback: PlaybackState,
     volume: VolumeState,
+    gain: GainState,
+    transition: TransitionState,
+    order: OrderState,
 }
 struct PlaybackState {
     playing: bool,
     played: Duration,
     duration: Duration,
+    overview: Option<Overview>,
 }
 struct VolumeState(u16);
+struct GainState {
+    mode: GainMode,
+    preamp: f32,
+}
+struct TransitionState {
+    crossfade: usize,
+    fade: bool,
+}
+struct OrderState {
+    shuffle: bool,
+    repeat: Repeat,
+}
 struct InfoState {
     metadata: MetadataState,
     queue: QueueState,
 }
+/// Focusable panes next to the player controls
+struct PanesState {
+    focus: Focus,
+    /// Selected song in the queue pane
+    selected: usize,
+    browser: Option<Browser>,
+    /// Library index, loaded when first searched
+    library: Option<Library>,
+    search: Option<Search>,
+    /// Files picked in the browser or search, waiting to be read
+    enqueue: Vec<PathBuf>,
+    message: Option<String>,
+}
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Focus {
+    Player,
+    Queue,
+    Browser,
+    Search,
+}
 struct MetadataState {
     title: String,
     artist: String,
@@ -330,18 +684,194 @@ struct MetadataState {
     sample_rate: u32,
     bit_depth: u32,
 }
+struct EqState {
+    presets: Presets,
+    open: bool,
+    row: usize,
+    message: Option<String>,
+}
 struct QueueState {
     queue: Vec<String>,
     current: usize,
 }
+struct VisualizerState {
+    mode: Mode,
+    analyzer: Analyzer,
+    bands: Vec<u64>,
+    scope: Vec<Vec<(f64, f64)>>,
+}
+
+impl ControlsState {
+    fn sink_volume(&self) -> f32 {
+        self.volume.0 as f32 / 100.0
+    }
+}
+impl GainState {
+    fn factor(&self, l: &Lilac) -> f32 {
+        l.gain(self.mode, self.preamp)
+    }
+}
+impl TransitionState {
+    fn crossfade(&self) -> Duration {
+        Duration::from_secs(CROSSFADES[self.crossfade])
+    }
+    fn fade(&self) -> Duration {
+        if self.fade {
+            FADE
+        } else {
+            Duration::new(0, 0)
+        }
+    }
+}
+impl EqState {
+    /// Handles a key press in the EQ panel, returning whether it was used
+    fn input(&mut self, code: KeyCode) -> bool {
+        let rows = self.presets.current().chain.filters.len() + 2;
+        self.message = None;
+        match code {
+            KeyCode::Char('e') | KeyCode::Esc => self.open = false,
+            KeyCode::Up => self.row = self.row.saturating_sub(1),
+            KeyCode::Down => self.row = (self.row + 1).min(rows - 1),
+            KeyCode::Left => self.adjust(-0.5),
+            KeyCode::Right => self.adjust(0.5),
+            KeyCode::Char('-') => self.shift(-1.0),
+            KeyCode::Char('=') => self.shift(1.0),
+            KeyCode::Tab => {
+                self.presets.cycle();
+                self.row = 0;
+            }
+            KeyCode::Char('x') => self.presets.enabled = !self.presets.enabled,
+            KeyCode::Char('l') => {
+                let limiter = &mut self.presets.current_mut().chain.limiter;
+                *limiter = match limiter {
+                    Some(_) => None,
+                    None => Some(lilac::Limiter::new(-0.3, 100.0)),
+                };
+            }
+            KeyCode::Char('s') => {
+                self.message = Some(match self.presets.save() {
+                    Ok(()) => "Saved".to_owned(),
+                    Err(e) => format!("{:#}", e),
+                })
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Adjusts the gain of the selected row in dB
+    fn adjust(&mut self, delta: f32) {
+        let row = self.row;
+        let chain = &mut self.presets.current_mut().chain;
+        let filters = chain.filters.len();
+        if row == 0 {
+            chain.preamp = (chain.preamp + delta).clamp(-MAX_EQ_GAIN, MAX_EQ_GAIN);
+        } else if row <= filters {
+            let f = &mut chain.filters[row - 1];
+            f.gain = (f.gain + delta).clamp(-MAX_EQ_GAIN, MAX_EQ_GAIN);
+        } else if let Some(l) = &mut chain.limiter {
+            l.threshold = (l.threshold + delta).clamp(-MAX_EQ_GAIN, 0.0);
+        }
+    }
+    /// Shifts the frequency of the selected filter by thirds of an octave
+    fn shift(&mut self, thirds: f32) {
+        let row = self.row;
+        let chain = &mut self.presets.current_mut().chain;
+        if row > 0 && row <= chain.filters.len() {
+            let f = &mut chain.filters[row - 1];
+            f.frequency = (f.frequency * 2f32.powf(thirds / 3.0)).clamp(20.0, 20000.0);
+        }
+    }
+}
+impl PanesState {
+    /// Moves the focus to the next pane, starting queue selection at the current song
+    fn cycle(&mut self, current: usize) {
+        self.focus = match self.focus {
+            Focus::Player => {
+                self.selected = current;
+                Focus::Queue
+            }
+            Focus::Queue if self.search.is_some() => Focus::Search,
+            Focus::Queue | Focus::Search if self.browser.is_some() => Focus::Browser,
+            _ => Focus::Player,
+        };
+    }
 
+    fn is_queue_key(&self, code: KeyCode) -> bool {
+        matches!(
+            code,
+            KeyCode::Up
+                | KeyCode::Down
+                | KeyCode::Enter
+                | KeyCode::Delete
+                | KeyCode::Char('d')
+                | KeyCode::Char('J')
+                | KeyCode::Char('K')
+                | KeyCode::Esc
+        )
+    }
+
+    /// Handles a key press in the browser, returning whether it was used
+    fn browse(&mut self, code: KeyCode) -> bool {
+        let browser = match &mut self.browser {
+            Some(b) => b,
+            None => return false,
+        };
+        self.message = None;
+        match browser.input(code) {
+            Ok(BrowserInput::Ignored) => return false,
+            Ok(BrowserInput::Handled) => (),
+            Ok(BrowserInput::Close) => {
+                self.browser = None;
+                self.focus = Focus::Player;
+            }
+            Ok(BrowserInput::Enqueue(files)) => self.enqueue = files,
+            Err(e) => self.message = Some(e.to_string()),
+        }
+        true
+    }
+
+    /// Handles a key press in the library search, returning whether it was used
+    fn search(&mut self, code: KeyCode) -> bool {
+        let (search, library) = match (&mut self.search, &self.library) {
+            (Some(s), Some(l)) => (s, l),
+            _ => return false,
+        };
+        self.message = None;
+        match search.input(library, code) {
+            SearchInput::Ignored => return false,
+            SearchInput::Handled => (),
+            SearchInput::Close => {
+                self.search = None;
+                self.focus = Focus::Player;
+            }
+            SearchInput::Enqueue(files) => self.enqueue = files,
+        }
+        true
+    }
+}
+impl VisualizerState {
+    fn update(&mut self, r: Recent) {
+        match self.mode {
+            Mode::Off => (),
+            Mode::Spectrum => {
+                self.bands = self
+                    .analyzer
+                    .spectrum(&r.samples, r.channels, r.sample_rate)
+            }
+            Mode::Oscilloscope => {
+                self.scope = visualizer::oscilloscope(&r.samples, r.channels, SCOPE_FRAMES)
+            }
+        }
+    }
+}
 impl InfoState {
     fn read(q: &Queue) -> Self {
-        let QueueEl { idx, lilac } = q.current();
+        let QueueEl { idx, lilac, .. } = q.current();
         Self {
             metadata: MetadataState::read(lilac),
             queue: QueueState {
-                queue: q.files().into_iter().map(ToOwned::to_owned).collect(),
+                queue: q.files(),
                 current: idx,
             },
         }
@@ -368,18 +898,42 @@ fn draw<T: Backend>(f: &mut Frame<T>, s: &State) {
         .split(f.size());
 
     draw_controls(f, &s.controls, chunks[1]);
-    draw_info(f, &s.info, chunks[0]);
+    if s.eq.open {
+        draw_eq(f, &s.eq, chunks[0]);
+    } else if s.visualizer.mode != Mode::Off {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
+            .split(chunks[0]);
+
+        draw_info(f, &s.info, &s.panes, chunks[0]);
+        draw_visualizer(f, &s.visualizer, chunks[1]);
+    } else {
+        draw_info(f, &s.info, &s.panes, chunks[0]);
+    }
 }
 
 fn draw_controls<T: Backend>(f: &mut Frame<T>, s: &ControlsState, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
-        .constraints([Constraint::Percentage(75), Constraint::Percentage(25)].as_ref())
+        .constraints(
+            [
+                Constraint::Min(1),
+                Constraint::Length(11),
+                Constraint::Length(14),
+                Constraint::Length(14),
+                Constraint::Percentage(25),
+            ]
+            .as_ref(),
+        )
         .horizontal_margin(2)
         .split(area);
 
     draw_playback(f, &s.playback, chunks[0]);
-    draw_volume(f, &s.volume, chunks[1]);
+    draw_order(f, &s.order, chunks[1]);
+    draw_transition(f, &s.transition, chunks[2]);
+    draw_gain(f, &s.gain, chunks[3]);
+    draw_volume(f, &s.volume, chunks[4]);
 }
 
 fn draw_playback<T: Backend>(f: &mut Frame<T>, s: &PlaybackState, area: Rect) {
@@ -398,26 +952,92 @@ fn draw_playback<T: Backend>(f: &mut Frame<T>, s: &PlaybackState, area: Rect) {
 
     let play_pause_text = [widgets::Text::styled(
         if s.playing { "PLAY  " } else { "PAUSE " },
-        BOLD,
+        styles().bold,
     )];
     let play_pause = widgets::Paragraph::new(play_pause_text.iter()).wrap(false);
     f.render_widget(play_pause, chunks[0]);
 
-    let timeline = widgets::Gauge::default()
-        .ratio((s.played.as_secs_f64() / s.duration.as_secs_f64()).min(1.0))
-        .label("")
-        .style(WHITE);
-    f.render_widget(timeline, chunks[1]);
+    let ratio = (s.played.as_secs_f64() / s.duration.as_secs_f64()).min(1.0);
+    match &s.overview {
+        Some(o) => draw_waveform(f, o, ratio, chunks[1]),
+        None => {
+            let timeline = widgets::Gauge::default()
+                .ratio(ratio)
+                .label("")
+                .style(styles().text);
+            f.render_widget(timeline, chunks[1]);
+        }
+    }
 
     let played = s.played.as_secs();
     let timestamp_text = [widgets::Text::styled(
         format!(" {:02}:{:02}", played / 60, played % 60),
-        BOLD,
+        styles().bold,
     )];
     let timestamp = widgets::Paragraph::new(timestamp_text.iter()).wrap(false);
     f.render_widget(timestamp, chunks[2]);
 }
 
+fn draw_waveform<T: Backend>(f: &mut Frame<T>, o: &Overview, ratio: f64, area: Rect) {
+    let glyphs = o.render(area.width as usize);
+    let head = ((ratio * glyphs.len() as f64) as usize).min(glyphs.len().saturating_sub(1));
+
+    let text = [
+        widgets::Text::styled(glyphs[..head].iter().collect::<String>(), styles().text),
+        widgets::Text::styled(
+            glyphs[head..].iter().take(1).collect::<String>(),
+            styles().selected,
+        ),
+        widgets::Text::styled(
+            glyphs[head..].iter().skip(1).collect::<String>(),
+            styles().unplayed,
+        ),
+    ];
+    f.render_widget(widgets::Paragraph::new(text.iter()).wrap(false), area);
+}
+
+fn draw_order<T: Backend>(f: &mut Frame<T>, s: &OrderState, area: Rect) {
+    let text = [widgets::Text::styled(
+        format!(
+            "{} {}",
+            if s.shuffle { "SHUF" } else { "" },
+            match s.repeat {
+                Repeat::Off => "",
+                Repeat::All => "REP",
+                Repeat::One => "REP1",
+            },
+        ),
+        styles().bold,
+    )];
+    f.render_widget(widgets::Paragraph::new(text.iter()).wrap(false), area);
+}
+
+fn draw_transition<T: Backend>(f: &mut Frame<T>, s: &TransitionState, area: Rect) {
+    let text = [widgets::Text::styled(
+        format!(
+            "{} {}",
+            match CROSSFADES[s.crossfade] {
+                0 => "GAPLESS".to_owned(),
+                secs => format!("XF {}s", secs),
+            },
+            if s.fade { "FADE" } else { "" },
+        ),
+        styles().bold,
+    )];
+    f.render_widget(widgets::Paragraph::new(text.iter()).wrap(false), area);
+}
+
+fn draw_gain<T: Backend>(f: &mut Frame<T>, s: &GainState, area: Rect) {
+    let text = [widgets::Text::styled(
+        match s.mode {
+            GainMode::Off => "RG OFF".to_owned(),
+            m => format!("RG {} {:+}", m.to_string().to_uppercase(), s.preamp),
+        },
+        styles().bold,
+    )];
+    f.render_widget(widgets::Paragraph::new(text.iter()).wrap(false), area);
+}
+
 fn draw_volume<T: Backend>(f: &mut Frame<T>, s: &VolumeState, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
@@ -428,28 +1048,95 @@ fn draw_volume<T: Backend>(f: &mut Frame<T>, s: &VolumeState, area: Rect) {
     let gauge = widgets::Gauge::default()
         .percent(s.0)
         .label("")
-        .style(WHITE);
+        .style(styles().text);
     f.render_widget(gauge, chunks[0]);
 
-    let level_text = [widgets::Text::Styled(format!(" {:3}", s.0).into(), BOLD)];
+    let level_text = [widgets::Text::Styled(
+        format!(" {:3}", s.0).into(),
+        styles().bold,
+    )];
     let level = widgets::Paragraph::new(level_text.iter()).wrap(false);
     f.render_widget(level, chunks[1]);
 }
 
-fn draw_info<T: Backend>(f: &mut Frame<T>, s: &InfoState, area: Rect) {
+fn draw_eq<T: Backend>(f: &mut Frame<T>, s: &EqState, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(2), Constraint::Min(1)].as_ref())
+        .horizontal_margin(4)
+        .split(area);
+
+    let preset = s.presets.current();
+    let header = [
+        widgets::Text::styled(&preset.name, styles().bold),
+        widgets::Text::raw(format!(
+            " {}  {}\n",
+            if s.presets.enabled { "ON" } else { "OFF" },
+            s.message.as_deref().unwrap_or(""),
+        )),
+    ];
+    f.render_widget(
+        widgets::Paragraph::new(header.iter()).wrap(false),
+        chunks[0],
+    );
+
+    let chain = &preset.chain;
+    let mut rows = vec![format!("Preamp     {:+5.1} dB", chain.preamp)];
+    rows.extend(chain.filters.iter().map(|f| {
+        let kind = match f.kind {
+            FilterKind::LowShelf => "Low shelf",
+            FilterKind::HighShelf => "High shelf",
+            FilterKind::Peaking => "Peaking",
+            FilterKind::LowPass => "Low pass",
+            FilterKind::HighPass => "High pass",
+        };
+        match f.kind {
+            FilterKind::LowPass | FilterKind::HighPass => {
+                format!("{:10} {:>8.0} Hz  Q {:.2}", kind, f.frequency, f.q)
+            }
+            _ => format!(
+                "{:10} {:+5.1} dB  {:>8.0} Hz  Q {:.2}",
+                kind, f.gain, f.frequency, f.q
+            ),
+        }
+    }));
+    rows.push(match chain.limiter {
+        Some(l) => format!("Limiter    {:+5.1} dBFS", l.threshold),
+        None => "Limiter    off".to_owned(),
+    });
+
+    let mut state = widgets::ListState::default();
+    state.select(Some(s.row));
+    f.render_stateful_widget(
+        widgets::List::new(rows.iter().map(widgets::Text::raw)).highlight_style(styles().bold),
+        chunks[1],
+        &mut state,
+    );
+}
+
+fn draw_info<T: Backend>(f: &mut Frame<T>, s: &InfoState, p: &PanesState, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([Constraint::Percentage(75), Constraint::Percentage(25)].as_ref())
         .horizontal_margin(4)
         .split(area);
 
-    draw_metadata(f, &s.metadata, chunks[0]);
-    draw_queue(f, &s.queue, chunks[1]);
+    match (&p.search, &p.browser) {
+        (Some(search), _) => draw_search(f, search, p, chunks[0]),
+        (None, Some(b)) => draw_browser(f, b, p, chunks[0]),
+        (None, None) => draw_metadata(f, &s.metadata, p.message.as_deref(), chunks[0]),
+    }
+    draw_queue(f, &s.queue, p, chunks[1]);
 }
 
-fn draw_metadata<T: Backend>(f: &mut Frame<T>, s: &MetadataState, area: Rect) {
+fn draw_metadata<T: Backend>(
+    f: &mut Frame<T>,
+    s: &MetadataState,
+    message: Option<&str>,
+    area: Rect,
+) {
     let text = [
-        widgets::Text::styled(&s.title, BOLD),
+        widgets::Text::styled(&s.title, styles().bold),
         widgets::Text::raw(format!("\n{}", s.artist)),
         widgets::Text::raw(format!("\n{}", s.album)),
         widgets::Text::raw(format!(
@@ -462,17 +1149,140 @@ fn draw_metadata<T: Backend>(f: &mut Frame<T>, s: &MetadataState, area: Rect) {
             },
             s.sample_rate,
         )),
+        widgets::Text::raw(format!("\n\n{}", message.unwrap_or(""))),
     ];
     f.render_widget(widgets::Paragraph::new(text.iter()).wrap(true), area);
 }
 
-fn draw_queue<T: Backend>(f: &mut Frame<T>, s: &QueueState, area: Rect) {
-    let items = s.queue.iter().map(widgets::Text::raw);
+fn draw_browser<T: Backend>(f: &mut Frame<T>, b: &Browser, p: &PanesState, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(2), Constraint::Min(1)].as_ref())
+        .split(area);
+
+    let header = [
+        widgets::Text::styled(b.dir.to_string_lossy(), styles().bold),
+        widgets::Text::raw(format!("  {}\n", p.message.as_deref().unwrap_or(""))),
+    ];
+    f.render_widget(
+        widgets::Paragraph::new(header.iter()).wrap(false),
+        chunks[0],
+    );
+
+    let items = b.entries.iter().map(|e| {
+        if e.is_dir {
+            widgets::Text::raw(format!("{}/", e.name))
+        } else {
+            widgets::Text::raw(e.name.as_str())
+        }
+    });
     let mut state = widgets::ListState::default();
-    state.select(Some(s.current));
+    state.select(Some(b.selected));
     f.render_stateful_widget(
-        widgets::List::new(items).highlight_style(BOLD),
+        widgets::List::new(items).highlight_style(if p.focus == Focus::Browser {
+            styles().selected
+        } else {
+            styles().bold
+        }),
+        chunks[1],
+        &mut state,
+    );
+}
+
+fn draw_search<T: Backend>(f: &mut Frame<T>, s: &Search, p: &PanesState, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(2), Constraint::Min(1)].as_ref())
+        .split(area);
+
+    let header = [
+        widgets::Text::styled(format!("/{}", s.query), styles().bold),
+        widgets::Text::raw(format!(
+            "  {} results  {}\n",
+            s.results.len(),
+            p.message.as_deref().unwrap_or("")
+        )),
+    ];
+    f.render_widget(
+        widgets::Paragraph::new(header.iter()).wrap(false),
+        chunks[0],
+    );
+
+    let items = s.results.iter().map(|t| widgets::Text::raw(t.to_string()));
+    let mut state = widgets::ListState::default();
+    state.select(Some(s.selected));
+    f.render_stateful_widget(
+        widgets::List::new(items).highlight_style(if p.focus == Focus::Search {
+            styles().selected
+        } else {
+            styles().bold
+        }),
+        chunks[1],
+        &mut state,
+    );
+}
+
+fn draw_queue<T: Backend>(f: &mut Frame<T>, s: &QueueState, p: &PanesState, area: Rect) {
+    let items = s.queue.iter().enumerate().map(|(i, q)| {
+        if i == s.current {
+            widgets::Text::styled(q.as_str(), styles().bold)
+        } else {
+            widgets::Text::raw(q.as_str())
+        }
+    });
+    let mut state = widgets::ListState::default();
+    let focused = p.focus == Focus::Queue;
+    state.select(Some(if focused { p.selected } else { s.current }));
+    f.render_stateful_widget(
+        widgets::List::new(items).highlight_style(if focused {
+            styles().selected
+        } else {
+            styles().bold
+        }),
         area,
         &mut state,
     );
 }
+
+fn draw_visualizer<T: Backend>(f: &mut Frame<T>, s: &VisualizerState, area: Rect) {
+    let area = Layout::default()
+        .constraints([Constraint::Min(1)].as_ref())
+        .horizontal_margin(4)
+        .vertical_margin(1)
+        .split(area)[0];
+
+    match s.mode {
+        Mode::Off => (),
+        Mode::Spectrum => {
+            let bars = visualizer::fit(&s.bands, area.width as usize);
+            let data: Vec<(&str, u64)> = bars.iter().map(|&b| ("", b)).collect();
+            let chart = widgets::BarChart::default()
+                .data(&data)
+                .max(100)
+                .bar_width(1)
+                .bar_gap(0)
+                .style(styles().text);
+            f.render_widget(chart, area);
+        }
+        Mode::Oscilloscope => {
+            let channels = s.scope.len().max(1) as u32;
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(vec![Constraint::Ratio(1, channels); channels as usize])
+                .split(area);
+
+            for (points, &area) in s.scope.iter().zip(&chunks) {
+                let datasets = [widgets::Dataset::default()
+                    .data(points)
+                    .marker(symbols::Marker::Braille)
+                    .graph_type(widgets::GraphType::Line)
+                    .style(styles().text)];
+                let chart = widgets::Chart::<String, String>::default()
+                    .x_axis(widgets::Axis::default().bounds([0.0, 1.0]))
+                    .y_axis(widgets::Axis::default().bounds([-1.0, 1.0]))
+                    .datasets(&datasets);
+                f.render_widget(chart, area);
+            }
+        }
+    }
+}
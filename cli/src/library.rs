@@ -0,0 +1,429 @@
+use crate::{config, format};
+use anyhow::Context;
+use crossterm::event::KeyCode;
+use lilac::Lilac;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use structopt::StructOpt;
+use walkdir::WalkDir;
+
+#[derive(StructOpt)]
+pub enum Opt {
+    /// Indexes the LILAC files in directories
+    ///
+    /// Given directories are added to the indexed ones,
+    /// as are the ones listed in the configuration.
+    /// Unchanged files are not read again.
+    Scan {
+        /// Directories to add to the library
+        #[structopt(name = "DIRS")]
+        dirs: Vec<PathBuf>,
+    },
+    /// Lists the tracks matching a query
+    ///
+    /// Words match the title, artist, album or file name.
+    /// Fields can be matched with title:, artist:, album:, year: and track:
+    Search {
+        #[structopt(name = "QUERY", required = true)]
+        query: Vec<String>,
+        /// Only print paths, for use as player arguments
+        #[structopt(short, long)]
+        paths: bool,
+    },
+    /// Lists all tracks, by artist, album and track number
+    List {
+        /// Only print paths, for use as player arguments
+        #[structopt(short, long)]
+        paths: bool,
+    },
+}
+
+/// Index of the tracks in the library directories,
+/// saved in the user data directory
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Library {
+    pub dirs: Vec<PathBuf>,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    pub path: PathBuf,
+    size: u64,
+    modified: u64,
+
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub year: Option<i32>,
+    /// Duration in seconds
+    pub duration: f64,
+
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bit_depth: u32,
+}
+
+/// Outcome of a scan
+#[derive(Default)]
+pub struct Scan {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub failed: Vec<(PathBuf, lilac::Error)>,
+}
+
+/// Search view of the interactive player
+pub struct Search {
+    pub query: String,
+    pub results: Vec<Track>,
+    pub selected: usize,
+}
+
+pub enum SearchInput {
+    Ignored,
+    Handled,
+    Close,
+    /// Files to add to the queue
+    Enqueue(Vec<PathBuf>),
+}
+
+pub fn main(opt: Opt, config: &config::Library) -> crate::Result {
+    let mut library = Library::load()?;
+    match opt {
+        Opt::Scan { mut dirs } => {
+            dirs.extend(config.paths());
+            for d in dirs {
+                let d = d
+                    .canonicalize()
+                    .with_context(|| format!("Failed to read `{}`", d.display()))?;
+                if !library.dirs.contains(&d) {
+                    library.dirs.push(d);
+                }
+            }
+
+            let scan = library.scan()?;
+            for (p, e) in &scan.failed {
+                eprintln!("`{}`: {}", p.display(), e);
+            }
+            library.save()?;
+            println!(
+                "{} tracks, {} added, {} updated, {} removed",
+                library.tracks.len(),
+                scan.added,
+                scan.updated,
+                scan.removed,
+            );
+        }
+        Opt::Search { query, paths } => print(&library.search(&query.join(" ")), paths),
+        Opt::List { paths } => print(&library.search(""), paths),
+    }
+    crate::OK
+}
+
+fn print(tracks: &[&Track], paths: bool) {
+    for t in tracks {
+        if paths {
+            println!("{}", t.path.display());
+        } else {
+            println!("{}", t);
+        }
+    }
+}
+
+impl Library {
+    /// Reads the index, empty when it doesn't exist yet
+    pub fn load() -> anyhow::Result<Self> {
+        let path = match path() {
+            Some(p) if p.exists() => p,
+            _ => return Ok(Self::default()),
+        };
+        serde_json::from_reader(BufReader::new(File::open(&path)?))
+            .with_context(|| format!("Invalid library index in `{}`", path.display()))
+    }
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = path().context("No data directory")?;
+        if let Some(p) = path.parent() {
+            fs::create_dir_all(p)?;
+        }
+        format::replace(&path, |temp| -> anyhow::Result<()> {
+            let mut writer = BufWriter::new(File::create(temp)?);
+            serde_json::to_writer(&mut writer, self)?;
+            writer.flush()?;
+            Ok(())
+        })
+    }
+
+    /// Updates the index from the library directories
+    ///
+    /// Files with the same size and modification time as when
+    /// they were indexed are kept as is.
+    pub fn scan(&mut self) -> anyhow::Result<Scan> {
+        let mut scan = Scan::default();
+        let mut files = Vec::new();
+        for d in &self.dirs {
+            walk(d, &mut files, &mut scan.failed)
+                .with_context(|| format!("Failed to read `{}`", d.display()))?;
+        }
+
+        let mut known: HashMap<PathBuf, Track> =
+            self.tracks.drain(..).map(|t| (t.path.clone(), t)).collect();
+        let mut changed = Vec::new();
+        for (path, size, modified) in files {
+            match known.remove(&path) {
+                Some(t) if t.size == size && t.modified == modified => self.tracks.push(t),
+                Some(_) => changed.push((path, size, modified, true)),
+                None => changed.push((path, size, modified, false)),
+            }
+        }
+        // Tracks under entries that couldn't be read are kept until they can be
+        for (path, t) in known {
+            if scan.failed.iter().any(|(p, _)| path.starts_with(p)) {
+                self.tracks.push(t);
+            } else {
+                scan.removed += 1;
+            }
+        }
+
+        let read: Vec<_> = changed
+            .into_par_iter()
+            .map(|(path, size, modified, known)| {
+                let lilac = Lilac::read_file(&path);
+                (path, size, modified, known, lilac)
+            })
+            .collect();
+        for (path, size, modified, known, lilac) in read {
+            match lilac {
+                Ok(l) => {
+                    if known {
+                        scan.updated += 1;
+                    } else {
+                        scan.added += 1;
+                    }
+                    self.tracks.push(Track::new(path, size, modified, &l));
+                }
+                Err(e) => scan.failed.push((path, e)),
+            }
+        }
+
+        self.tracks.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        Ok(scan)
+    }
+
+    /// Tracks matching all the words of a query, in library order
+    pub fn search(&self, query: &str) -> Vec<&Track> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        self.tracks
+            .iter()
+            .filter(|t| terms.iter().all(|term| t.matches(term)))
+            .collect()
+    }
+}
+
+impl Track {
+    fn new(path: PathBuf, size: u64, modified: u64, l: &Lilac) -> Self {
+        Self {
+            path,
+            size,
+            modified,
+            title: l.title.clone(),
+            artist: l.artist.clone(),
+            album: l.album.clone(),
+            track: l.track,
+            year: l.year,
+            duration: l.duration().as_secs_f64(),
+            channels: l.channels,
+            sample_rate: l.sample_rate,
+            bit_depth: l.bit_depth,
+        }
+    }
+
+    fn sort_key(&self) -> (String, String, Option<u32>, &Path) {
+        let lower = |s: &Option<String>| s.as_deref().unwrap_or("").to_lowercase();
+        (
+            lower(&self.artist),
+            lower(&self.album),
+            self.track,
+            &self.path,
+        )
+    }
+
+    /// Whether a lowercase query term matches, either `field:value` or a plain word
+    fn matches(&self, term: &str) -> bool {
+        let contains = |s: &Option<String>, v: &str| {
+            s.as_deref().is_some_and(|s| s.to_lowercase().contains(v))
+        };
+        let number = |n: Option<i64>, v: &str| n.is_some_and(|n| v.parse() == Ok(n));
+
+        match term.find(':').map(|i| (&term[..i], &term[i + 1..])) {
+            Some(("title", v)) => contains(&self.title, v),
+            Some(("artist", v)) => contains(&self.artist, v),
+            Some(("album", v)) => contains(&self.album, v),
+            Some(("year", v)) => number(self.year.map(i64::from), v),
+            Some(("track", v)) => number(self.track.map(i64::from), v),
+            _ => {
+                contains(&self.title, term)
+                    || contains(&self.artist, term)
+                    || contains(&self.album, term)
+                    || self
+                        .path
+                        .file_name()
+                        .is_some_and(|n| n.to_string_lossy().to_lowercase().contains(term))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Track {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.duration.round() as u64;
+        write!(
+            f,
+            "{} - {} - ",
+            self.artist.as_deref().unwrap_or("Unknown artist"),
+            self.album.as_deref().unwrap_or("Unknown album"),
+        )?;
+        if let Some(n) = self.track {
+            write!(f, "{:02} ", n)?;
+        }
+        match &self.title {
+            Some(t) => write!(f, "{}", t)?,
+            None => write!(f, "{}", self.path.display())?,
+        }
+        write!(f, " ({}:{:02})", secs / 60, secs % 60)
+    }
+}
+
+impl Search {
+    pub fn new(library: &Library) -> Self {
+        let mut search = Self {
+            query: String::new(),
+            results: Vec::new(),
+            selected: 0,
+        };
+        search.update(library);
+        search
+    }
+
+    /// Handles a key press, typed characters going to the query
+    pub fn input(&mut self, library: &Library, code: KeyCode) -> SearchInput {
+        match code {
+            KeyCode::Esc => return SearchInput::Close,
+            KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down => {
+                self.selected = (self.selected + 1).min(self.results.len().saturating_sub(1))
+            }
+            KeyCode::Enter => {
+                if let Some(t) = self.results.get(self.selected) {
+                    return SearchInput::Enqueue(vec![t.path.clone()]);
+                }
+            }
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.update(library);
+            }
+            KeyCode::Char(c) => {
+                self.query.push(c);
+                self.update(library);
+            }
+            _ => return SearchInput::Ignored,
+        }
+        SearchInput::Handled
+    }
+
+    fn update(&mut self, library: &Library) {
+        self.results = library.search(&self.query).into_iter().cloned().collect();
+        self.selected = 0;
+    }
+}
+
+fn path() -> Option<PathBuf> {
+    dirs::data_dir().map(|d| d.join("lilac").join("library.json"))
+}
+
+/// Collects the LILAC files under a directory with their size and modification time
+///
+/// Symbolic links are followed, except the ones leading back to a parent directory.
+/// Entries that can't be read are collected as failed, only the directory itself is required.
+fn walk(
+    dir: &Path,
+    files: &mut Vec<(PathBuf, u64, u64)>,
+    failed: &mut Vec<(PathBuf, lilac::Error)>,
+) -> io::Result<()> {
+    let entries = WalkDir::new(dir)
+        .follow_links(true)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'));
+    for e in entries {
+        let e = match e {
+            Ok(e) => e,
+            Err(e) if e.loop_ancestor().is_some() => continue,
+            Err(e) if e.depth() == 0 => return Err(e.into()),
+            Err(e) => {
+                let path = e.path().unwrap_or(dir).to_owned();
+                failed.push((path, io::Error::from(e).into()));
+                continue;
+            }
+        };
+        let path = e.path();
+        if !e.file_type().is_file()
+            || !path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("lilac"))
+        {
+            continue;
+        }
+
+        let metadata = e.metadata().map_err(io::Error::from).and_then(|m| {
+            let modified = m.modified()?.duration_since(UNIX_EPOCH);
+            Ok((m.len(), modified.map_or(0, |d| d.as_secs())))
+        });
+        match metadata {
+            Ok((size, modified)) => files.push((e.into_path(), size, modified)),
+            Err(err) => failed.push((e.into_path(), err.into())),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn walks_past_loops_and_failures() {
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(format!("lilac-library-{}", std::process::id()));
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join(".hidden")).unwrap();
+        for f in &["a/x.lilac", "y.LILAC", ".hidden/z.lilac", "a/w.txt"] {
+            fs::write(dir.join(f), "").unwrap();
+        }
+        symlink(&dir, dir.join("a/loop")).unwrap();
+        symlink(dir.join("missing"), dir.join("broken")).unwrap();
+
+        let mut files = Vec::new();
+        let mut failed = Vec::new();
+        walk(&dir, &mut files, &mut failed).unwrap();
+        let mut paths: Vec<_> = files.into_iter().map(|(p, _, _)| p).collect();
+        paths.sort();
+        assert_eq!(paths, [dir.join("a/x.lilac"), dir.join("y.LILAC")]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, dir.join("broken"));
+
+        assert!(walk(&dir.join("missing"), &mut Vec::new(), &mut Vec::new()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
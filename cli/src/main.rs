@@ -1,5 +1,5 @@
-use anyhow::Context;
-use lilac::Lilac;
+use anyhow::{bail, Context};
+use lilac::{GainMode, Lilac};
 use rodio::{Sink, Source};
 use std::{path::PathBuf, process, thread};
 use structopt::StructOpt;
@@ -7,15 +7,65 @@ use structopt::StructOpt;
 type Result = anyhow::Result<()>;
 const OK: Result = Result::Ok(());
 
+mod browser;
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod edit;
+mod eq;
+mod format;
+mod info;
 mod interactive;
+mod library;
+#[cfg(feature = "mpris")]
+mod mpris;
+mod overview;
+mod pattern;
+mod player;
+mod playlist;
+mod queue;
+mod serve;
+mod session;
+mod tag;
 mod transcode;
+mod verify;
+mod visualizer;
 
 /// LILAC playback and transcoding utility
 ///
 /// If neither of the subcommands are detected,
 /// opens an interactive player and load the provided files.
+///
+/// Defaults are read from lilac/config.toml in the user
+/// configuration directory and can be overridden by flags.
+#[derive(StructOpt)]
+struct Opt {
+    /// Configuration file to use instead of the default one
+    #[structopt(long, name = "CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// Interactive player volume
+    ///
+    /// Should be anywhere between 0.0 and 1.0 inclusively
+    #[structopt(long, name = "VOLUME")]
+    volume: Option<f32>,
+    /// Interactive player ReplayGain mode
+    #[structopt(long, name = "MODE")]
+    gain: Option<GainMode>,
+    /// Interactive player ReplayGain preamp in dB
+    #[structopt(long, name = "DB", allow_hyphen_values = true)]
+    preamp: Option<f32>,
+    /// Restores the interactive player session saved on exit
+    ///
+    /// Given files are added to the restored queue.
+    #[structopt(long)]
+    resume: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
 #[derive(StructOpt)]
-enum Opt {
+enum Command {
     /// Plays a LILAC file
     Play {
         /// File to play
@@ -23,50 +73,153 @@ enum Opt {
         file: PathBuf,
         /// Playback volume
         ///
-        /// Should be anywhere between 0.0 and 1.0 inclusively
-        #[structopt(short, long, name = "VOLUME", default_value = "1.0")]
-        volume: f32,
+        /// Should be anywhere between 0.0 and 1.0 inclusively.
+        /// Defaults to the configured volume
+        #[structopt(short, long, name = "VOLUME")]
+        volume: Option<f32>,
+        /// ReplayGain mode
+        ///
+        /// One of off, track or album.
+        /// Album gain falls back to track gain when missing and vice versa.
+        /// Defaults to the configured mode
+        #[structopt(short, long, name = "MODE")]
+        gain: Option<GainMode>,
+        /// ReplayGain preamp in dB
+        ///
+        /// Also applies to files without ReplayGain tags,
+        /// never boosting them past full scale.
+        /// Defaults to the configured preamp
+        #[structopt(short, long, name = "DB", allow_hyphen_values = true)]
+        preamp: Option<f32>,
     },
     /// Transcodes a file to or from LILAC
     ///
     /// Supports transcoding from MP3, FLAC,
     /// OGG and WAV, and transcoding to WAV.
-    /// Input and output formats are automatically inferred
-    Transcode {
-        /// Glob matching the input files
-        #[structopt(name = "GLOB")]
-        glob: String,
-        /// Output files naming pattern
-        ///
-        /// %F is replaced with the input filename without extension,
-        /// %E with the output format extension,
-        /// %e with the input format extension,
-        /// %T with the song title,
-        /// %A with the song artist,
-        /// %a with the song album.
-        #[structopt(name = "PATTERN", default_value = "%F.%E")]
-        output: String,
-        /// Keep input files after transcoding
-        #[structopt(short, long)]
-        keep: bool,
-    },
+    /// Input and output formats are automatically inferred.
+    /// Whole directory trees can be mirrored with --input-dir and --output-dir
+    Transcode(transcode::Opt),
+    /// Prints metadata and stream properties of files
+    ///
+    /// Works on every format transcode accepts.
+    Info(info::Opt),
+    /// Views and edits the tags of LILAC files
+    ///
+    /// Without any edit option, the current tags are printed.
+    /// Files are rewritten atomically.
+    Tag(tag::Opt),
+    /// Checks files against their embedded checksum
+    ///
+    /// LILAC files store a digest of their samples when written,
+    /// and FLAC files are checked against their MD5 signature.
+    /// Damaged audio blocks of binary LILAC files can be concealed.
+    Verify(verify::Opt),
+    /// Keeps a time or frame range of a LILAC file
+    ///
+    /// Metadata is preserved, except for track ReplayGain values.
+    Cut(edit::CutOpt),
+    /// Joins LILAC files end to end
+    ///
+    /// The output keeps the metadata of the first file.
+    /// Inputs must share their channels, sample rate and bit depth
+    /// unless conversion is requested.
+    Concat(edit::ConcatOpt),
+    /// Splits a LILAC file into numbered parts
+    ///
+    /// Parts are cut either at fixed times or on silences.
+    Split(edit::SplitOpt),
+    /// Indexes and searches LILAC files
+    ///
+    /// The index is kept in the user data directory.
+    Library(library::Opt),
+    /// Serves the library over HTTP
+    ///
+    /// GET /tracks lists the indexed tracks as JSON, each one available
+    /// as /tracks/<id>.lilac or transcoded to /tracks/<id>.wav.
+    Serve(serve::Opt),
+    /// Plays in the background, controlled through a local socket
+    ///
+    /// Requests are JSON objects on a single line, like
+    /// {"command": "seek", "position": 30} or {"command": "status"},
+    /// each answered by a single line.
+    #[cfg(unix)]
+    Daemon(daemon::DaemonOpt),
+    /// Sends a command to the daemon
+    #[cfg(unix)]
+    Ctl(daemon::CtlOpt),
 
     #[structopt(external_subcommand)]
     Interactive(Vec<String>),
 }
 
 fn main() {
-    if let Err(e) = match Opt::from_args() {
-        Opt::Play { file, volume } => play(file, volume),
-        Opt::Transcode { glob, output, keep } => transcode::main(glob, output, keep),
-        Opt::Interactive(queue) => interactive::main(queue),
-    } {
+    if let Err(e) = run(Opt::from_args()) {
         eprintln!("{:#}", e);
         process::exit(1);
     }
 }
 
-fn play(file: PathBuf, volume: f32) -> Result {
+fn run(opt: Opt) -> Result {
+    let mut config = config::Config::load(opt.config.as_deref())?;
+    let player = &mut config.player;
+    if let Some(v) = opt.volume {
+        if !(0.0..=1.0).contains(&v) {
+            bail!("--volume must be between 0.0 and 1.0");
+        }
+        player.volume = (v * 100.0).round() as u16;
+    }
+    if let Some(g) = opt.gain {
+        player.gain = config::Mode(g);
+    }
+    if let Some(p) = opt.preamp {
+        if !(-config::MAX_PREAMP..=config::MAX_PREAMP).contains(&p) {
+            bail!(
+                "--preamp must be between -{0} and {0} dB",
+                config::MAX_PREAMP
+            );
+        }
+        player.preamp = p;
+    }
+
+    let command = match opt.command {
+        Some(c) => c,
+        None if opt.resume => Command::Interactive(Vec::new()),
+        None => {
+            Opt::clap().print_help()?;
+            println!();
+            return OK;
+        }
+    };
+    match command {
+        Command::Play {
+            file,
+            volume,
+            gain,
+            preamp,
+        } => play(
+            file,
+            volume.unwrap_or(f32::from(config.player.volume) / 100.0),
+            gain.unwrap_or(config.player.gain.0),
+            preamp.unwrap_or(config.player.preamp),
+        ),
+        Command::Info(opt) => info::main(opt),
+        Command::Tag(opt) => tag::main(opt),
+        Command::Verify(opt) => verify::main(opt),
+        Command::Transcode(opt) => transcode::main(opt, &config.transcode),
+        Command::Cut(opt) => edit::cut(opt),
+        Command::Concat(opt) => edit::concat(opt),
+        Command::Split(opt) => edit::split(opt, &config.transcode),
+        Command::Library(opt) => library::main(opt, &config.library),
+        Command::Serve(opt) => serve::main(opt),
+        #[cfg(unix)]
+        Command::Daemon(opt) => daemon::daemon(opt, &config),
+        #[cfg(unix)]
+        Command::Ctl(opt) => daemon::ctl(opt),
+        Command::Interactive(queue) => interactive::main(queue, &config, opt.resume),
+    }
+}
+
+fn play(file: PathBuf, volume: f32, gain: GainMode, preamp: f32) -> Result {
     let lilac = Lilac::read_file(file)?;
     println!(
         "Now playing {} by {} on {}",
@@ -78,10 +231,11 @@ fn play(file: PathBuf, volume: f32) -> Result {
     let device = rodio::default_output_device().context("no audio device")?;
     let sink = Sink::new(&device);
 
+    let gain = lilac.gain(gain, preamp);
     let source = lilac.source();
     let duration = source.total_duration().unwrap();
 
-    sink.set_volume(volume);
+    sink.set_volume(volume * gain);
     sink.append(source);
     sink.play();
 
@@ -0,0 +1,439 @@
+use crate::playlist;
+use dbus::{
+    arg::{PropMap, RefArg, Variant},
+    blocking::{
+        stdintf::org_freedesktop_dbus::{PropertiesPropertiesChanged, RequestNameReply},
+        Connection,
+    },
+    channel::MatchingReceiver,
+    message::{MatchRule, SignalArgs},
+    Path as ObjectPath,
+};
+use dbus_crossroads::{Crossroads, IfaceBuilder, MethodErr};
+use std::{
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+const NAME: &str = "org.mpris.MediaPlayer2.lilac";
+const PATH: &str = "/org/mpris/MediaPlayer2";
+const ROOT: &str = "org.mpris.MediaPlayer2";
+const PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Request from a D-Bus client
+pub enum Command {
+    PlayPause,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+    /// Offset from the current position in microseconds
+    Seek(i64),
+    /// Position in microseconds, for the song with the given id
+    SetPosition(usize, i64),
+    /// Volume between 0.0 and 1.0
+    Volume(f64),
+    Quit,
+}
+
+/// Player state shown to D-Bus clients
+#[derive(Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub playing: bool,
+    pub volume: f64,
+    pub position: Duration,
+    pub song: Option<Song>,
+}
+#[derive(Clone, PartialEq)]
+pub struct Song {
+    pub id: usize,
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub duration: Duration,
+}
+
+/// Handle used to publish the player state
+pub struct Mpris {
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    snapshot: Snapshot,
+    /// Position to announce with the `Seeked` signal
+    seeked: Option<Duration>,
+}
+
+/// Object data of the crossroads tree
+struct Data {
+    shared: Arc<Mutex<Shared>>,
+    send: Box<dyn Fn(Command) -> bool + Send>,
+}
+
+/// Connects to the session bus and serves the MPRIS interface on a new thread
+///
+/// The player is named `org.mpris.MediaPlayer2.lilac`, with an instance suffix
+/// when another player already owns that name. A private bus can be used by
+/// pointing `DBUS_SESSION_BUS_ADDRESS` to it, as `dbus-run-session` does.
+/// Commands are passed to `send`, which returns false once the player is closing.
+pub fn spawn<F>(send: F) -> Result<Mpris, dbus::Error>
+where
+    F: Fn(Command) -> bool + Send + 'static,
+{
+    serve(Connection::new_session()?, send)
+}
+
+/// Serves the MPRIS interface on an open bus connection, see `spawn`
+fn serve<F>(conn: Connection, send: F) -> Result<Mpris, dbus::Error>
+where
+    F: Fn(Command) -> bool + Send + 'static,
+{
+    if conn.request_name(NAME, false, false, true)? != RequestNameReply::PrimaryOwner {
+        let name = format!("{}.instance{}", NAME, process::id());
+        conn.request_name(name, false, false, true)?;
+    }
+
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    let mut cr = Crossroads::new();
+    let root = cr.register(ROOT, register_root);
+    let player = cr.register(PLAYER, register_player);
+    cr.insert(
+        PATH,
+        &[root, player],
+        Data {
+            shared: shared.clone(),
+            send: Box::new(send),
+        },
+    );
+
+    let emitter = shared.clone();
+    thread::spawn(move || {
+        conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| cr.handle_message(msg, conn).is_ok()),
+        );
+
+        let mut last = Snapshot::default();
+        loop {
+            if conn.process(Duration::from_millis(100)).is_err() {
+                break;
+            }
+            let (snapshot, seeked) = {
+                let mut shared = emitter.lock().unwrap();
+                (shared.snapshot.clone(), shared.seeked.take())
+            };
+            if let Some(message) = changes(&last, &snapshot) {
+                if conn.channel().send(message).is_err() {
+                    break;
+                }
+            }
+            if let Some(position) = seeked {
+                let seeked = dbus::Message::signal(&PATH.into(), &PLAYER.into(), &"Seeked".into())
+                    .append1(micros(position));
+                if conn.channel().send(seeked).is_err() {
+                    break;
+                }
+            }
+            last = snapshot;
+        }
+    });
+
+    Ok(Mpris { shared })
+}
+
+impl Mpris {
+    pub fn update(&self, snapshot: Snapshot) {
+        self.shared.lock().unwrap().snapshot = snapshot;
+    }
+    /// Announces a jump in the current song
+    pub fn seeked(&self, position: Duration) {
+        self.shared.lock().unwrap().seeked = Some(position);
+    }
+}
+
+fn register_root(b: &mut IfaceBuilder<Data>) {
+    b.method("Raise", (), (), |_, _, _: ()| Ok(()));
+    b.method("Quit", (), (), |_, data, _: ()| send(data, Command::Quit));
+    b.property("CanQuit").get(|_, _| Ok(true));
+    b.property("CanRaise").get(|_, _| Ok(false));
+    b.property("HasTrackList").get(|_, _| Ok(false));
+    b.property("Identity").get(|_, _| Ok("lilac".to_owned()));
+    b.property("SupportedUriSchemes")
+        .get(|_, _| Ok(vec!["file".to_owned()]));
+    b.property("SupportedMimeTypes")
+        .get(|_, _| Ok(Vec::<String>::new()));
+}
+
+fn register_player(b: &mut IfaceBuilder<Data>) {
+    b.signal::<(i64,), _>("Seeked", ("Position",));
+
+    b.method("PlayPause", (), (), |_, data, _: ()| {
+        send(data, Command::PlayPause)
+    });
+    b.method("Play", (), (), |_, data, _: ()| send(data, Command::Play));
+    b.method("Pause", (), (), |_, data, _: ()| send(data, Command::Pause));
+    b.method("Stop", (), (), |_, data, _: ()| send(data, Command::Stop));
+    b.method("Next", (), (), |_, data, _: ()| send(data, Command::Next));
+    b.method("Previous", (), (), |_, data, _: ()| {
+        send(data, Command::Previous)
+    });
+    b.method("Seek", ("Offset",), (), |_, data, (offset,): (i64,)| {
+        send(data, Command::Seek(offset))
+    });
+    b.method(
+        "SetPosition",
+        ("TrackId", "Position"),
+        (),
+        |_, data, (track, position): (ObjectPath<'static>, i64)| match id(&track) {
+            Some(id) => send(data, Command::SetPosition(id, position)),
+            None => Ok(()),
+        },
+    );
+    b.method("OpenUri", ("Uri",), (), |_, _, (_,): (String,)| {
+        Err::<(), _>(MethodErr::failed("Opening URIs is not supported"))
+    });
+
+    b.property("PlaybackStatus").get(|_, data| {
+        let snapshot = &data.shared.lock().unwrap().snapshot;
+        Ok(status(snapshot).to_owned())
+    });
+    b.property("Rate").get(|_, _| Ok(1.0));
+    b.property("MinimumRate").get(|_, _| Ok(1.0));
+    b.property("MaximumRate").get(|_, _| Ok(1.0));
+    b.property("Metadata")
+        .get(|_, data| Ok(metadata(&data.shared.lock().unwrap().snapshot)));
+    b.property("Volume")
+        .get(|_, data| Ok(data.shared.lock().unwrap().snapshot.volume))
+        .set(|_, data, volume: f64| {
+            send(data, Command::Volume(volume.clamp(0.0, 1.0)))?;
+            Ok(None)
+        });
+    b.property("Position")
+        .emits_changed_false()
+        .get(|_, data| Ok(micros(data.shared.lock().unwrap().snapshot.position)));
+    for p in &[
+        "CanGoNext",
+        "CanGoPrevious",
+        "CanPlay",
+        "CanPause",
+        "CanSeek",
+    ] {
+        b.property(*p).get(|_, _| Ok(true));
+    }
+    b.property("CanControl")
+        .emits_changed_const()
+        .get(|_, _| Ok(true));
+}
+
+fn send(data: &mut Data, command: Command) -> Result<(), MethodErr> {
+    if (data.send)(command) {
+        Ok(())
+    } else {
+        Err(MethodErr::failed("The player is closing"))
+    }
+}
+
+/// `PropertiesChanged` signal for the properties that differ between two snapshots
+fn changes(old: &Snapshot, new: &Snapshot) -> Option<dbus::Message> {
+    let mut changed = PropMap::new();
+    if status(old) != status(new) {
+        changed.insert(
+            "PlaybackStatus".to_owned(),
+            Variant(Box::new(status(new).to_owned())),
+        );
+    }
+    if old.volume != new.volume {
+        changed.insert("Volume".to_owned(), Variant(Box::new(new.volume)));
+    }
+    if old.song != new.song {
+        changed.insert("Metadata".to_owned(), Variant(Box::new(metadata(new))));
+    }
+    if changed.is_empty() {
+        return None;
+    }
+
+    let signal = PropertiesPropertiesChanged {
+        interface_name: PLAYER.to_owned(),
+        changed_properties: changed,
+        invalidated_properties: Vec::new(),
+    };
+    Some(signal.to_emit_message(&PATH.into()))
+}
+
+fn status(snapshot: &Snapshot) -> &'static str {
+    match (&snapshot.song, snapshot.playing) {
+        (None, _) => "Stopped",
+        (Some(_), true) => "Playing",
+        (Some(_), false) => "Paused",
+    }
+}
+
+fn metadata(snapshot: &Snapshot) -> PropMap {
+    let mut map = PropMap::new();
+    let song = match &snapshot.song {
+        Some(s) => s,
+        None => {
+            let none = ObjectPath::from("/org/mpris/MediaPlayer2/TrackList/NoTrack");
+            map.insert("mpris:trackid".to_owned(), Variant(Box::new(none)));
+            return map;
+        }
+    };
+
+    let mut insert = |key: &str, value: Box<dyn RefArg>| {
+        map.insert(key.to_owned(), Variant(value));
+    };
+    insert("mpris:trackid", Box::new(track_path(song.id)));
+    insert("mpris:length", Box::new(micros(song.duration)));
+    if let Some(t) = &song.title {
+        insert("xesam:title", Box::new(t.clone()));
+    }
+    if let Some(a) = &song.artist {
+        insert("xesam:artist", Box::new(vec![a.clone()]));
+    }
+    if let Some(a) = &song.album {
+        insert("xesam:album", Box::new(a.clone()));
+    }
+    if let Some(n) = song.track {
+        insert("xesam:trackNumber", Box::new(n as i32));
+    }
+    if let Ok(p) = song.path.canonicalize() {
+        insert("xesam:url", Box::new(playlist::to_uri(&p)));
+    }
+    map
+}
+
+fn track_path(id: usize) -> ObjectPath<'static> {
+    ObjectPath::from(format!("/org/lilac/track/{}", id))
+}
+fn id(path: &ObjectPath<'_>) -> Option<usize> {
+    path.strip_prefix("/org/lilac/track/")?.parse().ok()
+}
+
+fn micros(d: Duration) -> i64 {
+    d.as_micros() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::{blocking::stdintf::org_freedesktop_dbus::Properties, channel::Channel};
+    use std::{
+        env, fs,
+        io::{BufRead, BufReader},
+        process::{Child, Command as Process, Stdio},
+        sync::mpsc,
+    };
+
+    /// Private session bus, stopped when dropped
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        fn start() -> Option<Self> {
+            let mut daemon = Process::new("dbus-daemon")
+                .args(["--session", "--print-address", "--nofork"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.as_mut()?)
+                .read_line(&mut address)
+                .ok()?;
+            let address = address.trim().to_owned();
+            Some(Self { daemon, address })
+        }
+
+        fn connect(&self) -> Connection {
+            let mut channel = Channel::open_private(&self.address).unwrap();
+            channel.register().unwrap();
+            Connection::from(channel)
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            self.daemon.kill().ok();
+            self.daemon.wait().ok();
+        }
+    }
+
+    /// Needs `dbus-daemon`, set `LILAC_SKIP_DBUS` to skip it where it's missing
+    #[test]
+    fn serves_player_interface() {
+        let bus = match Bus::start() {
+            Some(b) => b,
+            None if env::var_os("LILAC_SKIP_DBUS").is_some() => {
+                return eprintln!("dbus-daemon isn't available, skipping");
+            }
+            None => panic!("dbus-daemon isn't available, set LILAC_SKIP_DBUS to skip this test"),
+        };
+
+        let dir = env::temp_dir().join(format!("lilac-mpris-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a b#é.lilac");
+        fs::write(&path, "").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let mpris = serve(bus.connect(), move |c| tx.send(c).is_ok()).unwrap();
+        mpris.update(Snapshot {
+            playing: true,
+            volume: 0.5,
+            position: Duration::from_secs(3),
+            song: Some(Song {
+                id: 7,
+                path: path.clone(),
+                title: Some("Title".to_owned()),
+                artist: Some("Artist".to_owned()),
+                album: None,
+                track: Some(2),
+                duration: Duration::from_secs(90),
+            }),
+        });
+
+        let client = bus.connect();
+        let proxy = client.with_proxy(NAME, PATH, Duration::from_secs(5));
+        let status: String = proxy.get(PLAYER, "PlaybackStatus").unwrap();
+        assert_eq!(status, "Playing");
+        let position: i64 = proxy.get(PLAYER, "Position").unwrap();
+        assert_eq!(position, 3_000_000);
+
+        let metadata: PropMap = proxy.get(PLAYER, "Metadata").unwrap();
+        let get = |key: &str| &metadata[key].0;
+        assert_eq!(get("mpris:trackid").as_str(), Some("/org/lilac/track/7"));
+        assert_eq!(get("mpris:length").as_i64(), Some(90_000_000));
+        assert_eq!(get("xesam:title").as_str(), Some("Title"));
+        assert_eq!(get("xesam:trackNumber").as_i64(), Some(2));
+        assert!(!metadata.contains_key("xesam:album"));
+        let url = get("xesam:url").as_str().unwrap();
+        assert!(url.starts_with("file:///"), "{}", url);
+        assert!(url.ends_with("/a%20b%23%C3%A9.lilac"), "{}", url);
+
+        let timeout = Duration::from_secs(5);
+        proxy
+            .method_call::<(), _, _, _>(PLAYER, "PlayPause", ())
+            .unwrap();
+        assert!(matches!(rx.recv_timeout(timeout), Ok(Command::PlayPause)));
+        proxy
+            .method_call::<(), _, _, _>(PLAYER, "Seek", (-5_000_000i64,))
+            .unwrap();
+        assert!(matches!(
+            rx.recv_timeout(timeout),
+            Ok(Command::Seek(-5_000_000))
+        ));
+
+        mpris.update(Snapshot::default());
+        let status: String = proxy.get(PLAYER, "PlaybackStatus").unwrap();
+        assert_eq!(status, "Stopped");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use crate::format;
+use lilac::Lilac;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Buckets computed for every track, merged to fit the available width when drawing
+const BUCKETS: usize = 1024;
+const GLYPHS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Waveform overview of a track
+///
+/// It is cached next to the track in a hidden `.<file>.peaks` file,
+/// which is recomputed when the track size or modification time changes.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Overview {
+    size: u64,
+    modified: u64,
+    /// Lowest and highest sample of every bucket, scaled to `i8`
+    peaks: Vec<(i8, i8)>,
+}
+
+impl Overview {
+    /// Reads the cached overview, computing and caching it when missing or stale
+    ///
+    /// Computing an overview takes a while for long tracks, so this is meant
+    /// to be called off the UI thread.
+    pub fn load(path: &Path, lilac: &Lilac) -> Self {
+        let (size, modified) = stamp(path).unwrap_or((0, 0));
+        let cache = cache_path(path);
+
+        if let Some(o) = cache
+            .as_ref()
+            .and_then(|c| File::open(c).ok())
+            .and_then(|f| serde_json::from_reader::<_, Self>(BufReader::new(f)).ok())
+        {
+            if o.size == size && o.modified == modified {
+                return o;
+            }
+        }
+
+        let overview = Self {
+            size,
+            modified,
+            peaks: lilac
+                .peaks(BUCKETS)
+                .into_iter()
+                .map(|(min, max)| ((min * 127.0) as i8, (max * 127.0) as i8))
+                .collect(),
+        };
+        // The cache is best effort, the directory might be read-only
+        if let Some(c) = cache {
+            format::replace(&c, |temp| -> anyhow::Result<()> {
+                serde_json::to_writer(BufWriter::new(File::create(temp)?), &overview)?;
+                Ok(())
+            })
+            .ok();
+        }
+        overview
+    }
+
+    /// One glyph per column, taller for louder parts
+    pub fn render(&self, width: usize) -> Vec<char> {
+        let buckets = self.peaks.len();
+        (0..width)
+            .map(|i| {
+                let start = i * buckets / width;
+                let end = ((i + 1) * buckets / width).max(start + 1).min(buckets);
+                let (min, max) = self.peaks[start.min(end)..end]
+                    .iter()
+                    .fold((0, 0), |(min, max), &(lo, hi)| (lo.min(min), hi.max(max)));
+                let span = (max as i32 - min as i32) as usize;
+                GLYPHS[(span * (GLYPHS.len() - 1)).div_ceil(254)]
+            })
+            .collect()
+    }
+}
+
+fn cache_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    Some(path.with_file_name(format!(".{}.peaks", name)))
+}
+
+fn stamp(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_peaks() {
+        let dir = std::env::temp_dir().join(format!("lilac-overview-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.lilac");
+        let lilac = Lilac::read(
+            r#"{"channels":1,"sampleRate":10,"bitDepth":8,"samples":[-128,64,0,127]}"#.as_bytes(),
+        )
+        .unwrap();
+        lilac.write_file(&path).unwrap();
+
+        let overview = Overview::load(&path, &lilac);
+        assert_eq!(overview.peaks.len(), BUCKETS);
+        assert!(overview.peaks.contains(&(-127, 0)));
+        assert!(overview.peaks.contains(&(0, 63)));
+        let cache = dir.join(".a.lilac.peaks");
+        let cached: Overview = serde_json::from_reader(File::open(&cache).unwrap()).unwrap();
+        assert_eq!(cached.peaks, overview.peaks);
+        assert_eq!((cached.size, cached.modified), stamp(&path).unwrap());
+
+        // A fresh cache is used as is, a stale one is recomputed
+        let mut edited = cached;
+        edited.peaks = vec![(-1, 1)];
+        serde_json::to_writer(File::create(&cache).unwrap(), &edited).unwrap();
+        assert_eq!(Overview::load(&path, &lilac).peaks, [(-1, 1)]);
+        edited.size += 1;
+        serde_json::to_writer(File::create(&cache).unwrap(), &edited).unwrap();
+        assert_eq!(Overview::load(&path, &lilac).peaks.len(), BUCKETS);
+
+        let entries: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
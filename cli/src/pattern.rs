@@ -0,0 +1,330 @@
+use serde::Deserialize;
+use std::{convert::TryFrom, iter::Peekable, str::Chars, str::FromStr};
+
+/// Placeholders recognized after a `%`
+const FIELDS: &[char] = &[
+    'F', 'D', 'E', 'e', 'T', 'A', 'B', 'a', 'g', 'y', 'n', 'd', 'r', 'b',
+];
+/// Characters with a special meaning, written literally after a `%`
+const ESCAPES: &[char] = &['%', '[', ']', '{', '}', '|'];
+/// Names Windows reserves for devices, whatever the extension
+const DEVICES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Parsed output naming pattern
+///
+/// `%X` placeholders are replaced with values, `[...]` sections are dropped
+/// when any placeholder inside them has no value, and `{a|b|c}` uses the
+/// first alternative whose placeholders all have a value.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern(Vec<Node>);
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Field(char),
+    Optional(Vec<Node>),
+    Fallback(Vec<Vec<Node>>),
+}
+
+/// Characters of substituted values replaced to keep them in a single path component
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sanitize {
+    /// Values are substituted verbatim and may create directories
+    None,
+    /// Path separators only
+    Separators,
+    /// Path separators and characters or names reserved on common filesystems
+    #[default]
+    Portable,
+}
+
+impl Pattern {
+    /// Path made from the pattern, given the value of every placeholder
+    ///
+    /// Empty values count as missing. Missing values outside of
+    /// sections and fallbacks are replaced with `Unknown`.
+    pub fn render<F: Fn(char) -> Option<String>>(&self, value: F) -> String {
+        let value = |f| value(f).filter(|v| !v.is_empty());
+        render(&self.0, &value, false).unwrap_or_default()
+    }
+
+    /// Placeholders used anywhere in the pattern
+    pub fn fields(&self) -> Vec<char> {
+        let mut fields = Vec::new();
+        collect_fields(&self.0, &mut fields);
+        fields
+    }
+}
+
+fn collect_fields(nodes: &[Node], fields: &mut Vec<char>) {
+    for node in nodes {
+        match node {
+            Node::Text(_) => (),
+            Node::Field(f) => fields.push(*f),
+            Node::Optional(inner) => collect_fields(inner, fields),
+            Node::Fallback(alternatives) => {
+                for a in alternatives {
+                    collect_fields(a, fields);
+                }
+            }
+        }
+    }
+}
+
+fn render<F: Fn(char) -> Option<String>>(
+    nodes: &[Node],
+    value: &F,
+    strict: bool,
+) -> Option<String> {
+    let mut output = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(t) => output.push_str(t),
+            Node::Field(f) => match value(*f) {
+                Some(v) => output.push_str(&v),
+                None if strict => return None,
+                None => output.push_str("Unknown"),
+            },
+            Node::Optional(inner) => {
+                if let Some(s) = render(inner, value, true) {
+                    output.push_str(&s);
+                }
+            }
+            Node::Fallback(alternatives) => {
+                match alternatives.iter().find_map(|a| render(a, value, true)) {
+                    Some(s) => output.push_str(&s),
+                    None if strict => return None,
+                    None => output.push_str("Unknown"),
+                }
+            }
+        }
+    }
+    Some(output)
+}
+
+/// Nodes up to the end of the pattern or an unescaped `]`, `}` or `|`
+fn sequence(chars: &mut Peekable<Chars<'_>>) -> Result<Vec<Node>, String> {
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+    let flush = |text: &mut String, nodes: &mut Vec<Node>| {
+        if !text.is_empty() {
+            nodes.push(Node::Text(std::mem::take(text)));
+        }
+    };
+
+    while let Some(&c) = chars.peek() {
+        if matches!(c, ']' | '}' | '|') {
+            break;
+        }
+        chars.next();
+        match c {
+            '%' => match chars.next() {
+                Some(e) if ESCAPES.contains(&e) => text.push(e),
+                Some(f) if FIELDS.contains(&f) => {
+                    flush(&mut text, &mut nodes);
+                    nodes.push(Node::Field(f));
+                }
+                Some(f) => return Err(format!("unknown placeholder `%{}`", f)),
+                None => return Err("pattern ends with `%`".to_owned()),
+            },
+            '[' => {
+                flush(&mut text, &mut nodes);
+                let inner = sequence(chars)?;
+                match chars.next() {
+                    Some(']') => nodes.push(Node::Optional(inner)),
+                    Some(c) => return Err(format!("unexpected `{}` in `[...]`", c)),
+                    None => return Err("unclosed `[`".to_owned()),
+                }
+            }
+            '{' => {
+                flush(&mut text, &mut nodes);
+                let mut alternatives = vec![sequence(chars)?];
+                loop {
+                    match chars.next() {
+                        Some('|') => alternatives.push(sequence(chars)?),
+                        Some('}') => break,
+                        Some(c) => return Err(format!("unexpected `{}` in `{{...}}`", c)),
+                        None => return Err("unclosed `{`".to_owned()),
+                    }
+                }
+                nodes.push(Node::Fallback(alternatives));
+            }
+            c => text.push(c),
+        }
+    }
+    flush(&mut text, &mut nodes);
+    Ok(nodes)
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars().peekable();
+        let nodes = sequence(&mut chars)?;
+        match chars.next() {
+            None => Ok(Pattern(nodes)),
+            Some(c) => Err(format!("unexpected `{0}`, use `%{0}` for a literal one", c)),
+        }
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern(vec![
+            Node::Field('F'),
+            Node::Text(".".to_owned()),
+            Node::Field('E'),
+        ])
+    }
+}
+
+impl Sanitize {
+    /// Whether a character is replaced in substituted values
+    pub fn reserves(self, c: char) -> bool {
+        match self {
+            Sanitize::None => false,
+            Sanitize::Separators => matches!(c, '/' | '\\'),
+            Sanitize::Portable => {
+                matches!(c, '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*') || c.is_control()
+            }
+        }
+    }
+
+    /// Value with its reserved characters replaced
+    ///
+    /// Portable values also lose their trailing dots and spaces,
+    /// and device names like `NUL` get the replacement appended to their stem.
+    pub fn apply(self, value: &str, replacement: char) -> String {
+        let mut value: String = value
+            .chars()
+            .map(|c| if self.reserves(c) { replacement } else { c })
+            .collect();
+        match self {
+            Sanitize::None => (),
+            Sanitize::Separators => {
+                if value == "." || value == ".." {
+                    value = value.replace('.', &replacement.to_string());
+                }
+            }
+            Sanitize::Portable => {
+                value.truncate(value.trim_end_matches(['.', ' ']).len());
+                let stem = value.split('.').next().unwrap_or_default().trim_end();
+                if DEVICES.iter().any(|d| d.eq_ignore_ascii_case(stem)) {
+                    let len = stem.len();
+                    value.insert(len, replacement);
+                }
+            }
+        }
+        value
+    }
+}
+
+impl FromStr for Sanitize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "none" => Ok(Sanitize::None),
+            "separators" => Ok(Sanitize::Separators),
+            "portable" => Ok(Sanitize::Portable),
+            _ => Err(format!("unknown sanitization `{}`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(pattern: &str, values: &[(char, &str)]) -> String {
+        let pattern: Pattern = pattern.parse().unwrap();
+        pattern.render(|f| {
+            values
+                .iter()
+                .find(|(k, _)| *k == f)
+                .map(|(_, v)| v.to_string())
+        })
+    }
+
+    #[test]
+    fn renders_fields() {
+        let values = [('A', "Artist"), ('T', "Title"), ('n', "03"), ('e', "")];
+        assert_eq!(render("%A/%n - %T", &values), "Artist/03 - Title");
+        assert_eq!(render("%T.%e", &values), "Title.Unknown");
+        assert_eq!(render("%% 100%[ %]", &values), "% 100[ ]");
+        assert_eq!(Pattern::default().render(|_| None), "Unknown.Unknown");
+    }
+
+    #[test]
+    fn renders_sections() {
+        let values = [('A', "Artist"), ('T', "Title"), ('d', "2")];
+        assert_eq!(render("[%d-]%T", &values), "2-Title");
+        assert_eq!(render("[%B - ]%T", &values), "Title");
+        assert_eq!(render("[%A[ (%B)]] %T", &values), "Artist Title");
+        assert_eq!(render("{%a|%A|Various}/%T", &values), "Artist/Title");
+        assert_eq!(render("{%a|%B}/%T", &values), "Unknown/Title");
+        assert_eq!(render("[{%a|%B}/]%T", &values), "Title");
+        assert_eq!(render("{%a|}%T", &values), "Title");
+    }
+
+    #[test]
+    fn lists_fields() {
+        let pattern: Pattern = "[%n - ]{%a|%D}/%T%%.%E".parse().unwrap();
+        assert_eq!(pattern.fields(), ['n', 'a', 'D', 'T', 'E']);
+        assert_eq!(Pattern::default().fields(), ['F', 'E']);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let error = |p: &str| p.parse::<Pattern>().unwrap_err();
+        assert_eq!(error("%x"), "unknown placeholder `%x`");
+        assert_eq!(error("%T%"), "pattern ends with `%`");
+        assert_eq!(error("[%T"), "unclosed `[`");
+        assert_eq!(error("{%T|%A"), "unclosed `{`");
+        assert_eq!(error("[%T|%A]"), "unexpected `|` in `[...]`");
+        assert_eq!(error("{%T]"), "unexpected `]` in `{...}`");
+        assert_eq!(error("%T}"), "unexpected `}`, use `%}` for a literal one");
+    }
+
+    #[test]
+    fn sanitizes_values() {
+        assert_eq!(Sanitize::None.apply("a/b:c", '_'), "a/b:c");
+        assert_eq!(Sanitize::Separators.apply("a/b\\c:d", '_'), "a_b_c:d");
+        assert_eq!(Sanitize::Separators.apply("..", '_'), "__");
+        assert_eq!(Sanitize::Separators.apply("...", '_'), "...");
+        assert_eq!(
+            Sanitize::Portable.apply("AC/DC: \"Live\"?\t", '_'),
+            "AC_DC_ _Live___"
+        );
+        assert_eq!(Sanitize::Portable.apply("Title. . ", '_'), "Title");
+        assert_eq!(Sanitize::Portable.apply("..", '_'), "");
+        assert_eq!(Sanitize::Portable.apply("nul", '_'), "nul_");
+        assert_eq!(Sanitize::Portable.apply("Com1.txt", '-'), "Com1-.txt");
+        assert_eq!(Sanitize::Portable.apply("Console", '_'), "Console");
+    }
+
+    #[test]
+    fn parses_sanitize() {
+        assert_eq!("Portable".parse(), Ok(Sanitize::Portable));
+        assert_eq!("separators".parse(), Ok(Sanitize::Separators));
+        assert_eq!("none".parse(), Ok(Sanitize::None));
+        assert_eq!(
+            "all".parse::<Sanitize>(),
+            Err("unknown sanitization `all`".to_owned())
+        );
+    }
+}
@@ -0,0 +1,513 @@
+use anyhow::Context;
+use lilac::{Dsp, DspHandle, Lilac};
+use rodio::{source::UniformSourceIterator, Device, DeviceTrait, Sink, Source};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Frames rendered at once by the deck
+const BLOCK_FRAMES: usize = 256;
+/// Frames kept for visualization
+const TAP_FRAMES: usize = 4096;
+
+/// Gapless player
+///
+/// A single never ending source is appended to the sink.
+/// It pulls samples from the current track and the next one,
+/// which lets it chain them without gaps, crossfade them,
+/// and fade out on pause and skip.
+/// Its output goes through a DSP chain before reaching the sink,
+/// and the last few thousand frames are kept for visualization.
+pub struct Player {
+    sink: Sink,
+    deck: Arc<Mutex<Deck>>,
+    tap: Arc<Mutex<VecDeque<f32>>>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+/// Snapshot of the player position
+pub struct Status {
+    pub position: Duration,
+    /// Number of times the player moved on to a preloaded track
+    pub advances: u64,
+    /// The last track finished and nothing was queued after it
+    pub ended: bool,
+}
+
+/// Last samples sent to the output
+pub struct Recent {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Interleaved samples, oldest first
+    pub samples: Vec<f32>,
+}
+
+struct Deck {
+    channels: u16,
+    sample_rate: u32,
+
+    current: Option<Track>,
+    next: Option<Track>,
+    outgoing: Option<(Track, u64)>,
+    ended: bool,
+    advances: u64,
+
+    playing: bool,
+    level: f32,
+
+    crossfade: Duration,
+    fade: Duration,
+}
+
+struct Track {
+    id: usize,
+    samples: Box<dyn Iterator<Item = f32> + Send>,
+    played: u64,
+    len: u64,
+    gain: f32,
+}
+
+struct DeckSource {
+    deck: Arc<Mutex<Deck>>,
+    channels: u16,
+    sample_rate: u32,
+
+    block: Vec<f32>,
+    pos: usize,
+}
+
+/// Source adapter copying samples to a shared buffer
+struct Tap<S: Source<Item = f32>> {
+    source: S,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+    block: Vec<f32>,
+}
+
+impl Player {
+    pub fn new(device: &Device, dsp: DspHandle) -> anyhow::Result<Self> {
+        let format = device
+            .default_output_format()
+            .context("No default output format")?;
+        let channels = format.channels;
+        let sample_rate = format.sample_rate.0;
+
+        let deck = Arc::new(Mutex::new(Deck {
+            channels,
+            sample_rate,
+
+            current: None,
+            next: None,
+            outgoing: None,
+            ended: false,
+            advances: 0,
+
+            playing: false,
+            level: 0.0,
+
+            crossfade: Duration::new(0, 0),
+            fade: Duration::new(0, 0),
+        }));
+
+        let sink = Sink::new(device);
+        let source = DeckSource {
+            deck: deck.clone(),
+            channels,
+            sample_rate,
+
+            block: Vec::with_capacity(BLOCK_FRAMES * channels as usize),
+            pos: 0,
+        };
+        let tap = Arc::new(Mutex::new(VecDeque::with_capacity(
+            TAP_FRAMES * channels as usize,
+        )));
+        sink.append(Tap {
+            source: Dsp::new(source, dsp),
+            buffer: tap.clone(),
+            block: Vec::with_capacity(BLOCK_FRAMES * channels as usize),
+        });
+        sink.play();
+
+        Ok(Self {
+            sink,
+            deck,
+            tap,
+            channels,
+            sample_rate,
+        })
+    }
+
+    // Tracks are built and dropped without holding the deck lock,
+    // since copying samples would stall the audio thread rendering from the deck.
+
+    /// Replaces the current track, fading the previous one out
+    pub fn load(&self, id: usize, lilac: &Lilac, gain: f32) {
+        let track = self.track(id, lilac, gain, Duration::new(0, 0));
+
+        let _replaced = {
+            let mut deck = self.deck.lock().unwrap();
+            let fade = deck.samples(deck.fade);
+            let mut previous = deck.current.replace(track);
+            let outgoing = if deck.playing && fade > 0 {
+                let fading = previous.take().map(|t| (t, fade));
+                std::mem::replace(&mut deck.outgoing, fading)
+            } else {
+                None
+            };
+            deck.ended = false;
+            (previous, outgoing, deck.next.take())
+        };
+    }
+    /// Queues a track to play after the given one
+    ///
+    /// Nothing is queued when the player already moved past that track,
+    /// the caller being expected to catch up with `Status::advances` first.
+    pub fn preload(&self, after: usize, id: usize, lilac: &Lilac, gain: f32) {
+        let track = self.track(id, lilac, gain, Duration::new(0, 0));
+
+        let _replaced = {
+            let mut deck = self.deck.lock().unwrap();
+            match &deck.current {
+                Some(t) if t.id == after => deck.next.replace(track),
+                _ => Some(track),
+            }
+        };
+    }
+    /// Restarts the current track at a position, without fading
+    pub fn seek(&self, lilac: &Lilac, position: Duration) {
+        let current = self.deck.lock().unwrap().current.as_ref().map(|t| t.id);
+        let id = match current {
+            Some(id) => id,
+            None => return,
+        };
+        let mut track = self.track(id, lilac, 1.0, position);
+
+        let _replaced = {
+            let mut deck = self.deck.lock().unwrap();
+            match &deck.current {
+                Some(t) if t.id == id => {
+                    track.gain = t.gain;
+                    deck.current.replace(track)
+                }
+                _ => Some(track),
+            }
+        };
+    }
+    /// Forgets the preloaded track, stopping after the current one
+    pub fn unload_next(&self) {
+        let _next = self.deck.lock().unwrap().next.take();
+    }
+
+    pub fn set_playing(&self, playing: bool) {
+        let mut deck = self.deck.lock().unwrap();
+        deck.playing = playing;
+        if deck.fade == Duration::new(0, 0) {
+            deck.level = if playing { 1.0 } else { 0.0 };
+        }
+    }
+    pub fn set_volume(&self, volume: f32) {
+        self.sink.set_volume(volume);
+    }
+    /// Updates the gain of a loaded track
+    pub fn set_gain(&self, id: usize, gain: f32) {
+        let deck = &mut *self.deck.lock().unwrap();
+        for t in deck.current.iter_mut().chain(deck.next.iter_mut()) {
+            if t.id == id {
+                t.gain = gain;
+            }
+        }
+    }
+
+    /// Overlap between consecutive tracks, zero for gapless playback
+    pub fn set_crossfade(&self, crossfade: Duration) {
+        self.deck.lock().unwrap().crossfade = crossfade;
+    }
+    /// Fade out length on pause and skip, zero to cut immediately
+    pub fn set_fade(&self, fade: Duration) {
+        self.deck.lock().unwrap().fade = fade;
+    }
+    pub fn fade(&self) -> Duration {
+        self.deck.lock().unwrap().fade
+    }
+
+    pub fn status(&self) -> Status {
+        let deck = self.deck.lock().unwrap();
+        let to_duration = |samples: u64| {
+            Duration::from_secs_f64(samples as f64 / deck.channels as f64 / deck.sample_rate as f64)
+        };
+
+        match &deck.current {
+            Some(t) => Status {
+                position: to_duration(t.played),
+                advances: deck.advances,
+                ended: false,
+            },
+            None => Status {
+                position: Duration::new(0, 0),
+                advances: deck.advances,
+                ended: deck.ended,
+            },
+        }
+    }
+
+    fn track(&self, id: usize, lilac: &Lilac, gain: f32, start: Duration) -> Track {
+        Track::new(id, lilac, gain, start, self.channels, self.sample_rate)
+    }
+
+    pub fn recent(&self) -> Recent {
+        Recent {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            samples: self.tap.lock().unwrap().iter().copied().collect(),
+        }
+    }
+}
+
+impl Track {
+    /// Track starting at the given position, converted to the output format
+    fn new(
+        id: usize,
+        lilac: &Lilac,
+        gain: f32,
+        start: Duration,
+        channels: u16,
+        sample_rate: u32,
+    ) -> Self {
+        let to_samples = |frames: usize| {
+            frames as u64 * sample_rate as u64 / lilac.sample_rate as u64 * channels as u64
+        };
+        let frames = lilac.frames();
+        let skipped = lilac.frame_at(start).min(frames);
+        let source = match skipped {
+            0 => lilac.clone(),
+            _ => lilac.slice_frames(skipped..frames).unwrap(),
+        };
+
+        Track {
+            id,
+            samples: Box::new(UniformSourceIterator::<_, f32>::new(
+                source.source(),
+                channels,
+                sample_rate,
+            )),
+            played: to_samples(skipped),
+            len: to_samples(frames),
+            gain,
+        }
+    }
+}
+
+impl Deck {
+    fn samples(&self, duration: Duration) -> u64 {
+        (duration.as_secs_f64() * self.sample_rate as f64) as u64 * self.channels as u64
+    }
+
+    fn render(&mut self, block: &mut Vec<f32>) {
+        let fade = self.samples(self.fade).max(1) as f32;
+        let level_step = if self.playing { 1.0 } else { -1.0 } / fade;
+
+        for _ in 0..BLOCK_FRAMES * self.channels as usize {
+            if !self.playing && self.level <= 0.0 {
+                block.push(0.0);
+                continue;
+            }
+            self.level = (self.level + level_step).clamp(0.0, 1.0);
+
+            let mut out = 0.0;
+
+            if let Some((t, left)) = &mut self.outgoing {
+                match t.samples.next() {
+                    Some(s) if *left > 0 => {
+                        out += s * t.gain * *left as f32 / fade;
+                        *left -= 1;
+                    }
+                    _ => self.outgoing = None,
+                }
+            }
+
+            let crossfade = match (&self.current, &self.next) {
+                (Some(c), Some(n)) => {
+                    let len = self.samples(self.crossfade).min(c.len / 2).min(n.len / 2);
+                    len - len % self.channels as u64
+                }
+                _ => 0,
+            };
+            while let Some(t) = &mut self.current {
+                if let Some(s) = t.samples.next() {
+                    let remaining = t.len.saturating_sub(t.played);
+                    let mixing = crossfade > 0 && remaining <= crossfade;
+                    let factor = if mixing {
+                        remaining as f32 / crossfade as f32
+                    } else {
+                        1.0
+                    };
+                    out += s * t.gain * factor;
+                    t.played += 1;
+
+                    if mixing {
+                        if let Some(n) = &mut self.next {
+                            if let Some(s) = n.samples.next() {
+                                out += s * n.gain * (1.0 - factor);
+                                n.played += 1;
+                            }
+                        }
+                    }
+                    break;
+                }
+
+                self.current = self.next.take();
+                self.ended = self.current.is_none();
+                if !self.ended {
+                    self.advances += 1;
+                }
+            }
+
+            block.push(out * self.level);
+        }
+    }
+}
+
+impl Iterator for DeckSource {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos == self.block.len() {
+            self.block.clear();
+            self.pos = 0;
+            self.deck.lock().unwrap().render(&mut self.block);
+        }
+
+        let s = self.block[self.pos];
+        self.pos += 1;
+        Some(s)
+    }
+}
+impl Source for DeckSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Tap<S> {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.source.next()?;
+        self.block.push(s);
+
+        if self.block.len() == self.block.capacity() {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.extend(self.block.drain(..));
+            let excess = buffer
+                .len()
+                .saturating_sub(TAP_FRAMES * self.source.channels() as usize);
+            buffer.drain(..excess);
+        }
+        Some(s)
+    }
+}
+impl<S: Source<Item = f32>> Source for Tap<S> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lilac(samples: &[i32]) -> Lilac {
+        let json = serde_json::json!({
+            "channels": 1,
+            "sampleRate": 10,
+            "bitDepth": 16,
+            "samples": samples,
+        });
+        Lilac::read(json.to_string().as_bytes()).unwrap()
+    }
+
+    fn deck() -> Deck {
+        Deck {
+            channels: 1,
+            sample_rate: 10,
+
+            current: None,
+            next: None,
+            outgoing: None,
+            ended: false,
+            advances: 0,
+
+            playing: true,
+            level: 0.0,
+
+            crossfade: Duration::new(0, 0),
+            fade: Duration::new(0, 0),
+        }
+    }
+
+    fn track(id: usize, lilac: &Lilac, start: Duration) -> Track {
+        Track::new(id, lilac, 1.0, start, 1, 10)
+    }
+
+    #[test]
+    fn advances_without_gap() {
+        let mut deck = deck();
+        deck.current = Some(track(0, &lilac(&[-16384; 3]), Duration::new(0, 0)));
+        deck.next = Some(track(1, &lilac(&[-8192; 2]), Duration::new(0, 0)));
+
+        let mut block = Vec::new();
+        deck.render(&mut block);
+        assert_eq!(block.len(), BLOCK_FRAMES);
+        assert_eq!(block[..5], [-0.5, -0.5, -0.5, -0.25, -0.25]);
+        assert!(block[5..].iter().all(|&s| s == 0.0));
+        assert_eq!(deck.advances, 1);
+        assert!(deck.ended);
+        assert!(deck.current.is_none());
+    }
+
+    #[test]
+    fn starts_at_position() {
+        let lilac = lilac(&[-32768, -16384, -8192, -4096]);
+        let mut deck = deck();
+        deck.current = Some(track(0, &lilac, Duration::from_millis(200)));
+
+        let t = deck.current.as_ref().unwrap();
+        assert_eq!((t.played, t.len), (2, 4));
+
+        let mut block = Vec::new();
+        deck.render(&mut block);
+        assert_eq!(block[..3], [-0.25, -0.125, 0.0]);
+        assert_eq!(deck.advances, 0);
+        assert!(deck.ended);
+    }
+}
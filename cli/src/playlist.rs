@@ -0,0 +1,495 @@
+use anyhow::{bail, Context};
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    M3u,
+    M3u8,
+    Pls,
+    Xspf,
+}
+
+/// Playlist entry, with the metadata the format provides
+pub struct Entry {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl Format {
+    /// Infers the format from the file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "m3u" => Some(Format::M3u),
+            "m3u8" => Some(Format::M3u8),
+            "pls" => Some(Format::Pls),
+            "xspf" => Some(Format::Xspf),
+            _ => None,
+        }
+    }
+}
+
+/// Reads a playlist, resolving entries relative to its directory
+pub fn read(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let format = match Format::from_path(path) {
+        Some(f) => f,
+        None => bail!("Unsupported playlist format for `{}`", path.display()),
+    };
+    let bytes = fs::read(path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    let text = match String::from_utf8(bytes) {
+        Ok(t) => t,
+        // Plain M3U files are traditionally Latin-1
+        Err(e) => e.into_bytes().into_iter().map(char::from).collect(),
+    };
+    let text = text.trim_start_matches('\u{feff}');
+
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut entries = match format {
+        Format::M3u | Format::M3u8 => read_m3u(text),
+        Format::Pls => read_pls(text),
+        Format::Xspf => read_xspf(text),
+    };
+    for e in &mut entries {
+        e.path = base.join(&e.path);
+    }
+    Ok(entries)
+}
+
+/// Writes a playlist, with paths relative to its directory when possible
+pub fn write(path: &Path, entries: &[Entry]) -> anyhow::Result<()> {
+    let format = match Format::from_path(path) {
+        Some(f) => f,
+        None => bail!("Unsupported playlist format for `{}`", path.display()),
+    };
+
+    let base = absolute(path.parent().unwrap_or_else(|| Path::new("")))?;
+    let mut paths = Vec::with_capacity(entries.len());
+    for e in entries {
+        let p = absolute(&e.path)?;
+        paths.push(relative(&p, &base).unwrap_or(p));
+    }
+
+    let mut out = String::new();
+    match format {
+        Format::M3u | Format::M3u8 => {
+            out.push_str("#EXTM3U\n");
+            for (e, p) in entries.iter().zip(&paths) {
+                if let Some(t) = &e.title {
+                    let secs = e.duration.map_or(-1, |d| d.as_secs() as i64);
+                    writeln!(out, "#EXTINF:{},{}", secs, t)?;
+                }
+                writeln!(out, "{}", p.display())?;
+            }
+        }
+        Format::Pls => {
+            out.push_str("[playlist]\n");
+            for (i, (e, p)) in entries.iter().zip(&paths).enumerate() {
+                let n = i + 1;
+                writeln!(out, "File{}={}", n, p.display())?;
+                if let Some(t) = &e.title {
+                    writeln!(out, "Title{}={}", n, t)?;
+                }
+                if let Some(d) = e.duration {
+                    writeln!(out, "Length{}={}", n, d.as_secs())?;
+                }
+            }
+            writeln!(out, "NumberOfEntries={}\nVersion=2", entries.len())?;
+        }
+        Format::Xspf => {
+            out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            out.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+            out.push_str("  <trackList>\n");
+            for (e, p) in entries.iter().zip(&paths) {
+                out.push_str("    <track>\n");
+                writeln!(out, "      <location>{}</location>", escape(&to_uri(p)))?;
+                if let Some(t) = &e.title {
+                    writeln!(out, "      <title>{}</title>", escape(t))?;
+                }
+                if let Some(d) = e.duration {
+                    writeln!(out, "      <duration>{}</duration>", d.as_millis())?;
+                }
+                out.push_str("    </track>\n");
+            }
+            out.push_str("  </trackList>\n</playlist>\n");
+        }
+    }
+
+    // Plain M3U files are written as UTF-8 too, which `read` tries before Latin-1
+    fs::write(path, out).with_context(|| format!("Failed to write `{}`", path.display()))
+}
+
+fn read_m3u(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut info = None;
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>[ attributes],<title>
+            let (meta, title) = extinf.split_at(extinf.find(',').unwrap_or(extinf.len()));
+            let secs = meta
+                .split_whitespace()
+                .next()
+                .and_then(|s| s.parse::<i64>().ok());
+            info = Some((
+                Some(title.trim_start_matches(',').to_owned()).filter(|t| !t.is_empty()),
+                secs.filter(|&s| s >= 0)
+                    .map(|s| Duration::from_secs(s as u64)),
+            ));
+        } else if !line.starts_with('#') {
+            let (title, duration) = info.take().unwrap_or((None, None));
+            entries.push(Entry {
+                path: from_location(line),
+                title,
+                duration,
+            });
+        }
+    }
+    entries
+}
+
+fn read_pls(text: &str) -> Vec<Entry> {
+    let mut entries: Vec<(usize, Entry)> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim_end(), line[i + 1..].trim()),
+            None => continue,
+        };
+        let (field, n) = match key.find(|c: char| c.is_ascii_digit()) {
+            Some(i) => (&key[..i], key[i..].parse::<usize>()),
+            None => continue,
+        };
+        let n = match n {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+
+        let idx = match entries.iter().position(|(i, _)| *i == n) {
+            Some(idx) => idx,
+            None => {
+                entries.push((
+                    n,
+                    Entry {
+                        path: PathBuf::new(),
+                        title: None,
+                        duration: None,
+                    },
+                ));
+                entries.len() - 1
+            }
+        };
+        let entry = &mut entries[idx].1;
+        match field.to_lowercase().as_str() {
+            "file" => entry.path = from_location(value),
+            "title" => entry.title = Some(value.to_owned()),
+            "length" => {
+                entry.duration = value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|&s| s >= 0)
+                    .map(|s| Duration::from_secs(s as u64))
+            }
+            _ => (),
+        }
+    }
+
+    entries.sort_by_key(|(n, _)| *n);
+    entries
+        .into_iter()
+        .map(|(_, e)| e)
+        .filter(|e| e.path != PathBuf::new())
+        .collect()
+}
+
+fn read_xspf(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut rest = text;
+    while let Some((track, after)) = element(rest, "track") {
+        rest = after;
+        if let Some((location, _)) = element(track, "location") {
+            entries.push(Entry {
+                path: from_uri(&unescape(location.trim())),
+                title: element(track, "title").map(|(t, _)| unescape(t.trim())),
+                duration: element(track, "duration")
+                    .and_then(|(d, _)| d.trim().parse().ok())
+                    .map(Duration::from_millis),
+            });
+        }
+    }
+    entries
+}
+
+/// Contents of the first `<name>` element and the text after it
+fn element<'a>(text: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+
+    let mut start = 0;
+    loop {
+        let i = start + text[start..].find(&open)?;
+        let after = &text[i + open.len()..];
+        // Skip longer names sharing the prefix, like <trackList> for <track>
+        match after.chars().next() {
+            Some('>') | Some(' ') | Some('\t') | Some('\n') | Some('\r') => {
+                let content = &after[after.find('>')? + 1..];
+                let end = content.find(&close)?;
+                return Some((&content[..end], &content[end + close.len()..]));
+            }
+            _ => start = i + open.len(),
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Path of a location, which is either a plain path or a `file://` URI
+fn from_location(location: &str) -> PathBuf {
+    match location.strip_prefix("file://") {
+        // Skip the authority, usually empty or localhost
+        Some(rest) => PathBuf::from(percent_decode(&rest[rest.find('/').unwrap_or(0)..])),
+        None => PathBuf::from(location),
+    }
+}
+
+/// Path of a URI reference, which is percent encoded even when relative
+fn from_uri(uri: &str) -> PathBuf {
+    if uri.contains("://") {
+        from_location(uri)
+    } else {
+        PathBuf::from(percent_decode(uri))
+    }
+}
+
+/// Absolute paths become `file://` URIs, relative ones are percent encoded
+pub fn to_uri(path: &Path) -> String {
+    let mut uri = String::new();
+    if path.is_absolute() {
+        uri.push_str("file://");
+    }
+    for &b in path.to_string_lossy().as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(b as char)
+            }
+            _ => write!(uri, "%{:02X}", b).unwrap(),
+        }
+    }
+    uri
+}
+
+fn percent_decode(s: &str) -> String {
+    if !s.contains('%') {
+        return s.to_owned();
+    }
+
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn absolute(path: &Path) -> anyhow::Result<PathBuf> {
+    Ok(if path.is_absolute() {
+        path.to_owned()
+    } else {
+        std::env::current_dir()?.join(path)
+    })
+}
+
+/// Path relative to a base directory, both being absolute
+fn relative(path: &Path, base: &Path) -> Option<PathBuf> {
+    let path = normalize(path);
+    let base = normalize(base);
+
+    // Paths only sharing their root read better as absolute
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    if common < 2 {
+        return None;
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for c in &path[common..] {
+        relative.push(c);
+    }
+    Some(relative)
+}
+
+/// Components with `.` and `..` resolved lexically
+fn normalize(path: &Path) -> Vec<Component<'_>> {
+    let mut components = Vec::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                components.pop();
+            }
+            c => components.push(c),
+        }
+    }
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("lilac-playlist-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_m3u() {
+        let entries = read_m3u(
+            "#EXTM3U\n\
+             #EXTINF:123 tvg-id=\"x\",Artist - Title\n\
+             a/b.lilac\n\
+             # comment\n\
+             \n\
+             #EXTINF:-1,\n\
+             file:///music/c%20d.lilac\n\
+             e.lilac\n",
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].path, Path::new("a/b.lilac"));
+        assert_eq!(entries[0].title.as_deref(), Some("Artist - Title"));
+        assert_eq!(entries[0].duration, Some(Duration::from_secs(123)));
+        assert_eq!(entries[1].path, Path::new("/music/c d.lilac"));
+        assert_eq!(entries[1].title, None);
+        assert_eq!(entries[1].duration, None);
+        assert_eq!(entries[2].path, Path::new("e.lilac"));
+        assert_eq!(entries[2].title, None);
+    }
+
+    #[test]
+    fn reads_pls() {
+        let entries = read_pls(
+            "[playlist]\n\
+             Title2=Second\n\
+             File2=b.lilac\n\
+             File1 = a.lilac\n\
+             Length1=60\n\
+             Length2=-1\n\
+             Title3=No file\n\
+             NumberOfEntries=3\n\
+             Version=2\n",
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, Path::new("a.lilac"));
+        assert_eq!(entries[0].duration, Some(Duration::from_secs(60)));
+        assert_eq!(entries[1].path, Path::new("b.lilac"));
+        assert_eq!(entries[1].title.as_deref(), Some("Second"));
+        assert_eq!(entries[1].duration, None);
+    }
+
+    #[test]
+    fn reads_xspf() {
+        let entries = read_xspf(
+            "<playlist><trackList>\
+             <track><location>a%20%26%20b.lilac</location><title>A &amp; B</title>\
+             <duration>1500</duration></track>\
+             <track><title>No location</title></track>\
+             <track>\n<location>file:///c.lilac</location></track>\
+             </trackList></playlist>",
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, Path::new("a & b.lilac"));
+        assert_eq!(entries[0].title.as_deref(), Some("A & B"));
+        assert_eq!(entries[0].duration, Some(Duration::from_millis(1500)));
+        assert_eq!(entries[1].path, Path::new("/c.lilac"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = temp("round-trip");
+        let entries = vec![
+            Entry {
+                path: dir.join("sub/Ünïcode 音楽.lilac"),
+                title: Some("Ünïcode 音楽".to_owned()),
+                duration: Some(Duration::from_secs(42)),
+            },
+            Entry {
+                path: dir.join("plain.lilac"),
+                title: None,
+                duration: None,
+            },
+        ];
+
+        for name in &["list.m3u", "list.m3u8", "list.pls", "list.xspf"] {
+            let path = dir.join(name);
+            write(&path, &entries).unwrap();
+            let read = read(&path).unwrap();
+            assert_eq!(read.len(), 2, "{}", name);
+            for (r, e) in read.iter().zip(&entries) {
+                assert_eq!(r.path, e.path, "{}", name);
+                assert_eq!(r.title, e.title, "{}", name);
+            }
+            assert_eq!(read[0].duration, entries[0].duration, "{}", name);
+        }
+
+        let written = fs::read_to_string(dir.join("list.m3u")).unwrap();
+        assert!(written.contains("sub/Ünïcode 音楽.lilac"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_latin1() {
+        let dir = temp("latin1");
+        let path = dir.join("list.m3u");
+        fs::write(&path, b"caf\xe9.lilac\n").unwrap();
+        let entries = read(&path).unwrap();
+        assert_eq!(entries[0].path, dir.join("café.lilac"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(
+            relative(Path::new("/a/b/c.lilac"), Path::new("/a/d")),
+            Some(PathBuf::from("../b/c.lilac"))
+        );
+        assert_eq!(
+            relative(Path::new("/a/./b/../c.lilac"), Path::new("/a")),
+            Some(PathBuf::from("c.lilac"))
+        );
+        assert_eq!(relative(Path::new("/a/b.lilac"), Path::new("/c")), None);
+    }
+}
@@ -0,0 +1,504 @@
+use crate::playlist::{self, Entry};
+use lilac::Lilac;
+use rand::{seq::SliceRandom, Rng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Repeat {
+    Off,
+    All,
+    One,
+}
+
+/// Songs and the order they are played in
+///
+/// The order lists song indices, played ones first.
+/// Entries before the cursor are the history used to go back,
+/// entries after it are planned. In shuffle mode the planned entries
+/// are a permutation of the songs, so none repeats until all were played.
+///
+/// Songs also get an identifier which is kept when the queue is edited.
+pub struct Queue {
+    songs: Vec<Song>,
+    next_id: usize,
+    order: Vec<usize>,
+    cursor: usize,
+    /// Start of the last round planned when repeating all
+    round: usize,
+    pub shuffle: bool,
+    pub repeat: Repeat,
+}
+pub struct QueueEl<'a> {
+    pub idx: usize,
+    pub id: usize,
+    pub lilac: &'a Lilac,
+    pub path: &'a Path,
+}
+
+struct Song {
+    id: usize,
+    lilac: Lilac,
+    path: PathBuf,
+}
+
+impl Repeat {
+    pub fn next(self) -> Self {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        }
+    }
+}
+
+impl Queue {
+    pub fn new<'a, P>(files: &'a [P]) -> Result<Self, lilac::Error>
+    where
+        P: AsRef<Path> + Sync,
+        &'a [P]: IntoParallelIterator<Item = &'a P>,
+    {
+        let (songs, errors) = read(files);
+        for (p, e) in errors {
+            io::stderr()
+                .lock()
+                .write_fmt(format_args!("`{}`: {}\n", p.display(), e))
+                .ok();
+        }
+
+        let songs: Vec<Song> = songs
+            .into_iter()
+            .enumerate()
+            .map(|(id, (lilac, path))| Song { id, lilac, path })
+            .collect();
+        Ok(Self {
+            order: (0..songs.len()).collect(),
+            next_id: songs.len(),
+            songs,
+            cursor: 0,
+            round: 0,
+            shuffle: false,
+            repeat: Repeat::Off,
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.songs.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.songs.len()
+    }
+
+    pub fn current(&self) -> QueueEl<'_> {
+        self.el(self.order[self.cursor])
+    }
+    /// Song played after the current one when it ends
+    pub fn upcoming(&self) -> Option<QueueEl<'_>> {
+        match self.repeat {
+            Repeat::One => Some(self.current()),
+            _ => self.order.get(self.cursor + 1).map(|&idx| self.el(idx)),
+        }
+    }
+    pub fn paths(&self) -> Vec<&Path> {
+        self.songs.iter().map(|s| s.path.as_path()).collect()
+    }
+    pub fn files(&self) -> Vec<String> {
+        self.songs
+            .iter()
+            .map(|s| {
+                s.path
+                    .file_stem()
+                    .unwrap_or_else(|| s.path.as_os_str())
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    /// Skips to the next song, ignoring repeat one
+    pub fn next(&mut self) -> bool {
+        if self.cursor + 1 == self.order.len() {
+            return false;
+        }
+
+        self.cursor += 1;
+        self.plan();
+        true
+    }
+    /// Goes back to the previously played song
+    pub fn prev(&mut self) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+
+        self.cursor -= 1;
+        true
+    }
+    /// Moves on to the upcoming song once the current one ended
+    pub fn advance(&mut self) -> bool {
+        match self.repeat {
+            Repeat::One => true,
+            _ => self.next(),
+        }
+    }
+    /// Starts over with a fresh order
+    pub fn restart(&mut self) {
+        self.order = (0..self.songs.len()).collect();
+        self.cursor = 0;
+        self.round = 0;
+        if self.shuffle {
+            self.order.shuffle(&mut rand::thread_rng());
+        }
+    }
+
+    /// Starts at the given song, the ones before it becoming the history
+    pub fn resume(&mut self, idx: usize) {
+        self.order = (0..self.songs.len()).collect();
+        self.cursor = idx.min(self.songs.len() - 1);
+        self.round = 0;
+        self.replan();
+    }
+    /// Plays the given song next, keeping the history
+    pub fn jump(&mut self, idx: usize) {
+        self.order.truncate(self.cursor + 1);
+        self.order.push(idx.min(self.songs.len() - 1));
+        self.cursor += 1;
+        self.replan();
+    }
+
+    /// Appends songs read beforehand, see `read`
+    pub fn append(&mut self, songs: Vec<(Lilac, PathBuf)>) {
+        let start = self.songs.len();
+        for (lilac, path) in songs {
+            self.songs.push(Song {
+                id: self.next_id,
+                lilac,
+                path,
+            });
+            self.next_id += 1;
+        }
+
+        if self.shuffle {
+            // Spread the new songs over the rest of the round
+            let mut rng = rand::thread_rng();
+            for idx in start..self.songs.len() {
+                let end = if self.round > self.cursor {
+                    self.round
+                } else {
+                    self.order.len()
+                };
+                let at = rng.gen_range(self.cursor + 1..=end);
+                self.order.insert(at, idx);
+                if self.round > self.cursor {
+                    self.round += 1;
+                }
+            }
+        } else {
+            self.replan();
+        }
+    }
+    /// Removes a song, unless it is the current one
+    pub fn remove(&mut self, idx: usize) -> bool {
+        if idx == self.order[self.cursor] || idx >= self.songs.len() {
+            return false;
+        }
+
+        self.songs.remove(idx);
+        self.remap(|i| match i {
+            i if i == idx => None,
+            i if i > idx => Some(i - 1),
+            i => Some(i),
+        });
+        if !self.shuffle {
+            self.replan();
+        }
+        true
+    }
+    /// Swaps a song with its neighbour, returning its new index
+    pub fn shift(&mut self, idx: usize, up: bool) -> usize {
+        let other = match up {
+            true if idx > 0 => idx - 1,
+            false if idx + 1 < self.songs.len() => idx + 1,
+            _ => return idx,
+        };
+
+        self.songs.swap(idx, other);
+        self.remap(|i| match i {
+            i if i == idx => Some(other),
+            i if i == other => Some(idx),
+            i => Some(i),
+        });
+        if !self.shuffle {
+            self.replan();
+        }
+        other
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        self.replan();
+    }
+    /// Changes the repeat mode, keeping the shuffled order of the current round
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+        if repeat != Repeat::All && self.round > self.cursor {
+            self.order.truncate(self.round);
+        }
+        self.plan();
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let entries: Vec<Entry> = self
+            .songs
+            .iter()
+            .map(|s| Entry {
+                path: s.path.clone(),
+                title: s.lilac.title.as_ref().map(|t| match &s.lilac.artist {
+                    Some(a) => format!("{} - {}", a, t),
+                    None => t.clone(),
+                }),
+                duration: Some(s.lilac.duration()),
+            })
+            .collect();
+        playlist::write(path, &entries)
+    }
+
+    fn el(&self, idx: usize) -> QueueEl<'_> {
+        let s = &self.songs[idx];
+        QueueEl {
+            idx,
+            id: s.id,
+            lilac: &s.lilac,
+            path: &s.path,
+        }
+    }
+
+    /// Rewrites the song indices of the order, dropping unmapped ones
+    fn remap<F: Fn(usize) -> Option<usize>>(&mut self, f: F) {
+        let (cursor, round) = (self.cursor, self.round);
+        let mut order = Vec::with_capacity(self.order.len());
+        for (pos, &i) in self.order.iter().enumerate() {
+            match f(i) {
+                Some(i) => order.push(i),
+                None => {
+                    if pos < cursor {
+                        self.cursor -= 1;
+                    }
+                    if pos < round {
+                        self.round -= 1;
+                    }
+                }
+            }
+        }
+        self.order = order;
+    }
+
+    /// Replaces the planned songs following the current one
+    fn replan(&mut self) {
+        let current = self.order[self.cursor];
+        self.order.truncate(self.cursor + 1);
+        self.round = self.round.min(self.cursor);
+        if self.shuffle {
+            let mut rest: Vec<usize> = (0..self.songs.len()).filter(|&i| i != current).collect();
+            rest.shuffle(&mut rand::thread_rng());
+            self.order.extend(rest);
+        } else {
+            self.order.extend(current + 1..self.songs.len());
+        }
+        self.plan();
+    }
+    /// Plans another round when repeating all and nothing follows
+    fn plan(&mut self) {
+        if self.repeat != Repeat::All || self.cursor + 1 < self.order.len() {
+            return;
+        }
+
+        let mut round: Vec<usize> = (0..self.songs.len()).collect();
+        if self.shuffle {
+            round.shuffle(&mut rand::thread_rng());
+            // Avoid playing the same song twice in a row across rounds
+            if round.len() > 1 && round[0] == self.order[self.cursor] {
+                let last = round.len() - 1;
+                round.swap(0, last);
+            }
+        }
+        self.round = self.order.len();
+        self.order.extend(round);
+    }
+}
+
+/// Reads files in parallel, returning the songs and the files that failed
+#[allow(clippy::type_complexity)]
+pub fn read<'a, P>(files: &'a [P]) -> (Vec<(Lilac, PathBuf)>, Vec<(PathBuf, lilac::Error)>)
+where
+    P: AsRef<Path> + Sync,
+    &'a [P]: IntoParallelIterator<Item = &'a P>,
+{
+    let results: Vec<_> = files
+        .par_iter()
+        .map(|f| (f.as_ref().to_owned(), Lilac::read_file(f)))
+        .collect();
+
+    let mut songs = Vec::new();
+    let mut errors = Vec::new();
+    for (path, result) in results {
+        match result {
+            Ok(l) => songs.push((l, path)),
+            Err(e) => errors.push((path, e)),
+        }
+    }
+    (songs, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashSet, fs};
+
+    /// Files of silent songs in a fresh directory
+    fn files(name: &str, n: usize) -> (PathBuf, Vec<PathBuf>) {
+        let dir = std::env::temp_dir().join(format!("lilac-queue-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+
+        let json = r#"{"channels":1,"sampleRate":10,"bitDepth":16,"samples":[0,0]}"#;
+        let lilac = Lilac::read(json.as_bytes()).unwrap();
+        let paths: Vec<PathBuf> = (0..n).map(|i| dir.join(format!("{}.lilac", i))).collect();
+        for p in &paths {
+            lilac.write_file(p).unwrap();
+        }
+        (dir, paths)
+    }
+
+    /// Indices of the songs played, starting with the current one
+    fn played(queue: &mut Queue, n: usize) -> Vec<usize> {
+        let mut played = vec![queue.current().idx];
+        for _ in 1..n {
+            assert!(queue.advance());
+            played.push(queue.current().idx);
+        }
+        played
+    }
+
+    fn is_round(songs: &[usize], len: usize) -> bool {
+        songs.len() == len && songs.iter().collect::<HashSet<_>>().len() == len
+    }
+
+    #[test]
+    fn plays_in_order() {
+        let (dir, paths) = files("order", 3);
+        let mut queue = Queue::new(&paths).unwrap();
+
+        assert_eq!(played(&mut queue, 3), [0, 1, 2]);
+        assert!(queue.upcoming().is_none());
+        assert!(!queue.advance());
+
+        assert!(queue.prev());
+        assert_eq!(queue.current().idx, 1);
+        queue.restart();
+        assert_eq!(queue.current().idx, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repeats() {
+        let (dir, paths) = files("repeat", 3);
+        let mut queue = Queue::new(&paths).unwrap();
+
+        queue.set_repeat(Repeat::All);
+        assert_eq!(played(&mut queue, 7), [0, 1, 2, 0, 1, 2, 0]);
+
+        queue.set_repeat(Repeat::One);
+        assert_eq!(queue.upcoming().unwrap().idx, 0);
+        assert_eq!(played(&mut queue, 3), [0, 0, 0]);
+        assert!(queue.next());
+        assert_eq!(queue.current().idx, 1);
+
+        queue.set_repeat(Repeat::Off);
+        assert_eq!(played(&mut queue, 2), [1, 2]);
+        assert!(queue.upcoming().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shuffles_rounds() {
+        let (dir, paths) = files("shuffle", 5);
+        let mut queue = Queue::new(&paths).unwrap();
+
+        queue.set_shuffle(true);
+        queue.set_repeat(Repeat::All);
+        // The first round starts with the song that was current
+        let songs = played(&mut queue, 15);
+        for round in songs.chunks(5) {
+            assert!(is_round(round, 5), "{:?}", songs);
+        }
+        assert!(songs.windows(2).all(|w| w[0] != w[1]), "{:?}", songs);
+
+        // Turning repeat off keeps the rest of the round
+        queue.set_repeat(Repeat::Off);
+        let mut rest = 0;
+        while queue.advance() {
+            rest += 1;
+        }
+        assert_eq!(rest, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shuffles_new_songs_into_round() {
+        let (dir, paths) = files("extend", 6);
+        let mut queue = Queue::new(&paths[..3]).unwrap();
+
+        queue.set_shuffle(true);
+        let (songs, errors) = read(&paths[3..]);
+        assert!(errors.is_empty());
+        queue.append(songs);
+        assert_eq!(queue.len(), 6);
+        let songs = played(&mut queue, 6);
+        assert!(is_round(&songs, 6), "{:?}", songs);
+        assert!(queue.upcoming().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edits_keep_ids() {
+        let (dir, paths) = files("edit", 4);
+        let mut queue = Queue::new(&paths).unwrap();
+        queue.next();
+        let id = queue.current().id;
+
+        assert!(!queue.remove(1));
+        assert!(queue.remove(0));
+        assert_eq!(queue.len(), 3);
+        assert_eq!((queue.current().idx, queue.current().id), (0, id));
+        assert!(!queue.prev());
+
+        assert_eq!(queue.shift(0, false), 1);
+        assert_eq!((queue.current().idx, queue.current().id), (1, id));
+        assert_eq!(queue.upcoming().unwrap().idx, 2);
+
+        queue.jump(2);
+        assert_eq!(queue.current().idx, 2);
+        assert!(queue.prev());
+        assert_eq!(queue.current().id, id);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resumes() {
+        let (dir, paths) = files("resume", 3);
+        let mut queue = Queue::new(&paths).unwrap();
+
+        queue.resume(1);
+        assert_eq!(queue.current().idx, 1);
+        assert!(queue.prev());
+        assert_eq!(queue.current().idx, 0);
+
+        queue.resume(10);
+        assert_eq!(queue.current().idx, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,375 @@
+use crate::library::{Library, Track};
+use anyhow::{bail, Context};
+use lilac::Lilac;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use structopt::StructOpt;
+
+/// Largest accepted request head
+const MAX_HEAD: usize = 16 * 1024;
+/// Connections handled at once, others being turned away
+const MAX_CONNECTIONS: usize = 32;
+/// Longest wait for a client to send or receive data
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(StructOpt)]
+pub struct Opt {
+    /// Address to listen on
+    ///
+    /// Use 0.0.0.0:8080 to make the library available on the local network
+    #[structopt(short, long, name = "ADDRESS", default_value = "127.0.0.1:8080")]
+    address: SocketAddr,
+    /// Print every track request
+    #[structopt(short, long)]
+    verbose: bool,
+}
+
+/// Library listing entry
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry<'a> {
+    id: usize,
+    title: Option<&'a str>,
+    artist: Option<&'a str>,
+    album: Option<&'a str>,
+    track: Option<u32>,
+    year: Option<i32>,
+    duration: f64,
+    channels: u16,
+    sample_rate: u32,
+    bit_depth: u32,
+    /// Raw LILAC file
+    lilac: String,
+    /// WAV transcoded on the fly
+    wav: String,
+}
+
+struct Server {
+    library: Library,
+    verbose: bool,
+    /// Connections being handled
+    connections: AtomicUsize,
+    /// Last transcoded track, as browsers request the same file in ranges
+    wav: Mutex<Option<(usize, Arc<Vec<u8>>)>>,
+}
+
+struct Request {
+    head: bool,
+    path: String,
+    range: Option<String>,
+}
+
+pub fn main(opt: Opt) -> crate::Result {
+    let library = Library::load()?;
+    if library.tracks.is_empty() {
+        bail!("The library is empty, see `lilac library scan`");
+    }
+
+    let listener = TcpListener::bind(opt.address)
+        .with_context(|| format!("Failed to listen on {}", opt.address))?;
+    println!(
+        "Serving {} tracks on http://{}/tracks",
+        library.tracks.len(),
+        opt.address
+    );
+
+    let server = Arc::new(Server {
+        library,
+        verbose: opt.verbose,
+        connections: AtomicUsize::new(0),
+        wav: Mutex::new(None),
+    });
+    for stream in listener.incoming() {
+        let mut stream = match stream.and_then(|s| {
+            s.set_read_timeout(Some(TIMEOUT))?;
+            s.set_write_timeout(Some(TIMEOUT))?;
+            Ok(s)
+        }) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        if server.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            server.connections.fetch_sub(1, Ordering::SeqCst);
+            // The reply fits in the socket buffer, so this doesn't hold up the loop
+            error(&mut stream, 503, "Too many connections").ok();
+            continue;
+        }
+
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(e) = server.handle(stream) {
+                eprintln!("{}", e);
+            }
+            server.connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    crate::OK
+}
+
+impl Server {
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        let request = match read_request(&mut stream)? {
+            Ok(r) => r,
+            Err((status, message)) => return error(&mut stream, status, message),
+        };
+
+        let (id, extension) = match request.path.as_str() {
+            "/" | "/tracks" => return self.list(&mut stream, &request),
+            p => match p
+                .strip_prefix("/tracks/")
+                .and_then(|f| f.split_once('.'))
+                .and_then(|(id, ext)| Some((id.parse::<usize>().ok()?, ext)))
+            {
+                Some(r) => r,
+                None => return error(&mut stream, 404, "Not found"),
+            },
+        };
+        let track = match self.library.tracks.get(id) {
+            Some(t) => t,
+            None => return error(&mut stream, 404, "Unknown track"),
+        };
+        if self.verbose {
+            println!("{} {}", track.path.display(), extension);
+        }
+
+        match extension {
+            "lilac" => {
+                let mut file = match File::open(&track.path) {
+                    Ok(f) => f,
+                    Err(e) => return error(&mut stream, 500, &e.to_string()),
+                };
+                let len = file.metadata()?.len();
+                send(&mut stream, &request, &mut file, len, "audio/x-lilac")
+            }
+            "wav" => {
+                let wav = match self.wav(id, track) {
+                    Ok(w) => w,
+                    Err(e) => return error(&mut stream, 500, &format!("{:#}", e)),
+                };
+                let len = wav.len() as u64;
+                send(
+                    &mut stream,
+                    &request,
+                    &mut Cursor::new(&wav[..]),
+                    len,
+                    "audio/wav",
+                )
+            }
+            "flac" => error(&mut stream, 501, "FLAC encoding is not supported yet"),
+            _ => error(&mut stream, 404, "Unknown format"),
+        }
+    }
+
+    fn list(&self, stream: &mut TcpStream, request: &Request) -> io::Result<()> {
+        let entries: Vec<Entry> = self
+            .library
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(id, t)| Entry {
+                id,
+                title: t.title.as_deref(),
+                artist: t.artist.as_deref(),
+                album: t.album.as_deref(),
+                track: t.track,
+                year: t.year,
+                duration: t.duration,
+                channels: t.channels,
+                sample_rate: t.sample_rate,
+                bit_depth: t.bit_depth,
+                lilac: format!("/tracks/{}.lilac", id),
+                wav: format!("/tracks/{}.wav", id),
+            })
+            .collect();
+        let json = serde_json::to_vec(&entries)?;
+        let len = json.len() as u64;
+        send(
+            stream,
+            request,
+            &mut Cursor::new(json),
+            len,
+            "application/json",
+        )
+    }
+
+    /// WAV encoding of a track, reusing the last one when possible
+    fn wav(&self, id: usize, track: &Track) -> anyhow::Result<Arc<Vec<u8>>> {
+        if let Some((cached, wav)) = &*self.wav.lock().unwrap() {
+            if *cached == id {
+                return Ok(wav.clone());
+            }
+        }
+
+        let lilac = Lilac::read_file(&track.path)
+            .with_context(|| format!("Failed to read `{}`", track.path.display()))?;
+        let mut wav = Cursor::new(Vec::new());
+        lilac.to_wav(&mut wav)?;
+        let wav = Arc::new(wav.into_inner());
+        *self.wav.lock().unwrap() = Some((id, wav.clone()));
+        Ok(wav)
+    }
+}
+
+/// Reads the request line and headers, or the error status to reply with
+#[allow(clippy::type_complexity)]
+fn read_request(stream: &mut TcpStream) -> io::Result<Result<Request, (u16, &'static str)>> {
+    let mut reader = BufReader::new(stream);
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if reader.read_until(b'\n', &mut head)? == 0 || head.len() > MAX_HEAD {
+            return Ok(Err((400, "Bad request")));
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Request::new(&mut headers);
+    match parsed.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => (),
+        _ => return Ok(Err((400, "Bad request"))),
+    }
+    let head = match parsed.method {
+        Some("GET") => false,
+        Some("HEAD") => true,
+        _ => return Ok(Err((405, "Method not allowed"))),
+    };
+    let path = parsed.path.unwrap_or("/");
+    let range = parsed
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("range"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map(str::to_owned);
+
+    Ok(Ok(Request {
+        head,
+        path: path.split('?').next().unwrap_or(path).to_owned(),
+        range,
+    }))
+}
+
+/// Sends content, or the requested part of it
+fn send<R: Read + Seek>(
+    stream: &mut TcpStream,
+    request: &Request,
+    content: &mut R,
+    len: u64,
+    content_type: &str,
+) -> io::Result<()> {
+    let (status, start, end) = match request.range.as_deref().map(|r| range(r, len)) {
+        None | Some(Some(None)) => (200, 0, len),
+        Some(Some(Some((start, end)))) => (206, start, end),
+        Some(None) => {
+            return write!(
+                stream,
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n\
+                 Content-Length: 0\r\nConnection: close\r\n\r\n",
+                len
+            )
+        }
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Accept-Ranges: bytes\r\nConnection: close\r\n",
+        if status == 206 {
+            "206 Partial Content"
+        } else {
+            "200 OK"
+        },
+        content_type,
+        end - start,
+    );
+    if status == 206 {
+        head.push_str(&format!(
+            "Content-Range: bytes {}-{}/{}\r\n",
+            start,
+            end - 1,
+            len
+        ));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+
+    if !request.head {
+        content.seek(SeekFrom::Start(start))?;
+        io::copy(&mut content.take(end - start), stream)?;
+    }
+    stream.flush()
+}
+
+fn error(stream: &mut TcpStream, status: u16, message: &str) -> io::Result<()> {
+    let reason = match status {
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}\n",
+        status,
+        reason,
+        message.len() + 1,
+        message
+    )
+}
+
+/// Byte range of a `Range` header as a half open interval
+///
+/// `None` when the range can't be satisfied,
+/// `Some(None)` when the whole content should be sent instead.
+fn range(header: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        // Multiple ranges are not supported
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return Some(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(r) => r,
+        None => return Some(None),
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(s), Ok(e)) if s <= e => (s, e.saturating_add(1).min(len)),
+        (Ok(s), Err(_)) if end.is_empty() => (s, len),
+        (Err(_), Ok(suffix)) if start.is_empty() => (len.saturating_sub(suffix), len),
+        _ => return Some(None),
+    };
+    if start >= end {
+        return None;
+    }
+    Some(Some((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(range("bytes=0-99", 1000), Some(Some((0, 100))));
+        assert_eq!(range("bytes=900-", 1000), Some(Some((900, 1000))));
+        assert_eq!(range("bytes=-100", 1000), Some(Some((900, 1000))));
+        assert_eq!(range("bytes=500-5000", 1000), Some(Some((500, 1000))));
+        assert_eq!(range("bytes=1000-", 1000), None);
+        assert_eq!(range("bytes=0-1,5-9", 1000), Some(None));
+        assert_eq!(range("bytes=9-5", 1000), Some(None));
+        assert_eq!(range("items=0-9", 1000), Some(None));
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{
+    format,
+    queue::{Queue, Repeat},
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Interactive player state saved on exit,
+/// kept in the user data directory
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    /// Queued files, in queue order
+    pub files: Vec<PathBuf>,
+    pub current: PathBuf,
+    /// Position in the current file in seconds
+    pub position: f64,
+    pub volume: u16,
+    pub shuffle: bool,
+    pub repeat: Repeat,
+}
+
+impl Session {
+    pub fn new(queue: &Queue, position: Duration, volume: u16) -> Self {
+        Self {
+            files: queue.paths().into_iter().map(absolute).collect(),
+            current: absolute(queue.current().path),
+            position: position.as_secs_f64(),
+            volume,
+            shuffle: queue.shuffle,
+            repeat: queue.repeat,
+        }
+    }
+
+    /// Reads the last saved session, if any
+    pub fn load() -> anyhow::Result<Option<Self>> {
+        let path = match path() {
+            Some(p) if p.exists() => p,
+            _ => return Ok(None),
+        };
+        serde_json::from_reader(BufReader::new(File::open(&path)?))
+            .with_context(|| format!("Invalid session in `{}`", path.display()))
+    }
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = path().context("No data directory")?;
+        if let Some(p) = path.parent() {
+            fs::create_dir_all(p)?;
+        }
+        format::replace(&path, |temp| -> anyhow::Result<()> {
+            let mut writer = BufWriter::new(File::create(temp)?);
+            serde_json::to_writer(&mut writer, self)?;
+            writer.flush()?;
+            Ok(())
+        })
+    }
+}
+
+fn path() -> Option<PathBuf> {
+    dirs::data_dir().map(|d| d.join("lilac").join("session.json"))
+}
+
+/// Paths are stored absolute so the session can be resumed from anywhere
+fn absolute(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
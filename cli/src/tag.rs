@@ -0,0 +1,386 @@
+use crate::format;
+use anyhow::{bail, Context};
+use lilac::{Encoding, Lilac};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Opt {
+    /// LILAC files to tag
+    #[structopt(name = "FILES", required = true)]
+    files: Vec<PathBuf>,
+    /// Sets a tag, like artist=Name
+    ///
+    /// Tags are title, artist, album, album-artist, genre, year, track and disc.
+    #[structopt(short, long, name = "TAG=VALUE", number_of_values = 1)]
+    set: Vec<Assignment>,
+    /// Removes a tag
+    #[structopt(short, long, name = "TAG", number_of_values = 1)]
+    clear: Vec<Tag>,
+    /// Copies every tag from a file, in any importable format
+    #[structopt(long, name = "FILE")]
+    copy: Option<PathBuf>,
+    /// Reads tags from the file path
+    ///
+    /// The pattern is matched against the end of the path without extension.
+    /// %T matches the title, %A the artist, %a the album,
+    /// %B the album artist, %g the genre, %y the year,
+    /// %n the track number and %d the disc number,
+    /// none of them matching across directories.
+    #[structopt(long, name = "PATTERN")]
+    from_path: Option<PathPattern>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Tag {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Year,
+    Track,
+    Disc,
+}
+
+struct Assignment(Tag, String);
+
+/// Pattern of `--from-path`, with its placeholders checked when parsing
+struct PathPattern(String);
+
+/// Tags read from a path or another file
+#[derive(Default)]
+struct Tags {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    genre: Option<String>,
+    year: Option<i32>,
+    track: Option<u32>,
+    disc: Option<u32>,
+}
+
+impl FromStr for Tag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "title" => Ok(Tag::Title),
+            "artist" => Ok(Tag::Artist),
+            "album" => Ok(Tag::Album),
+            "album-artist" | "albumartist" => Ok(Tag::AlbumArtist),
+            "genre" => Ok(Tag::Genre),
+            "year" => Ok(Tag::Year),
+            "track" => Ok(Tag::Track),
+            "disc" => Ok(Tag::Disc),
+            _ => Err(format!("unknown tag `{}`", s)),
+        }
+    }
+}
+
+impl FromStr for Assignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tag, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected TAG=VALUE, got `{}`", s))?;
+        let tag: Tag = tag.trim().parse()?;
+        let valid = match tag {
+            Tag::Year => value.parse::<i32>().is_ok(),
+            Tag::Track | Tag::Disc => value.parse::<u32>().is_ok(),
+            _ => true,
+        };
+        if !valid {
+            return Err(format!("invalid {:?} `{}`", tag, value).to_lowercase());
+        }
+        Ok(Assignment(tag, value.to_owned()))
+    }
+}
+
+impl FromStr for PathPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '%' {
+                match chars.next() {
+                    Some('T' | 'A' | 'a' | 'B' | 'g' | 'y' | 'n' | 'd' | '%') => (),
+                    Some(p) => return Err(format!("unknown placeholder `%{}`", p)),
+                    None => return Err("pattern ends with `%`".to_owned()),
+                }
+            }
+        }
+        Ok(PathPattern(s.to_owned()))
+    }
+}
+
+pub fn main(opt: Opt) -> crate::Result {
+    let copied = match &opt.copy {
+        Some(p) => {
+            let (lilac, _) =
+                format::read(p).with_context(|| format!("Failed to read `{}`", p.display()))?;
+            Some(Tags {
+                title: lilac.title,
+                artist: lilac.artist,
+                album: lilac.album,
+                album_artist: lilac.album_artist,
+                genre: lilac.genre,
+                year: lilac.year,
+                track: lilac.track,
+                disc: lilac.disc,
+            })
+        }
+        None => None,
+    };
+    let editing =
+        copied.is_some() || opt.from_path.is_some() || !opt.set.is_empty() || !opt.clear.is_empty();
+
+    let mut failed = 0;
+    for (n, path) in opt.files.iter().enumerate() {
+        let result = if editing {
+            edit(path, &opt, copied.as_ref())
+        } else {
+            if n > 0 {
+                println!();
+            }
+            view(path)
+        };
+        if let Err(e) = result {
+            eprintln!("{:#}", e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        bail!("Failed to tag {} files", failed);
+    }
+    crate::OK
+}
+
+fn view(path: &Path) -> anyhow::Result<()> {
+    let lilac =
+        Lilac::read_file(path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    let show = |s: &Option<String>| s.clone().unwrap_or_default();
+
+    println!("{}", path.display());
+    println!("  title         {}", show(&lilac.title));
+    println!("  artist        {}", show(&lilac.artist));
+    println!("  album         {}", show(&lilac.album));
+    println!("  album-artist  {}", show(&lilac.album_artist));
+    println!("  genre         {}", show(&lilac.genre));
+    println!(
+        "  year          {}",
+        lilac.year.map(|y| y.to_string()).unwrap_or_default()
+    );
+    println!(
+        "  track         {}",
+        lilac.track.map(|t| t.to_string()).unwrap_or_default()
+    );
+    println!(
+        "  disc          {}",
+        lilac.disc.map(|d| d.to_string()).unwrap_or_default()
+    );
+    Ok(())
+}
+
+fn edit(path: &Path, opt: &Opt, copied: Option<&Tags>) -> anyhow::Result<()> {
+    let mut lilac =
+        Lilac::read_file(path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    let original = lilac.clone();
+
+    if let Some(tags) = copied {
+        lilac.title = tags.title.clone();
+        lilac.artist = tags.artist.clone();
+        lilac.album = tags.album.clone();
+        lilac.album_artist = tags.album_artist.clone();
+        lilac.genre = tags.genre.clone();
+        lilac.year = tags.year;
+        lilac.track = tags.track;
+        lilac.disc = tags.disc;
+    }
+    if let Some(pattern) = &opt.from_path {
+        let tags = from_path(pattern, path)
+            .with_context(|| format!("`{}` doesn't match `{}`", path.display(), pattern.0))?;
+        lilac.title = tags.title.or(lilac.title);
+        lilac.artist = tags.artist.or(lilac.artist);
+        lilac.album = tags.album.or(lilac.album);
+        lilac.album_artist = tags.album_artist.or(lilac.album_artist);
+        lilac.genre = tags.genre.or(lilac.genre);
+        lilac.year = tags.year.or(lilac.year);
+        lilac.track = tags.track.or(lilac.track);
+        lilac.disc = tags.disc.or(lilac.disc);
+    }
+    for Assignment(tag, value) in &opt.set {
+        match tag {
+            Tag::Title => lilac.title = Some(value.clone()),
+            Tag::Artist => lilac.artist = Some(value.clone()),
+            Tag::Album => lilac.album = Some(value.clone()),
+            Tag::AlbumArtist => lilac.album_artist = Some(value.clone()),
+            Tag::Genre => lilac.genre = Some(value.clone()),
+            // Validated when parsing the argument
+            Tag::Year => lilac.year = value.parse().ok(),
+            Tag::Track => lilac.track = value.parse().ok(),
+            Tag::Disc => lilac.disc = value.parse().ok(),
+        }
+    }
+    for tag in &opt.clear {
+        match tag {
+            Tag::Title => lilac.title = None,
+            Tag::Artist => lilac.artist = None,
+            Tag::Album => lilac.album = None,
+            Tag::AlbumArtist => lilac.album_artist = None,
+            Tag::Genre => lilac.genre = None,
+            Tag::Year => lilac.year = None,
+            Tag::Track => lilac.track = None,
+            Tag::Disc => lilac.disc = None,
+        }
+    }
+
+    if lilac == original {
+        println!("`{}` unchanged", path.display());
+        return Ok(());
+    }
+    // Binary files can usually have their header rewritten in place
+    let in_place = lilac.encoding() == Encoding::Binary
+        && lilac
+            .write_tags(path)
+            .with_context(|| format!("Failed to write `{}`", path.display()))?;
+    if !in_place {
+        format::write_atomic(&lilac, path)?;
+    }
+    println!("`{}` updated", path.display());
+    Ok(())
+}
+
+/// Tags matched by a pattern against the end of a path
+fn from_path(pattern: &PathPattern, path: &Path) -> Option<Tags> {
+    let path = path.with_extension("");
+    let path = path.to_string_lossy().replace('\\', "/");
+    let pattern: Vec<char> = pattern.0.chars().collect();
+
+    // Only try matches starting at a component boundary
+    let starts = std::iter::once(0).chain(path.match_indices('/').map(|(i, _)| i + 1));
+    for start in starts {
+        let text: Vec<char> = path[start..].chars().collect();
+        let mut captures = Vec::new();
+        if matches(&pattern, &text, &mut captures) {
+            let mut tags = Tags::default();
+            for (placeholder, value) in captures {
+                match placeholder {
+                    'T' => tags.title = Some(value),
+                    'A' => tags.artist = Some(value),
+                    'a' => tags.album = Some(value),
+                    'B' => tags.album_artist = Some(value),
+                    'g' => tags.genre = Some(value),
+                    'y' => tags.year = Some(value.parse().ok()?),
+                    'n' => tags.track = Some(value.parse().ok()?),
+                    'd' => tags.disc = Some(value.parse().ok()?),
+                    _ => unreachable!(),
+                }
+            }
+            return Some(tags);
+        }
+    }
+    None
+}
+
+/// Matches the whole text, placeholders taking at least one character
+fn matches(pattern: &[char], text: &[char], captures: &mut Vec<(char, String)>) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['%', p @ ('T' | 'A' | 'a' | 'B' | 'g' | 'y' | 'n' | 'd'), rest @ ..] => {
+            let max = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            for end in 1..=max {
+                let value = &text[..end];
+                let numeric = matches!(p, 'y' | 'n' | 'd');
+                if numeric && !value.iter().all(char::is_ascii_digit) {
+                    break;
+                }
+                captures.push((*p, value.iter().collect()));
+                if matches(rest, &text[end..], captures) {
+                    return true;
+                }
+                captures.pop();
+            }
+            false
+        }
+        ['%', '%', rest @ ..] => text.first() == Some(&'%') && matches(rest, &text[1..], captures),
+        [c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..], captures),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pattern: &str, path: &str) -> Option<Tags> {
+        from_path(&pattern.parse().unwrap(), Path::new(path))
+    }
+
+    #[test]
+    fn matches_end_of_path() {
+        let t = tags("%A/%a/%n - %T", "music/Artist/Album/03 - A Song.lilac").unwrap();
+        assert_eq!(t.artist.as_deref(), Some("Artist"));
+        assert_eq!(t.album.as_deref(), Some("Album"));
+        assert_eq!(t.track, Some(3));
+        assert_eq!(t.title.as_deref(), Some("A Song"));
+        assert_eq!(t.year, None);
+    }
+
+    #[test]
+    fn numeric_placeholders() {
+        let t = tags("%B/%y - %a/%d-%n %T", "VA/1999 - Hits/2-07 Song.flac").unwrap();
+        assert_eq!(t.album_artist.as_deref(), Some("VA"));
+        assert_eq!(t.year, Some(1999));
+        assert_eq!(t.album.as_deref(), Some("Hits"));
+        assert_eq!(t.disc, Some(2));
+        assert_eq!(t.track, Some(7));
+        assert_eq!(t.title.as_deref(), Some("Song"));
+
+        assert!(tags("%n - %T", "xx - Song.lilac").is_none());
+    }
+
+    #[test]
+    fn component_boundaries() {
+        // Placeholders never match across directories
+        assert_eq!(tags("%T", "a/b.lilac").unwrap().title.as_deref(), Some("b"));
+        assert!(tags("%A - %T", "a - b/c.lilac").is_none());
+        // Matches start at a component boundary
+        assert!(tags("b - %T", "ab - c.lilac").is_none());
+        assert!(tags("b - %T", "a/b - c.lilac").is_some());
+    }
+
+    #[test]
+    fn literal_percent() {
+        let t = tags("100%% %T", "100% Song.lilac").unwrap();
+        assert_eq!(t.title.as_deref(), Some("Song"));
+        assert!(tags("100%% %T", "100 Song.lilac").is_none());
+    }
+
+    #[test]
+    fn assignments() {
+        let Assignment(tag, value) = "Album-Artist=Someone".parse().unwrap();
+        assert_eq!(tag, Tag::AlbumArtist);
+        assert_eq!(value, "Someone");
+        assert!("track=x".parse::<Assignment>().is_err());
+        assert!("disc=2".parse::<Assignment>().is_ok());
+        assert!("nope=2".parse::<Assignment>().is_err());
+        assert!("title".parse::<Assignment>().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let error = |s: &str| s.parse::<PathPattern>().err().unwrap();
+        assert_eq!(error("%A - %x"), "unknown placeholder `%x`");
+        assert_eq!(error("%F"), "unknown placeholder `%F`");
+        assert_eq!(error("%T%"), "pattern ends with `%`");
+        assert!("%B/%y - %a/%d-%n %T 100%%".parse::<PathPattern>().is_ok());
+        assert!(Opt::from_iter_safe(&["tag", "--from-path", "%x", "a.lilac"]).is_err());
+    }
+}
@@ -1,134 +1,1002 @@
-use anyhow::Context;
-use lilac::Lilac;
+use crate::{
+    config,
+    format::{self, Format},
+    pattern::{Pattern, Sanitize},
+};
+use anyhow::{bail, Context};
+use crossterm::{
+    cursor, execute,
+    terminal::{Clear, ClearType},
+};
+use lilac::{Dither, Encoding, Lilac, NoiseShaping};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, File},
-    io::{BufReader, Read, Seek, SeekFrom},
-    path::PathBuf,
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    io::{self, IsTerminal, Write},
+    mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
+use structopt::StructOpt;
+use walkdir::{DirEntry, WalkDir};
+
+#[derive(StructOpt)]
+pub struct Opt {
+    /// Glob matching the input files
+    #[structopt(
+        name = "GLOB",
+        required_unless = "INPUT_DIR",
+        conflicts_with = "INPUT_DIR"
+    )]
+    glob: Option<String>,
+    /// Output files naming pattern
+    ///
+    /// %F is replaced with the input filename without extension,
+    /// %D with the input directory name,
+    /// %E with the output format extension,
+    /// %e with the input format extension,
+    /// %T with the song title,
+    /// %A with the song artist,
+    /// %B with the album artist,
+    /// %a with the song album,
+    /// %g with the genre,
+    /// %y with the year,
+    /// %n with the track number padded to two digits,
+    /// %d with the disc number,
+    /// %r with the sample rate in Hz,
+    /// %b with the bit depth.
+    ///
+    /// [...] is left out when a tag inside it is missing, like [%n - ]%T,
+    /// and {...|...} uses the first alternative with every tag present,
+    /// like {%B|%A|Various}. Other missing tags are replaced with Unknown.
+    /// %%, %[, %], %{, %} and %| are literal characters.
+    ///
+    /// Defaults to the configured pattern, or %F.%E
+    #[structopt(name = "PATTERN")]
+    output: Option<Pattern>,
+    /// Characters replaced in tags substituted into the pattern
+    ///
+    /// One of portable, separators or none. Portable replaces path separators
+    /// and characters or names reserved on common filesystems,
+    /// separators only path separators, and none keeps tags verbatim.
+    /// Defaults to the configured mode, or portable
+    #[structopt(long, name = "MODE")]
+    sanitize: Option<Sanitize>,
+    /// Character replacing sanitized ones
+    ///
+    /// Defaults to the configured character, or _
+    #[structopt(long, name = "CHAR")]
+    replacement: Option<char>,
+    /// Output format
+    ///
+    /// One of auto, lilac or wav. Auto transcodes LILAC files
+    /// to WAV and other files to LILAC.
+    /// Defaults to the configured format, or auto
+    #[structopt(short, long, name = "FORMAT")]
+    format: Option<Target>,
+    /// Encoding of LILAC output files
+    ///
+    /// One of json or binary. Binary files are smaller and
+    /// can be partially recovered when damaged.
+    /// Defaults to the encoding of LILAC input files, or json
+    #[structopt(short, long, name = "ENCODING")]
+    encoding: Option<Encoding>,
+    /// Keep input files after transcoding
+    #[structopt(short, long)]
+    keep: bool,
+    /// Overwrite existing files, including the input itself
+    #[structopt(long)]
+    force: bool,
+    /// Decode every output and compare it with the input before removing the input
+    #[structopt(long)]
+    verify: bool,
+    /// Directory to mirror instead of transcoding files in place
+    ///
+    /// Audio files are transcoded to the same relative path in the output
+    /// directory, and other files like cover art are copied.
+    /// Inputs are always kept and outputs newer than their input are skipped,
+    /// so that running it again only processes what changed.
+    #[structopt(long, name = "INPUT_DIR", requires = "OUTPUT_DIR")]
+    input_dir: Option<PathBuf>,
+    /// Directory receiving the mirrored tree
+    #[structopt(long, name = "OUTPUT_DIR", requires = "INPUT_DIR")]
+    output_dir: Option<PathBuf>,
+    /// Remove files in the output directory without a matching input
+    #[structopt(long, requires = "INPUT_DIR")]
+    delete: bool,
+    /// Write a JSON report of the succeeded, failed and skipped files
+    #[structopt(long, name = "REPORT")]
+    report: Option<PathBuf>,
+    /// Convert the samples to this bit depth
+    #[structopt(short, long, name = "BITS")]
+    bit_depth: Option<u32>,
+    /// Dither used when lowering the bit depth
+    ///
+    /// One of none, rpdf or tpdf
+    #[structopt(long, name = "DITHER", default_value = "tpdf")]
+    dither: Dither,
+    /// Noise shaping used when lowering the bit depth
+    ///
+    /// One of none, first-order or lipshitz
+    #[structopt(long, name = "FILTER", default_value = "none")]
+    noise_shaping: NoiseShaping,
+    /// Remove leading and trailing silence
+    #[structopt(short, long)]
+    trim_silence: bool,
+    /// Level under which audio is considered silent, in dBFS
+    #[structopt(long, name = "DB", default_value = "-60", allow_hyphen_values = true)]
+    silence_threshold: f32,
+}
+
+/// Output format
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Auto,
+    Lilac,
+    Wav,
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Target::Auto => "auto",
+            Target::Lilac => "lilac",
+            Target::Wav => "wav",
+        })
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "auto" => Ok(Target::Auto),
+            "lilac" => Ok(Target::Lilac),
+            "wav" => Ok(Target::Wav),
+            _ => Err(format!("unknown output format `{}`", s)),
+        }
+    }
+}
+
+/// File to process and the file it's transcoded or copied to
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+    /// Size of the input, used to estimate the remaining time
+    size: u64,
+}
+
+/// Files to process, with the outputs every input maps to
+#[derive(Default)]
+struct Plan {
+    jobs: Vec<Job>,
+    /// Inputs of every output, by absolute path
+    outputs: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Inputs whose output is up to date
+    skipped: Vec<PathBuf>,
+    /// Entries that couldn't be read
+    failed: Vec<Outcome>,
+    /// Output directories mirroring unreadable entries, left alone when deleting orphans
+    kept: Vec<PathBuf>,
+}
 
-static MP3_MAGIC_NUMBERS: &[&[u8]] = &[&[0xFF, 0xFB], &[0xFF, 0xF3], &[0xFF, 0xF2], b"ID3"];
-static FLAC_MAGIC_NUMBER: &[u8] = b"fLaC";
-static OGG_MAGIC_NUMBER: &[u8] = b"OggS";
-static WAV_MAGIC_NUMBER: &[u8] = b"WAVE";
-const WAV_MAGIC_NUMBER_OFFSET: usize = 8;
-
-pub fn main(glob: String, output: String, keep: bool) -> crate::Result {
-    let files = glob::glob(&glob)?;
-    let results: Vec<anyhow::Result<(PathBuf, PathBuf)>> = files
-        .par_bridge()
-        .map(|r| transcode(r?, &output, keep))
+struct Outcome {
+    input: PathBuf,
+    result: anyhow::Result<Done>,
+}
+struct Done {
+    output: PathBuf,
+    /// Duration of the transcoded audio, none for copied files
+    audio: Option<Duration>,
+}
+
+/// Progress line redrawn on the standard error when it's a terminal
+struct Progress {
+    tty: bool,
+    start: Instant,
+    files: usize,
+    bytes: u64,
+    state: Mutex<ProgressState>,
+}
+#[derive(Default)]
+struct ProgressState {
+    files: usize,
+    bytes: u64,
+    audio: Duration,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Report<'a> {
+    succeeded: Vec<Succeeded<'a>>,
+    failed: Vec<Failed<'a>>,
+    skipped: &'a [PathBuf],
+    /// Wall clock time in seconds
+    elapsed: f64,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Succeeded<'a> {
+    input: &'a Path,
+    output: &'a Path,
+    /// Seconds of audio, none for copied files
+    duration: Option<f64>,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Failed<'a> {
+    input: &'a Path,
+    error: String,
+}
+
+pub fn main(mut opt: Opt, config: &config::Transcode) -> crate::Result {
+    opt.output.get_or_insert_with(|| config.pattern.clone());
+    opt.format.get_or_insert(config.format);
+    opt.sanitize.get_or_insert(config.sanitize);
+    let replacement = *opt.replacement.get_or_insert(config.replacement);
+    if Sanitize::Portable.reserves(replacement) {
+        bail!(
+            "`{}` can't replace reserved characters",
+            replacement.escape_debug()
+        );
+    }
+
+    let mut plan = match (&opt.input_dir, &opt.output_dir) {
+        (Some(input), Some(output)) => mirror(input, output, &opt)?,
+        _ => find(&opt)?,
+    };
+    plan.refuse_collisions();
+
+    let progress = Progress::new(&plan.jobs);
+    let mut outcomes: Vec<Outcome> = mem::take(&mut plan.jobs)
+        .into_par_iter()
+        .map(|job| {
+            let outcome = Outcome {
+                result: run(&job, &opt),
+                input: job.input,
+            };
+            progress.update(&outcome, job.size);
+            outcome
+        })
         .collect();
-    for r in results {
-        match r {
-            Ok((i, o)) => println!("`{}` -> `{}`", i.display(), o.display()),
-            Err(e) => eprintln!("{:#}", e),
+    progress.clear();
+    outcomes.append(&mut plan.failed);
+
+    if opt.delete {
+        if let Some(output) = &opt.output_dir {
+            outcomes.extend(delete_orphans(output, &plan));
         }
     }
 
+    let succeeded: Vec<Succeeded> = outcomes
+        .iter()
+        .filter_map(|o| match &o.result {
+            Ok(d) => Some(Succeeded {
+                input: &o.input,
+                output: &d.output,
+                duration: d.audio.map(|a| a.as_secs_f64()),
+            }),
+            Err(_) => None,
+        })
+        .collect();
+    let failed: Vec<Failed> = outcomes
+        .iter()
+        .filter_map(|o| match &o.result {
+            Ok(_) => None,
+            Err(e) => Some(Failed {
+                input: &o.input,
+                error: format!("{:#}", e),
+            }),
+        })
+        .collect();
+    println!(
+        "{} succeeded, {} failed, {} skipped in {}",
+        succeeded.len(),
+        failed.len(),
+        plan.skipped.len(),
+        time(progress.start.elapsed()),
+    );
+
+    if let Some(path) = &opt.report {
+        let report = Report {
+            elapsed: progress.start.elapsed().as_secs_f64(),
+            skipped: &plan.skipped,
+            succeeded,
+            failed,
+        };
+        let json = serde_json::to_string_pretty(&report)?;
+        fs::write(path, json).with_context(|| format!("Failed to write `{}`", path.display()))?;
+        if !report.failed.is_empty() {
+            bail!("{} files failed", report.failed.len());
+        }
+    } else if !failed.is_empty() {
+        bail!("{} files failed", failed.len());
+    }
     crate::OK
 }
 
-enum Format {
-    Lilac,
-    Mp3,
-    Flac,
-    Ogg,
-    Wav,
+/// Plans transcoding the files matching the glob next to them
+///
+/// Files are only read when naming their output needs their tags or format.
+fn find(opt: &Opt) -> anyhow::Result<Plan> {
+    let mut plan = Plan::default();
+    let mut inputs = Vec::new();
+    for entry in glob::glob(opt.glob.as_deref().unwrap_or_default())? {
+        match entry {
+            Ok(input) => inputs.push(input),
+            Err(e) => plan.failed.push(Outcome {
+                input: e.path().to_owned(),
+                result: Err(e.into()),
+            }),
+        }
+    }
+
+    let outputs: Vec<_> = inputs
+        .into_par_iter()
+        .map(|input| {
+            let output = output(&input, opt).and_then(|o| Ok((resolve(&o)?, o)));
+            (input, output)
+        })
+        .collect();
+    for (input, output) in outputs {
+        match output {
+            Ok((claim, output)) => {
+                plan.outputs.entry(claim).or_default().push(input.clone());
+                plan.jobs.push(Job {
+                    size: fs::metadata(&input).map_or(0, |m| m.len()),
+                    input,
+                    output,
+                });
+            }
+            Err(e) => plan.failed.push(Outcome {
+                input,
+                result: Err(e),
+            }),
+        }
+    }
+    Ok(plan)
 }
 
-fn transcode(filename: PathBuf, output: &str, keep: bool) -> anyhow::Result<(PathBuf, PathBuf)> {
-    let reader = BufReader::new(File::open(&filename)?);
-
-    let (lilac, format) = match filename
-        .extension()
-        .map(|e| e.to_str().map(|e| e.to_lowercase()))
-    {
-        Some(Some(s)) => match s.as_ref() {
-            "lilac" => (Lilac::read(reader)?, Format::Lilac),
-            "mp3" => (Lilac::from_mp3(reader)?, Format::Mp3),
-            "flac" => (Lilac::from_flac(reader)?, Format::Flac),
-            "ogg" => (Lilac::from_ogg(reader)?, Format::Ogg),
-            "wav" => (Lilac::from_wav(reader)?, Format::Wav),
-            _ => detect(reader)?,
-        },
-        _ => detect(reader)?,
+/// Plans mirroring the input directory tree into the output directory
+///
+/// Files whose output is newer than them are skipped, as are hidden files
+/// like peak caches and the temporary files of interrupted runs.
+fn mirror(input: &Path, output: &Path, opt: &Opt) -> anyhow::Result<Plan> {
+    let input = input
+        .canonicalize()
+        .with_context(|| format!("Failed to read `{}`", input.display()))?;
+    fs::create_dir_all(output)?;
+    let output = output.canonicalize()?;
+    // Inputs would be mistaken for orphans of the output directory
+    if input.starts_with(&output) {
+        bail!(
+            "The output directory `{}` can't be or contain the input directory",
+            output.display()
+        );
+    }
+
+    let mut plan = Plan::default();
+    // The output directory is skipped when it's inside the input one
+    let entries = WalkDir::new(&input)
+        .into_iter()
+        .filter_entry(|e| e.path() != output && (e.depth() == 0 || !is_hidden(e)));
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) if e.depth() == 0 => {
+                return Err(e).with_context(|| format!("Failed to read `{}`", input.display()))
+            }
+            Err(e) => {
+                let path = e.path().unwrap_or(&input).to_owned();
+                if let Some(dir) = path
+                    .strip_prefix(&input)
+                    .ok()
+                    .and_then(|r| output.join(r).parent().map(Path::to_owned))
+                {
+                    plan.kept.push(dir);
+                }
+                plan.failed.push(Outcome {
+                    input: path,
+                    result: Err(e.into()),
+                });
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(&input)?;
+        let outfile = match Format::from_path(relative) {
+            Some(format) => output
+                .join(relative)
+                .with_extension(target(opt, format).to_string()),
+            None => output.join(relative),
+        };
+
+        plan.outputs
+            .entry(outfile.clone())
+            .or_default()
+            .push(entry.path().to_owned());
+        let metadata = match entry.metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                plan.failed.push(Outcome {
+                    input: entry.into_path(),
+                    result: Err(e.into()),
+                });
+                continue;
+            }
+        };
+        let modified = fs::metadata(&outfile).and_then(|m| m.modified()).ok();
+        match (metadata.modified().ok(), modified) {
+            (Some(i), Some(o)) if o >= i => plan.skipped.push(entry.into_path()),
+            _ => plan.jobs.push(Job {
+                input: entry.into_path(),
+                output: outfile,
+                size: metadata.len(),
+            }),
+        }
+    }
+    Ok(plan)
+}
+
+/// Removes the files of the output directory no input maps to, returning the failures
+///
+/// Hidden files are kept, as are the directories mirroring unreadable inputs.
+fn delete_orphans(output: &Path, plan: &Plan) -> Vec<Outcome> {
+    let mut failed = Vec::new();
+    let output = match output.canonicalize() {
+        Ok(o) => o,
+        Err(e) => {
+            failed.push(Outcome {
+                input: output.to_owned(),
+                result: Err(e.into()),
+            });
+            return failed;
+        }
     };
 
-    let output = output
-        .replace(
-            "%F",
-            filename
-                .file_stem()
-                .context("Invalid filename")?
-                .to_string_lossy()
-                .as_ref(),
-        )
-        .replace(
-            "%E",
-            match format {
-                Format::Lilac => "wav",
-                _ => "lilac",
-            },
-        )
-        .replace(
-            "%e",
-            match format {
-                Format::Lilac => "lilac",
-                Format::Mp3 => "mp3",
-                Format::Flac => "flac",
-                Format::Ogg => "ogg",
-                Format::Wav => "wav",
-            },
-        )
-        .replace("%T", lilac.title())
-        .replace("%A", lilac.artist())
-        .replace("%a", lilac.album());
-    let outfile = filename
-        .parent()
-        .map(|p| p.join(&output))
-        .unwrap_or_else(|| PathBuf::from(output));
+    // Directories are visited after their contents, so emptied ones can go too
+    let entries = WalkDir::new(&output)
+        .min_depth(1)
+        .contents_first(true)
+        .into_iter()
+        .filter_entry(|e| !is_hidden(e));
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                failed.push(Outcome {
+                    input: e.path().unwrap_or(&output).to_owned(),
+                    result: Err(e.into()),
+                });
+                continue;
+            }
+        };
+        let path = entry.path();
+        if plan.kept.iter().any(|k| path.starts_with(k)) {
+            continue;
+        }
+
+        let result = if entry.file_type().is_dir() {
+            fs::read_dir(path).and_then(|mut d| match d.next() {
+                None => fs::remove_dir(path),
+                Some(_) => Ok(()),
+            })
+        } else if !plan.outputs.contains_key(path) {
+            fs::remove_file(path).map(|_| println!("Removed `{}`", path.display()))
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            failed.push(Outcome {
+                input: path.to_owned(),
+                result: Err(anyhow::Error::new(e).context("Failed to remove orphan")),
+            });
+        }
+    }
+    failed
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.file_name().to_string_lossy().starts_with('.')
+}
+
+/// Transcodes an audio file or copies any other file when mirroring
+fn run(job: &Job, opt: &Opt) -> anyhow::Result<Done> {
+    let output = job.output.clone();
+    if opt.input_dir.is_some() && Format::from_path(&job.input).is_none() {
+        if let Some(p) = output.parent() {
+            fs::create_dir_all(p)?;
+        }
+        format::replace(&output, |temp| fs::copy(&job.input, temp).map(|_| ()))?;
+        return Ok(Done {
+            output,
+            audio: None,
+        });
+    }
+
+    let audio = transcode(&job.input, &output, opt)?;
+    Ok(Done {
+        output,
+        audio: Some(audio),
+    })
+}
+
+impl Plan {
+    /// Fails every input sharing its output with another one
+    ///
+    /// This happens before writing anything, so that which input
+    /// would have won never depends on the order jobs finish in.
+    fn refuse_collisions(&mut self) {
+        let colliding: HashMap<&Path, (&Path, &[PathBuf])> = self
+            .outputs
+            .iter()
+            .filter(|(_, inputs)| inputs.len() > 1)
+            .flat_map(|(output, inputs)| {
+                inputs
+                    .iter()
+                    .map(move |i| (i.as_path(), (output.as_path(), inputs.as_slice())))
+            })
+            .collect();
+        if colliding.is_empty() {
+            return;
+        }
+
+        let mut failed = Vec::new();
+        let mut refuse = |input: &Path| match colliding.get(input) {
+            Some((output, inputs)) => {
+                let inputs: Vec<String> = inputs
+                    .iter()
+                    .map(|i| format!("`{}`", i.display()))
+                    .collect();
+                failed.push(Outcome {
+                    input: input.to_owned(),
+                    result: Err(anyhow::anyhow!(
+                        "{} all transcode to `{}`",
+                        inputs.join(", "),
+                        output.display()
+                    )),
+                });
+                true
+            }
+            None => false,
+        };
+        self.jobs.retain(|j| !refuse(&j.input));
+        self.skipped.retain(|i| !refuse(i));
+        self.failed.append(&mut failed);
+    }
+}
 
+impl Progress {
+    fn new(jobs: &[Job]) -> Self {
+        Self {
+            tty: io::stderr().is_terminal(),
+            start: Instant::now(),
+            files: jobs.len(),
+            bytes: jobs.iter().map(|j| j.size).sum(),
+            state: Mutex::new(ProgressState::default()),
+        }
+    }
+
+    /// Prints the outcome of a file and redraws the progress line
+    fn update(&self, outcome: &Outcome, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.files += 1;
+        state.bytes += size;
+        if let Ok(Done { audio: Some(a), .. }) = &outcome.result {
+            state.audio += *a;
+        }
+
+        self.clear();
+        match &outcome.result {
+            Ok(d) => println!("`{}` -> `{}`", outcome.input.display(), d.output.display()),
+            Err(e) => eprintln!("`{}`: {:#}", outcome.input.display(), e),
+        }
+        if !self.tty || state.files == self.files {
+            return;
+        }
+
+        let elapsed = self.start.elapsed();
+        let eta = match state.bytes {
+            0 => "unknown".to_owned(),
+            done => time(elapsed.mul_f64(self.bytes.saturating_sub(done) as f64 / done as f64)),
+        };
+        eprint!(
+            "[{}/{}] {} of audio, ETA {}",
+            state.files,
+            self.files,
+            time(state.audio),
+            eta
+        );
+        let _ = io::stderr().flush();
+    }
+
+    fn clear(&self) {
+        if self.tty {
+            let _ = execute!(
+                io::stderr(),
+                cursor::MoveToColumn(0),
+                Clear(ClearType::CurrentLine)
+            );
+        }
+    }
+}
+
+fn time(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        h => format!("{}:{:02}:{:02}", h, secs / 60 % 60, secs % 60),
+    }
+}
+
+fn target(opt: &Opt, format: Format) -> Target {
+    match opt.format.unwrap_or(Target::Auto) {
+        Target::Auto => match format {
+            Format::Lilac => Target::Wav,
+            _ => Target::Lilac,
+        },
+        t => t,
+    }
+}
+
+/// Transcodes a file, returning the duration of the audio
+fn transcode(filename: &Path, outfile: &Path, opt: &Opt) -> anyhow::Result<Duration> {
+    let (mut lilac, format) = format::read(filename)?;
+
+    if opt.trim_silence {
+        lilac.trim_silence(opt.silence_threshold);
+    }
+    if let Some(encoding) = opt.encoding {
+        lilac.set_encoding(encoding);
+    }
+    if let Some(bit_depth) = opt.bit_depth {
+        lilac.convert_bit_depth(bit_depth, opt.dither, opt.noise_shaping)?;
+    }
+
+    let target = target(opt, format);
+    let mirroring = opt.input_dir.is_some();
     if let Some(p) = outfile.parent() {
         fs::create_dir_all(p)?;
     }
 
-    match format {
-        Format::Lilac => lilac.to_wav_file(&outfile)?,
-        _ => lilac.write_file(&outfile)?,
+    let in_place = resolve(outfile)? == resolve(filename)?;
+    if outfile.exists() && !opt.force && !mirroring {
+        if in_place {
+            bail!(
+                "`{}` would be overwritten by its own output, use --force to replace it",
+                filename.display()
+            );
+        }
+        bail!(
+            "`{}` already exists, use --force to overwrite it",
+            outfile.display()
+        );
     }
 
-    if !keep {
-        fs::remove_file(&filename)?;
+    // The temporary file is verified before it replaces anything
+    format::replace(outfile, |temp| -> anyhow::Result<()> {
+        match target {
+            Target::Wav => lilac.to_wav_file(temp)?,
+            _ => lilac.write_file(temp)?,
+        }
+        if opt.verify {
+            let (decoded, _) = format::read(temp)?;
+            let same = decoded.channels == lilac.channels
+                && decoded.sample_rate == lilac.sample_rate
+                && decoded.bit_depth == lilac.bit_depth
+                && decoded.md5() == lilac.md5();
+            if !same {
+                bail!(
+                    "`{}` wouldn't decode to the transcoded audio, keeping `{}`",
+                    outfile.display(),
+                    filename.display()
+                );
+            }
+        }
+        Ok(())
+    })?;
+
+    if !opt.keep && !in_place && !mirroring {
+        fs::remove_file(filename)?;
     }
-    Ok((filename, outfile))
+    Ok(lilac.duration())
 }
 
-fn detect<R: Read + Seek>(mut reader: R) -> anyhow::Result<(Lilac, Format)> {
-    let magic_numer_len = MP3_MAGIC_NUMBERS
+/// Output path of a file matched by the glob
+///
+/// The file is only read when the pattern uses its tags or audio properties,
+/// or when its format can't be told from its extension.
+fn output(filename: &Path, opt: &Opt) -> anyhow::Result<PathBuf> {
+    let pattern = opt.output.clone().unwrap_or_default();
+    let needs_file = pattern
+        .fields()
         .iter()
-        .fold(0, |max, n| max.max(n.len()))
-        .max(FLAC_MAGIC_NUMBER.len())
-        .max(OGG_MAGIC_NUMBER.len())
-        .max(WAV_MAGIC_NUMBER_OFFSET + WAV_MAGIC_NUMBER.len());
-    let mut magic_number = vec![0; magic_numer_len];
+        .any(|f| !matches!(f, 'F' | 'D' | 'E' | 'e'));
+    let (lilac, format) = match Format::from_path(filename) {
+        Some(format) if !needs_file => (None, format),
+        _ => {
+            let (lilac, format) = format::read(filename)?;
+            (Some(lilac), format)
+        }
+    };
+    let target = target(opt, format);
+    self::pattern(filename, &pattern, lilac.as_ref(), format, target, opt)
+}
 
-    reader.read_exact(&mut magic_number)?;
-    reader.seek(SeekFrom::Start(0))?;
+/// Output path made from the naming pattern, relative to the input directory
+///
+/// Fields taken from the audio are missing when it wasn't read.
+fn pattern(
+    filename: &Path,
+    pattern: &Pattern,
+    lilac: Option<&Lilac>,
+    format: Format,
+    target: Target,
+    opt: &Opt,
+) -> anyhow::Result<PathBuf> {
+    let stem = filename.file_stem().context("Invalid filename")?;
+    let dir = resolve(filename)?
+        .parent()
+        .and_then(Path::file_name)
+        .map(|d| d.to_string_lossy().into_owned());
+    let sanitize = opt.sanitize.unwrap_or_default();
+    let replacement = opt.replacement.unwrap_or('_');
+    let tag = |t: &Option<String>| t.as_deref().map(|t| sanitize.apply(t, replacement));
 
-    let result = if MP3_MAGIC_NUMBERS
-        .iter()
-        .any(|n| &magic_number[..n.len()] == *n)
-    {
-        (Lilac::from_mp3(reader)?, Format::Mp3)
-    } else if FLAC_MAGIC_NUMBER == &magic_number[..FLAC_MAGIC_NUMBER.len()] {
-        (Lilac::from_flac(reader)?, Format::Flac)
-    } else if OGG_MAGIC_NUMBER == &magic_number[..OGG_MAGIC_NUMBER.len()] {
-        (Lilac::from_ogg(reader)?, Format::Ogg)
-    } else if WAV_MAGIC_NUMBER == &magic_number[WAV_MAGIC_NUMBER_OFFSET..WAV_MAGIC_NUMBER.len()] {
-        (Lilac::from_wav(reader)?, Format::Wav)
-    } else {
-        (Lilac::read(reader)?, Format::Lilac)
-    };
-    Ok(result)
+    let output = pattern.render(|field| match (field, lilac) {
+        ('F', _) => Some(stem.to_string_lossy().into_owned()),
+        ('D', _) => dir.clone(),
+        ('E', _) => Some(target.to_string()),
+        ('e', _) => Some(format.extension().to_owned()),
+        ('T', Some(l)) => tag(&l.title),
+        ('A', Some(l)) => tag(&l.artist),
+        ('B', Some(l)) => tag(&l.album_artist),
+        ('a', Some(l)) => tag(&l.album),
+        ('g', Some(l)) => tag(&l.genre),
+        ('y', Some(l)) => l.year.map(|y| y.to_string()),
+        ('n', Some(l)) => l.track.map(|n| format!("{:02}", n)),
+        ('d', Some(l)) => l.disc.map(|d| d.to_string()),
+        ('r', Some(l)) => Some(l.sample_rate.to_string()),
+        ('b', Some(l)) => Some(opt.bit_depth.unwrap_or(l.bit_depth).to_string()),
+        _ => None,
+    });
+    if output.is_empty() {
+        bail!("The pattern gives an empty path");
+    }
+    Ok(filename
+        .parent()
+        .map(|p| p.join(&output))
+        .unwrap_or_else(|| PathBuf::from(output)))
+}
+
+/// Absolute path of a file that may not exist yet, nor its directories
+///
+/// The deepest existing ancestor is resolved and the rest is appended as is,
+/// so two spellings of the same output compare equal.
+fn resolve(path: &Path) -> anyhow::Result<PathBuf> {
+    let path = std::env::current_dir()?.join(path);
+    let mut existing = path.as_path();
+    let mut rest = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(dir) => return Ok(rest.iter().rev().fold(dir, |p, c| p.join(c))),
+            Err(_) => {
+                rest.push(existing.file_name().context("Invalid filename")?);
+                existing = existing.parent().context("Invalid filename")?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("lilac-transcode-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    fn touch(dir: &Path, files: &[&str]) {
+        for f in files {
+            let path = dir.join(f);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    }
+
+    fn opt(input: &Path, output: &Path) -> Opt {
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+        Opt::from_iter_safe(&["transcode", "--input-dir", input, "--output-dir", output]).unwrap()
+    }
+
+    fn sorted(paths: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = paths.into_iter().collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn plans_mirror() {
+        let root = dir("plan");
+        let (input, output) = (root.join("in"), root.join("out"));
+        touch(
+            &input,
+            &[
+                "a.lilac",
+                "sub/b.flac",
+                "sub/cover.jpg",
+                ".a.lilac.peaks",
+                "sub/.b.lilac.1-0.tmp",
+                ".hidden/c.flac",
+            ],
+        );
+        touch(&output, &["sub/b.lilac"]);
+
+        let plan = mirror(&input, &output, &opt(&input, &output)).unwrap();
+        let jobs: Vec<_> = plan
+            .jobs
+            .iter()
+            .map(|j| (j.input.clone(), j.output.clone()))
+            .collect();
+        assert_eq!(
+            sorted(jobs.iter().map(|(i, _)| i.clone())),
+            [input.join("a.lilac"), input.join("sub/cover.jpg")]
+        );
+        for (i, o) in &jobs {
+            let expected = match i.extension().unwrap().to_str() {
+                Some("lilac") => output.join("a.wav"),
+                _ => output.join("sub/cover.jpg"),
+            };
+            assert_eq!(o, &expected);
+        }
+        assert_eq!(plan.skipped, [input.join("sub/b.flac")]);
+        assert_eq!(
+            sorted(plan.outputs.keys().cloned()),
+            [
+                output.join("a.wav"),
+                output.join("sub/b.lilac"),
+                output.join("sub/cover.jpg")
+            ]
+        );
+        assert!(plan.failed.is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn skips_output_inside_input() {
+        let input = dir("inside");
+        let output = input.join("out");
+        touch(&input, &["a.flac", "out/old.lilac"]);
+
+        let plan = mirror(&input, &output, &opt(&input, &output)).unwrap();
+        assert_eq!(plan.jobs.len(), 1);
+        assert_eq!(plan.jobs[0].input, input.join("a.flac"));
+        fs::remove_dir_all(&input).unwrap();
+    }
+
+    #[test]
+    fn rejects_output_containing_input() {
+        let root = dir("ancestor");
+        let input = root.join("music");
+        touch(&input, &["a.flac"]);
+
+        for output in &[input.clone(), root.clone(), input.join(".")] {
+            let error = mirror(&input, output, &opt(&input, output)).err().unwrap();
+            assert!(
+                error.to_string().contains("can't be or contain"),
+                "{}",
+                error
+            );
+        }
+        assert!(input.join("a.flac").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn deletes_orphans() {
+        let root = dir("orphans");
+        let (input, output) = (root.join("in"), root.join("out"));
+        touch(&input, &["a.lilac", "sub/b.flac"]);
+        touch(
+            &output,
+            &[
+                "a.wav",
+                "old.wav",
+                ".a.wav.peaks",
+                ".a.wav.1-0.tmp",
+                "gone/x.wav",
+                "sub/b.lilac",
+            ],
+        );
+
+        let plan = mirror(&input, &output, &opt(&input, &output)).unwrap();
+        assert!(delete_orphans(&output, &plan).is_empty());
+        let remaining = sorted(
+            WalkDir::new(&output)
+                .min_depth(1)
+                .into_iter()
+                .map(|e| e.unwrap().into_path()),
+        );
+        assert_eq!(
+            remaining,
+            [
+                output.join(".a.wav.1-0.tmp"),
+                output.join(".a.wav.peaks"),
+                output.join("a.wav"),
+                output.join("sub"),
+                output.join("sub/b.lilac"),
+            ]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn keeps_outputs_of_unreadable_inputs() {
+        let root = dir("kept");
+        let output = root.join("out");
+        touch(&output, &["sub/b.lilac", "c.wav"]);
+
+        let plan = Plan {
+            kept: vec![output.join("sub")],
+            ..Plan::default()
+        };
+        assert!(delete_orphans(&output, &plan).is_empty());
+        assert!(output.join("sub/b.lilac").exists());
+        assert!(!output.join("c.wav").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn refuses_colliding_outputs() {
+        let root = dir("collide");
+        let (input, output) = (root.join("in"), root.join("out"));
+        touch(&input, &["a.flac", "a.wav", "b.flac"]);
+
+        let mut plan = mirror(&input, &output, &opt(&input, &output)).unwrap();
+        plan.refuse_collisions();
+        let jobs: Vec<_> = plan.jobs.iter().map(|j| j.input.clone()).collect();
+        assert_eq!(jobs, [input.join("b.flac")]);
+        assert_eq!(
+            sorted(plan.failed.iter().map(|o| o.input.clone())),
+            [input.join("a.flac"), input.join("a.wav")]
+        );
+        for outcome in &plan.failed {
+            let error = outcome.result.as_ref().err().unwrap().to_string();
+            assert!(error.contains("all transcode to"), "{}", error);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn plans_glob_outputs() {
+        let root = dir("glob");
+        touch(&root, &["x/a.flac", "y/a.flac", "y/b.lilac"]);
+
+        let glob = format!("{}/*/*", root.display());
+        let opt = Opt::from_iter_safe(&["transcode", &glob, "../%F.%E"]).unwrap();
+        let mut plan = find(&opt).unwrap();
+        plan.refuse_collisions();
+        assert_eq!(plan.jobs.len(), 1);
+        assert_eq!(plan.jobs[0].input, root.join("y/b.lilac"));
+        assert_eq!(resolve(&plan.jobs[0].output).unwrap(), root.join("b.wav"));
+        assert_eq!(
+            sorted(plan.failed.iter().map(|o| o.input.clone())),
+            [root.join("x/a.flac"), root.join("y/a.flac")]
+        );
+        assert!(!root.join("a.lilac").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,122 @@
+use crate::format;
+use anyhow::bail;
+use lilac::{Concealment, Encoding, Lilac, Verification};
+use rayon::prelude::*;
+use std::{
+    fs::File,
+    io::BufReader,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Opt {
+    /// Glob matching the files to check
+    #[structopt(name = "GLOB")]
+    glob: String,
+    /// Only print files that aren't valid
+    #[structopt(short, long)]
+    quiet: bool,
+    /// Rewrite binary LILAC files with damaged audio blocks concealed
+    ///
+    /// Repaired files read without errors but keep their original checksum,
+    /// so they are still reported as corrupt afterwards.
+    #[structopt(short, long)]
+    repair: bool,
+    /// Replacement for damaged audio blocks
+    ///
+    /// One of silence or interpolate
+    #[structopt(long, name = "CONCEALMENT", default_value = "silence")]
+    conceal: Concealment,
+}
+
+enum Status {
+    Valid,
+    Missing,
+    Corrupt(String),
+    Repaired(String),
+}
+
+pub fn main(opt: Opt) -> crate::Result {
+    let files = glob::glob(&opt.glob)?.collect::<Result<Vec<PathBuf>, _>>()?;
+    let results: Vec<Status> = files.par_iter().map(|f| check(f, &opt)).collect();
+
+    let mut corrupt = 0;
+    for (file, status) in files.iter().zip(results) {
+        match status {
+            Status::Valid if !opt.quiet => println!("OK           {}", file.display()),
+            Status::Missing => println!("NO CHECKSUM  {}", file.display()),
+            Status::Corrupt(reason) => {
+                println!("CORRUPT      {}: {}", file.display(), reason);
+                corrupt += 1;
+            }
+            Status::Repaired(reason) => println!("REPAIRED     {}: {}", file.display(), reason),
+            _ => (),
+        }
+    }
+
+    if corrupt > 0 {
+        bail!("{} of {} files are corrupt", corrupt, files.len());
+    }
+    crate::OK
+}
+
+/// Checks a file against its stored checksum
+///
+/// FLAC signatures are checked when importing, other
+/// formats than LILAC and FLAC never have a checksum.
+/// Binary LILAC files are read leniently to report every damaged range.
+fn check(file: &Path, opt: &Opt) -> Status {
+    let binary = File::open(file)
+        .map(BufReader::new)
+        .map_err(Into::into)
+        .and_then(|mut r| Encoding::detect(&mut r))
+        .is_ok_and(|e| e == Encoding::Binary);
+    if !binary {
+        return match format::read(file) {
+            Ok((lilac, _)) => verify(&lilac),
+            Err(e) => Status::Corrupt(format!("unreadable, {}", e)),
+        };
+    }
+
+    let (lilac, report) = match Lilac::read_file_lenient(file, opt.conceal) {
+        Ok(r) => r,
+        Err(e) => return Status::Corrupt(format!("unreadable, {}", e)),
+    };
+    if report.damaged.is_empty() {
+        return verify(&lilac);
+    }
+
+    let ranges: Vec<String> = report.damaged.iter().map(range).collect();
+    let reason = format!("damaged at {}", ranges.join(", "));
+    if !opt.repair {
+        return Status::Corrupt(reason);
+    }
+    match format::write_atomic(&lilac, file) {
+        Ok(()) => Status::Repaired(reason),
+        Err(e) => Status::Corrupt(format!("{}, {:#}", reason, e)),
+    }
+}
+
+fn verify(lilac: &Lilac) -> Status {
+    match lilac.verify() {
+        Verification::Valid => Status::Valid,
+        Verification::Missing => Status::Missing,
+        Verification::Corrupt => Status::Corrupt("checksum mismatch".to_owned()),
+    }
+}
+
+fn range(range: &Range<Duration>) -> String {
+    let time = |d: Duration| {
+        let millis = d.as_millis();
+        format!(
+            "{}:{:02}.{:03}",
+            millis / 60_000,
+            millis / 1000 % 60,
+            millis % 1000
+        )
+    };
+    format!("{}-{}", time(range.start), time(range.end))
+}
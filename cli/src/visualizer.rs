@@ -0,0 +1,199 @@
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{f32::consts::PI, sync::Arc};
+
+/// Frames analyzed for every spectrum
+pub const FFT_LEN: usize = 2048;
+/// Log-frequency bands computed for every spectrum,
+/// merged to fit the available width when drawing
+pub const BANDS: usize = 128;
+
+const MIN_FREQUENCY: f32 = 20.0;
+const MAX_FREQUENCY: f32 = 20000.0;
+/// Level shown as an empty bar, in dBFS
+const FLOOR: f32 = -72.0;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Mode {
+    Off,
+    Spectrum,
+    Oscilloscope,
+}
+
+impl Mode {
+    pub fn next(self) -> Self {
+        match self {
+            Mode::Off => Mode::Spectrum,
+            Mode::Spectrum => Mode::Oscilloscope,
+            Mode::Oscilloscope => Mode::Off,
+        }
+    }
+}
+
+pub struct Analyzer {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+}
+
+impl Analyzer {
+    pub fn new() -> Self {
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(FFT_LEN),
+            window: (0..FFT_LEN)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_LEN as f32).cos())
+                .collect(),
+        }
+    }
+
+    /// Levels of log-spaced bands between 0 and 100
+    ///
+    /// Channels are mixed down and only the last `FFT_LEN` frames are used.
+    pub fn spectrum(&self, samples: &[f32], channels: u16, sample_rate: u32) -> Vec<u64> {
+        let channels = channels.max(1) as usize;
+        let frames = samples.len() / channels;
+        let skip = frames.saturating_sub(FFT_LEN);
+
+        let mut buffer: Vec<Complex<f32>> = samples
+            .chunks_exact(channels)
+            .skip(skip)
+            .map(|f| f.iter().sum::<f32>() / channels as f32)
+            .chain(std::iter::repeat(0.0))
+            .zip(&self.window)
+            .map(|(s, w)| Complex::new(s * w, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+
+        let scale = 2.0 / self.window.iter().sum::<f32>();
+        let bin_width = sample_rate as f32 / FFT_LEN as f32;
+        let max = MAX_FREQUENCY.min(sample_rate as f32 / 2.0);
+        let ratio = (max / MIN_FREQUENCY).powf(1.0 / BANDS as f32);
+
+        (0..BANDS)
+            .map(|b| {
+                let lo = MIN_FREQUENCY * ratio.powi(b as i32);
+                let hi = lo * ratio;
+                let first = (lo / bin_width) as usize;
+                let last = ((hi / bin_width) as usize).max(first).min(FFT_LEN / 2);
+
+                let magnitude = buffer[first..=last]
+                    .iter()
+                    .map(|c| c.norm() * scale)
+                    .fold(0.0, f32::max);
+                let db = 20.0 * magnitude.max(1e-9).log10();
+                ((db - FLOOR) / -FLOOR * 100.0).clamp(0.0, 100.0) as u64
+            })
+            .collect()
+    }
+}
+
+/// Points of every channel, with time on the x axis in `[0, 1]`
+pub fn oscilloscope(samples: &[f32], channels: u16, points: usize) -> Vec<Vec<(f64, f64)>> {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let points = points.min(frames).max(1);
+    let skip = frames - points.min(frames);
+
+    (0..channels)
+        .map(|c| {
+            samples
+                .chunks_exact(channels)
+                .skip(skip)
+                .enumerate()
+                .map(|(i, f)| (i as f64 / points as f64, f[c] as f64))
+                .collect()
+        })
+        .collect()
+}
+
+/// Merges bands to fit the given number of bars, keeping the loudest
+pub fn fit(bands: &[u64], bars: usize) -> Vec<u64> {
+    let bars = bars.max(1).min(bands.len().max(1));
+    (0..bars)
+        .map(|i| {
+            let start = i * bands.len() / bars;
+            let end = ((i + 1) * bands.len() / bars).max(start + 1);
+            bands[start..end.min(bands.len())]
+                .iter()
+                .copied()
+                .max()
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let s = (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin();
+                vec![s, s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sine_peaks_in_its_band() {
+        let analyzer = Analyzer::new();
+        for &(frequency, sample_rate) in &[(1000.0, 48000), (220.0, 44100), (8000.0, 48000)] {
+            let bands =
+                analyzer.spectrum(&sine(frequency, sample_rate, 3 * FFT_LEN), 2, sample_rate);
+            assert_eq!(bands.len(), BANDS);
+
+            let ratio = (MAX_FREQUENCY / MIN_FREQUENCY).powf(1.0 / BANDS as f32);
+            let expected = ((frequency / MIN_FREQUENCY).ln() / ratio.ln()) as usize;
+            let loudest = (0..BANDS).max_by_key(|&b| bands[b]).unwrap();
+            assert!(
+                (loudest as isize - expected as isize).abs() <= 1,
+                "{} Hz peaks in band {} instead of {}",
+                frequency,
+                loudest,
+                expected
+            );
+            assert!(bands[loudest] >= 95, "{:?}", bands);
+            assert!(bands[0] < 50 && bands[BANDS - 1] < 50, "{:?}", bands);
+        }
+    }
+
+    #[test]
+    fn spectrum_of_short_input() {
+        let analyzer = Analyzer::new();
+        assert_eq!(analyzer.spectrum(&[], 2, 48000), vec![0; BANDS]);
+        assert_eq!(analyzer.spectrum(&[0.0; 101], 2, 48000), vec![0; BANDS]);
+        assert!(analyzer
+            .spectrum(&sine(1000.0, 48000, 100), 2, 48000)
+            .iter()
+            .any(|&b| b > 0));
+    }
+
+    #[test]
+    fn fits_bands() {
+        assert_eq!(fit(&[1, 5, 2, 7, 3], 2), [5, 7]);
+        assert_eq!(fit(&[1, 5, 2, 7, 3], 5), [1, 5, 2, 7, 3]);
+        assert_eq!(fit(&[1, 5, 2], 10), [1, 5, 2]);
+        assert_eq!(fit(&[1, 5, 2], 0), [5]);
+        assert_eq!(fit(&[], 4), [0]);
+    }
+
+    #[test]
+    fn oscilloscope_points() {
+        assert_eq!(oscilloscope(&[], 2, 10), vec![Vec::new(), Vec::new()]);
+        assert_eq!(
+            oscilloscope(&[0.1, 0.2, 0.3], 2, 10),
+            [[(0.0, 0.1f32 as f64)], [(0.0, 0.2f32 as f64)]]
+        );
+        assert_eq!(
+            oscilloscope(&[0.1, 0.2, 0.3, 0.4, 0.5], 2, 4)[1],
+            [(0.0, 0.2f32 as f64), (0.5, 0.4f32 as f64)]
+        );
+        assert_eq!(
+            oscilloscope(&[0.1, 0.2, 0.3, 0.4, 0.5], 2, 1),
+            [[(0.0, 0.3f32 as f64)], [(0.0, 0.4f32 as f64)]]
+        );
+        assert_eq!(
+            oscilloscope(&[0.1, 0.2, 0.3], 0, 2),
+            [[(0.0, 0.2f32 as f64), (0.5, 0.3f32 as f64)]]
+        );
+    }
+}
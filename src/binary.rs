@@ -0,0 +1,620 @@
+use crate::{Error, Lilac, ReplayGain};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{BufRead, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    str::FromStr,
+    time::Duration,
+};
+
+/// Start of binary files, the last byte being the version
+const MAGIC: &[u8] = b"LILAC\x01";
+/// Start of every audio block, used to find the next block after damage
+const SYNC: &[u8] = b"LBLK";
+/// Frames in every audio block but the last
+const BLOCK_FRAMES: u32 = 4096;
+/// Spaces after the JSON header, leaving room to edit tags in place
+const HEADER_PADDING: usize = 256;
+
+/// How a LILAC file is stored
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Single JSON document
+    #[default]
+    Json,
+    /// JSON header followed by packed audio blocks, each with its own CRC
+    Binary,
+}
+
+/// Replacement for damaged audio blocks when reading leniently
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Concealment {
+    Silence,
+    /// Linear interpolation between the frames around the damage
+    Interpolate,
+}
+
+/// Damage found while reading leniently
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Report {
+    /// Time ranges replaced by concealment
+    pub damaged: Vec<Range<Duration>>,
+}
+
+/// Binary header, the metadata of the JSON encoding without the samples
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Header {
+    title: Option<String>,
+    artist: Option<String>,
+    year: Option<i32>,
+    album: Option<String>,
+    track: Option<u32>,
+    disc: Option<u32>,
+    album_artist: Option<String>,
+    genre: Option<String>,
+    #[serde(default, skip_serializing_if = "ReplayGain::is_empty")]
+    replay_gain: ReplayGain,
+
+    channels: u16,
+    sample_rate: u32,
+    bit_depth: u32,
+
+    frames: u64,
+    block_frames: u32,
+    md5: Option<String>,
+}
+
+impl Encoding {
+    /// Encoding of a file from its first bytes, which aren't consumed
+    pub fn detect<R: BufRead>(reader: &mut R) -> Result<Self, Error> {
+        if reader.fill_buf()?.starts_with(MAGIC) {
+            Ok(Encoding::Binary)
+        } else {
+            Ok(Encoding::Json)
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Json => "json",
+            Encoding::Binary => "binary",
+        })
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "json" => Ok(Encoding::Json),
+            "binary" => Ok(Encoding::Binary),
+            _ => Err(format!("unknown encoding `{}`", s)),
+        }
+    }
+}
+
+impl FromStr for Concealment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "silence" => Ok(Concealment::Silence),
+            "interpolate" => Ok(Concealment::Interpolate),
+            _ => Err(format!("unknown concealment `{}`", s)),
+        }
+    }
+}
+
+impl Header {
+    fn new(lilac: &Lilac, block_frames: u32, md5: Option<String>) -> Self {
+        Self {
+            title: lilac.title.clone(),
+            artist: lilac.artist.clone(),
+            year: lilac.year,
+            album: lilac.album.clone(),
+            track: lilac.track,
+            disc: lilac.disc,
+            album_artist: lilac.album_artist.clone(),
+            genre: lilac.genre.clone(),
+            replay_gain: lilac.replay_gain,
+            channels: lilac.channels,
+            sample_rate: lilac.sample_rate,
+            bit_depth: lilac.bit_depth,
+            frames: lilac.frames() as u64,
+            block_frames,
+            md5,
+        }
+    }
+}
+
+/// Writes the binary encoding
+///
+/// The layout is the magic number, the header length, the JSON header padded
+/// with spaces and its CRC, followed by blocks made of the sync marker,
+/// the block index, the frame count, the packed samples and the CRC
+/// of everything after the marker.
+/// Samples are little endian using as many bytes as the bit depth requires.
+pub(crate) fn write<W: Write>(lilac: &Lilac, mut writer: W) -> Result<(), Error> {
+    let channels = lilac.channels.max(1) as usize;
+    let bytes = sample_bytes(lilac.bit_depth);
+
+    let mut header = serde_json::to_vec(&Header::new(lilac, BLOCK_FRAMES, Some(lilac.checksum())))?;
+    header.resize(header.len() + HEADER_PADDING, b' ');
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(&header)?;
+    writer.write_all(&crc32fast::hash(&header).to_le_bytes())?;
+
+    let mut block = Vec::new();
+    for (index, samples) in lilac
+        .samples
+        .chunks(BLOCK_FRAMES as usize * channels)
+        .enumerate()
+    {
+        block.clear();
+        block.extend_from_slice(&(index as u32).to_le_bytes());
+        block.extend_from_slice(&((samples.len() / channels) as u32).to_le_bytes());
+        for s in samples {
+            block.extend_from_slice(&s.to_le_bytes()[..bytes]);
+        }
+        writer.write_all(SYNC)?;
+        writer.write_all(&block)?;
+        writer.write_all(&crc32fast::hash(&block).to_le_bytes())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Rewrites the header of a binary file with the tags of the given stream
+///
+/// Returns false without writing anything when the file isn't in the binary encoding,
+/// holds a different stream, or the new header doesn't fit in the space of the old one.
+/// The stored checksum is kept as is.
+pub(crate) fn write_header<F: Read + Write + Seek>(
+    lilac: &Lilac,
+    mut file: F,
+) -> Result<bool, Error> {
+    let mut start = [0; MAGIC.len() + 4];
+    file.seek(SeekFrom::Start(0))?;
+    if file.read_exact(&mut start).is_err() || !start.starts_with(MAGIC) {
+        return Ok(false);
+    }
+    let len = u32_at(&start, MAGIC.len()).ok_or(Error::Header)? as usize;
+    let mut old = Vec::new();
+    (&mut file).take(len as u64 + 4).read_to_end(&mut old)?;
+    if old.len() != len + 4 || u32_at(&old, len) != Some(crc32fast::hash(&old[..len])) {
+        return Err(Error::Header);
+    }
+    let old: Header = serde_json::from_slice(&old[..len])?;
+    let same = old.channels == lilac.channels
+        && old.sample_rate == lilac.sample_rate
+        && old.bit_depth == lilac.bit_depth
+        && old.frames == lilac.frames() as u64;
+    if !same {
+        return Ok(false);
+    }
+
+    let mut header = serde_json::to_vec(&Header::new(lilac, old.block_frames, old.md5))?;
+    if header.len() > len {
+        return Ok(false);
+    }
+    header.resize(len, b' ');
+    file.seek(SeekFrom::Start(start.len() as u64))?;
+    file.write_all(&header)?;
+    file.write_all(&crc32fast::hash(&header).to_le_bytes())?;
+    file.flush()?;
+    Ok(true)
+}
+
+/// Reads the binary encoding
+///
+/// Without concealment any damaged block is an error. With it, damaged blocks
+/// are skipped by searching for the next sync marker and replaced afterwards.
+pub(crate) fn read<R: Read>(
+    mut reader: R,
+    concealment: Option<Concealment>,
+) -> Result<(Lilac, Report), Error> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    if !data.starts_with(MAGIC) {
+        return Err(Error::Header);
+    }
+
+    let mut pos = MAGIC.len();
+    let len = u32_at(&data, pos).ok_or(Error::Header)? as usize;
+    let header = data.get(pos + 4..pos + 4 + len).ok_or(Error::Header)?;
+    pos += 4 + len;
+    if u32_at(&data, pos) != Some(crc32fast::hash(header)) {
+        return Err(Error::Header);
+    }
+    pos += 4;
+    let header: Header = serde_json::from_slice(header)?;
+    if header.bit_depth == 0 || header.bit_depth > 32 {
+        return Err(Error::BitDepth(header.bit_depth));
+    }
+    if header.channels == 0 {
+        return Err(Error::Channels(header.channels));
+    }
+    if header.sample_rate == 0 {
+        return Err(Error::SampleRate(header.sample_rate));
+    }
+    if header.block_frames == 0 {
+        return Err(Error::Header);
+    }
+
+    let layout = Layout {
+        channels: header.channels as usize,
+        bytes: sample_bytes(header.bit_depth),
+        frames: header.frames as usize,
+        block_frames: header.block_frames as usize,
+    };
+    // The header is untrusted, so the allocation is bounded by the data actually there.
+    // Lenient reads accept up to half of the audio missing, like a truncated file.
+    let size = layout
+        .frames
+        .checked_mul(layout.channels)
+        .and_then(|n| n.checked_mul(layout.bytes))
+        .ok_or(Error::Header)?;
+    let available = data.len() - pos;
+    let limit = match concealment {
+        None => available,
+        Some(_) => available.saturating_mul(2),
+    };
+    if size > limit {
+        return Err(Error::Header);
+    }
+    let blocks = layout.frames.div_ceil(layout.block_frames);
+    let mut samples = vec![0; layout.frames * layout.channels];
+    let mut filled = vec![false; blocks];
+
+    while pos < data.len() {
+        match layout.block_at(&data, pos, blocks) {
+            Some((index, payload, end)) => {
+                let start = index * layout.block_frames * layout.channels;
+                for (s, b) in samples[start..]
+                    .iter_mut()
+                    .zip(payload.chunks_exact(layout.bytes))
+                {
+                    *s = layout.decode(b);
+                }
+                filled[index] = true;
+                pos = end;
+            }
+            None if concealment.is_none() => {
+                let first = filled.iter().position(|f| !f).unwrap_or(blocks);
+                return Err(Error::Damaged(first * layout.block_frames));
+            }
+            None => {
+                pos = data[pos + 1..]
+                    .windows(SYNC.len())
+                    .position(|w| w == SYNC)
+                    .map_or(data.len(), |i| pos + 1 + i);
+            }
+        }
+    }
+
+    // Runs of missing blocks as frame ranges
+    let mut damaged: Vec<Range<usize>> = Vec::new();
+    for (index, _) in filled.iter().enumerate().filter(|(_, f)| !**f) {
+        let range =
+            index * layout.block_frames..((index + 1) * layout.block_frames).min(layout.frames);
+        match damaged.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => damaged.push(range),
+        }
+    }
+    match concealment {
+        None if !damaged.is_empty() => return Err(Error::Damaged(damaged[0].start)),
+        Some(Concealment::Interpolate) => {
+            for range in &damaged {
+                layout.interpolate(&mut samples, range.clone());
+            }
+        }
+        _ => (),
+    }
+
+    let time =
+        |frame: usize| Duration::from_secs_f64(frame as f64 / header.sample_rate.max(1) as f64);
+    let report = Report {
+        damaged: damaged.iter().map(|r| time(r.start)..time(r.end)).collect(),
+    };
+    let lilac = Lilac {
+        title: header.title,
+        artist: header.artist,
+        year: header.year,
+        album: header.album,
+        track: header.track,
+        disc: header.disc,
+        album_artist: header.album_artist,
+        genre: header.genre,
+        replay_gain: header.replay_gain,
+        channels: header.channels,
+        sample_rate: header.sample_rate,
+        bit_depth: header.bit_depth,
+        samples,
+        md5: header.md5,
+        encoding: Encoding::Binary,
+    };
+    Ok((lilac, report))
+}
+
+struct Layout {
+    channels: usize,
+    bytes: usize,
+    frames: usize,
+    block_frames: usize,
+}
+
+impl Layout {
+    /// Index, payload and end of the block starting at the given position, if it's intact
+    fn block_at<'a>(
+        &self,
+        data: &'a [u8],
+        pos: usize,
+        blocks: usize,
+    ) -> Option<(usize, &'a [u8], usize)> {
+        if data.get(pos..pos + SYNC.len())? != SYNC {
+            return None;
+        }
+        let start = pos + SYNC.len();
+        let index = u32_at(data, start)? as usize;
+        let frames = u32_at(data, start + 4)? as usize;
+        if index >= blocks
+            || frames != (self.frames - index * self.block_frames).min(self.block_frames)
+        {
+            return None;
+        }
+
+        let end = start + 8 + frames * self.channels * self.bytes;
+        let block = data.get(start..end)?;
+        if u32_at(data, end)? != crc32fast::hash(block) {
+            return None;
+        }
+        Some((index, &block[8..], end + 4))
+    }
+
+    /// Sign extended sample from its packed bytes
+    fn decode(&self, bytes: &[u8]) -> i32 {
+        let mut padded = [0; 4];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        let shift = 32 - 8 * bytes.len() as u32;
+        i32::from_le_bytes(padded).wrapping_shl(shift) >> shift
+    }
+
+    /// Replaces a frame range with a line between the frames around it
+    fn interpolate(&self, samples: &mut [i32], range: Range<usize>) {
+        let len = range.end - range.start + 1;
+        for c in 0..self.channels {
+            let before = range
+                .start
+                .checked_sub(1)
+                .map(|f| samples[f * self.channels + c]);
+            let after = (range.end < self.frames).then(|| samples[range.end * self.channels + c]);
+            let (a, b) = match (before, after) {
+                (Some(a), Some(b)) => (a, b),
+                (Some(a), None) => (a, a),
+                (None, Some(b)) => (b, b),
+                (None, None) => (0, 0),
+            };
+            for (i, f) in range.clone().enumerate() {
+                let t = (i + 1) as f64 / len as f64;
+                samples[f * self.channels + c] =
+                    (a as f64 + (b as f64 - a as f64) * t).round() as i32;
+            }
+        }
+    }
+}
+
+fn sample_bytes(bit_depth: u32) -> usize {
+    bit_depth.div_ceil(8).clamp(1, 4) as usize
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::lilac, Verification};
+
+    /// Stereo stream spanning a few blocks, with a short last one
+    fn stream(bit_depth: u32) -> Lilac {
+        let max = (1i64 << (bit_depth - 1)) - 1;
+        let frames = BLOCK_FRAMES as i64 * 3 + 100;
+        let samples = (0..frames * 2)
+            .map(|i| ((i * 7919) % (2 * max + 1) - max) as i32)
+            .collect();
+        let mut l = lilac(2, 8000, bit_depth, samples);
+        l.set_encoding(Encoding::Binary);
+        l
+    }
+
+    fn encode(l: &Lilac) -> Vec<u8> {
+        let mut data = Vec::new();
+        write(l, &mut data).unwrap();
+        data
+    }
+
+    /// Position of the n-th block sync marker
+    fn block(data: &[u8], n: usize) -> usize {
+        data.windows(SYNC.len())
+            .enumerate()
+            .filter(|(_, w)| *w == SYNC)
+            .nth(n)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn round_trip() {
+        for &bit_depth in &[8, 16, 24, 32] {
+            let l = stream(bit_depth);
+            let (read, report) = read(&encode(&l)[..], None).unwrap();
+            assert_eq!(read.samples, l.samples);
+            assert_eq!(read.encoding(), Encoding::Binary);
+            assert_eq!(read.verify(), Verification::Valid);
+            assert!(report.damaged.is_empty());
+        }
+    }
+
+    #[test]
+    fn detects_encoding() {
+        let data = encode(&stream(16));
+        assert_eq!(Encoding::detect(&mut &data[..]).unwrap(), Encoding::Binary);
+        assert_eq!(Encoding::detect(&mut &b"{}"[..]).unwrap(), Encoding::Json);
+        assert_eq!(
+            Lilac::read(&data[..]).unwrap().frames(),
+            stream(16).frames()
+        );
+    }
+
+    #[test]
+    fn damaged_block() {
+        let l = stream(16);
+        let mut data = encode(&l);
+        let at = block(&data, 1) + 20;
+        data[at] ^= 0xff;
+
+        match read(&data[..], None) {
+            Err(Error::Damaged(frame)) => assert_eq!(frame, BLOCK_FRAMES as usize),
+            r => panic!("expected a damaged block, got {:?}", r.map(|_| ())),
+        }
+
+        let (silenced, report) = read(&data[..], Some(Concealment::Silence)).unwrap();
+        let start = BLOCK_FRAMES as usize * 2;
+        let end = start * 2;
+        assert!(silenced.samples[start..end].iter().all(|&s| s == 0));
+        assert_eq!(silenced.samples[..start], l.samples[..start]);
+        assert_eq!(silenced.samples[end..], l.samples[end..]);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].start, Duration::from_millis(512));
+        assert_eq!(report.damaged[0].end, Duration::from_millis(1024));
+        assert_eq!(silenced.verify(), Verification::Corrupt);
+
+        let (interpolated, _) = read(&data[..], Some(Concealment::Interpolate)).unwrap();
+        let (before, after) = (l.samples[start - 2], l.samples[end]);
+        let middle = interpolated.samples[start + BLOCK_FRAMES as usize];
+        assert!(middle >= before.min(after) && middle <= before.max(after));
+    }
+
+    #[test]
+    fn rewrite_keeps_concealed_files_corrupt() {
+        let mut data = encode(&stream(16));
+        let at = block(&data, 2) + 20;
+        data[at] ^= 0xff;
+
+        let (concealed, _) = read(&data[..], Some(Concealment::Silence)).unwrap();
+        let (rewritten, report) = read(&encode(&concealed)[..], None).unwrap();
+        assert!(report.damaged.is_empty());
+        assert_eq!(rewritten.verify(), Verification::Corrupt);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = encode(&stream(16));
+        let cut = &data[..block(&data, 3)];
+        assert!(read(cut, None).is_err());
+
+        let (l, report) = read(cut, Some(Concealment::Silence)).unwrap();
+        assert_eq!(l.frames(), stream(16).frames());
+        assert_eq!(report.damaged.len(), 1);
+    }
+
+    #[test]
+    fn damaged_header() {
+        let mut data = encode(&stream(16));
+        data[MAGIC.len() + 6] ^= 0xff;
+        assert!(matches!(read(&data[..], None), Err(Error::Header)));
+        assert!(matches!(
+            read(&data[..], Some(Concealment::Silence)),
+            Err(Error::Header)
+        ));
+        assert!(matches!(read(&b"LILAC"[..], None), Err(Error::Header)));
+    }
+
+    #[test]
+    fn header_in_place() {
+        let l = stream(16);
+        let data = encode(&l);
+        let mut file = std::io::Cursor::new(data.clone());
+
+        let mut tagged = read(&data[..], None).unwrap().0;
+        tagged.title = Some("Title".to_owned());
+        tagged.genre = Some("Genre".to_owned());
+        assert!(write_header(&tagged, &mut file).unwrap());
+        let file = file.into_inner();
+        assert_eq!(file.len(), data.len());
+        assert_eq!(file[block(&file, 0)..], data[block(&data, 0)..]);
+
+        let (read, _) = read(&file[..], None).unwrap();
+        assert_eq!(read.title.as_deref(), Some("Title"));
+        assert_eq!(read.genre.as_deref(), Some("Genre"));
+        assert_eq!(read.verify(), Verification::Valid);
+    }
+
+    #[test]
+    fn header_not_in_place() {
+        let l = stream(16);
+        let data = encode(&l);
+
+        let mut long = l.clone();
+        long.title = Some("x".repeat(HEADER_PADDING * 2));
+        let mut file = std::io::Cursor::new(data.clone());
+        assert!(!write_header(&long, &mut file).unwrap());
+        assert_eq!(file.into_inner(), data);
+
+        let mut other = l.clone();
+        other.resample(4000).unwrap();
+        let mut file = std::io::Cursor::new(data.clone());
+        assert!(!write_header(&other, &mut file).unwrap());
+        assert_eq!(file.into_inner(), data);
+
+        let mut json = Vec::new();
+        lilac(1, 8000, 16, vec![0]).write(&mut json).unwrap();
+        let mut file = std::io::Cursor::new(json.clone());
+        assert!(!write_header(&l, &mut file).unwrap());
+        assert_eq!(file.into_inner(), json);
+    }
+
+    #[test]
+    fn oversized_header() {
+        for &frames in &[u64::MAX / 2, 1 << 32] {
+            let header = serde_json::to_vec(&Header {
+                title: None,
+                artist: None,
+                year: None,
+                album: None,
+                track: None,
+                disc: None,
+                album_artist: None,
+                genre: None,
+                replay_gain: ReplayGain::default(),
+                channels: 2,
+                sample_rate: 8000,
+                bit_depth: 16,
+                frames,
+                block_frames: BLOCK_FRAMES,
+                md5: None,
+            })
+            .unwrap();
+            let mut data = MAGIC.to_vec();
+            data.extend_from_slice(&(header.len() as u32).to_le_bytes());
+            data.extend_from_slice(&header);
+            data.extend_from_slice(&crc32fast::hash(&header).to_le_bytes());
+
+            assert!(matches!(read(&data[..], None), Err(Error::Header)));
+            assert!(matches!(
+                read(&data[..], Some(Concealment::Interpolate)),
+                Err(Error::Header)
+            ));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use crate::Lilac;
+use serde::Serialize;
+
+/// Result of checking the samples against the stored checksum
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Verification {
+    Valid,
+    Corrupt,
+    /// The file was written without a checksum
+    Missing,
+}
+
+/// Serialized form, with the stored checksum or one computed when writing
+#[derive(Serialize)]
+pub(crate) struct Checksummed<'a> {
+    #[serde(flatten)]
+    lilac: &'a Lilac,
+    md5: String,
+}
+
+impl Lilac {
+    /// MD5 digest of the samples, as a lowercase hexadecimal string
+    ///
+    /// Samples are hashed interleaved and little endian, using as many bytes
+    /// as the bit depth requires, which is the same digest FLAC stores.
+    pub fn md5(&self) -> String {
+        format!("{:x}", md5(&self.samples, self.bit_depth))
+    }
+
+    /// Checks the samples against the checksum read with the file
+    ///
+    /// Any edit of the samples discards the checksum, which is computed again
+    /// when writing. Otherwise the stored checksum is written back as is,
+    /// so that rewriting a corrupt file doesn't make it pass verification.
+    pub fn verify(&self) -> Verification {
+        match &self.md5 {
+            Some(md5) if md5.eq_ignore_ascii_case(&self.md5()) => Verification::Valid,
+            Some(_) => Verification::Corrupt,
+            None => Verification::Missing,
+        }
+    }
+
+    /// Checksum to write, the stored one unless the samples were edited
+    pub(crate) fn checksum(&self) -> String {
+        self.md5.clone().unwrap_or_else(|| self.md5())
+    }
+
+    pub(crate) fn checksummed(&self) -> Checksummed<'_> {
+        Checksummed {
+            lilac: self,
+            md5: self.checksum(),
+        }
+    }
+}
+
+pub(crate) fn md5(samples: &[i32], bit_depth: u32) -> md5::Digest {
+    let bytes = bit_depth.div_ceil(8).clamp(1, 4) as usize;
+    let mut context = md5::Context::new();
+    let mut buffer = Vec::with_capacity(4096 * bytes);
+    for chunk in samples.chunks(4096) {
+        buffer.clear();
+        for s in chunk {
+            buffer.extend_from_slice(&s.to_le_bytes()[..bytes]);
+        }
+        context.consume(&buffer);
+    }
+    context.compute()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lilac;
+
+    #[test]
+    fn md5_packs_samples_by_bit_depth() {
+        // 16 bits samples are hashed as two little endian bytes
+        let l = lilac(1, 8000, 16, vec![1, -1]);
+        assert_eq!(
+            l.md5(),
+            format!("{:x}", md5::compute([0x01, 0x00, 0xff, 0xff]))
+        );
+        let l = lilac(1, 8000, 24, vec![1]);
+        assert_eq!(l.md5(), format!("{:x}", md5::compute([0x01, 0x00, 0x00])));
+    }
+
+    #[test]
+    fn verify_round_trip() {
+        let l = lilac(2, 8000, 16, vec![1, 2, 3, 4]);
+        assert_eq!(l.verify(), Verification::Missing);
+
+        let mut json = Vec::new();
+        l.write(&mut json).unwrap();
+        let read = Lilac::read(&json[..]).unwrap();
+        assert_eq!(read.verify(), Verification::Valid);
+    }
+
+    #[test]
+    fn rewrite_keeps_corrupt_checksum() {
+        let mut json = Vec::new();
+        lilac(1, 8000, 16, vec![1, 2, 3, 4])
+            .write(&mut json)
+            .unwrap();
+        let mut value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        value["samples"][2] = 5.into();
+        let tampered = serde_json::to_vec(&value).unwrap();
+
+        let read = Lilac::read(&tampered[..]).unwrap();
+        assert_eq!(read.verify(), Verification::Corrupt);
+
+        let mut rewritten = Vec::new();
+        read.write(&mut rewritten).unwrap();
+        let read = Lilac::read(&rewritten[..]).unwrap();
+        assert_eq!(read.verify(), Verification::Corrupt);
+    }
+
+    #[test]
+    fn edits_discard_checksum() {
+        let mut json = Vec::new();
+        lilac(1, 8000, 16, vec![1, 2, 3, 4])
+            .write(&mut json)
+            .unwrap();
+        let mut read = Lilac::read(&json[..]).unwrap();
+        read.convert_bit_depth(16, crate::Dither::None, crate::NoiseShaping::None)
+            .unwrap();
+        assert_eq!(read.verify(), Verification::Valid);
+
+        read.convert_bit_depth(24, crate::Dither::None, crate::NoiseShaping::None)
+            .unwrap();
+        assert_eq!(read.verify(), Verification::Missing);
+    }
+}
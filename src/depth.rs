@@ -0,0 +1,262 @@
+use crate::{Error, Lilac};
+use std::{fmt, str::FromStr};
+
+/// Dither noise added before requantization
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Dither {
+    /// Plain rounding
+    None,
+    /// Rectangular probability density, ±0.5 LSB
+    Rpdf,
+    /// Triangular probability density, ±1 LSB
+    Tpdf,
+}
+
+/// Error feedback filter shaping the requantization noise
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum NoiseShaping {
+    None,
+    /// First order highpass, `1 - z^-1`
+    FirstOrder,
+    /// Lipshitz's 5 tap E-weighted filter, designed for 44.1 kHz
+    Lipshitz,
+}
+
+static FIRST_ORDER: &[f64] = &[1.0];
+static LIPSHITZ: &[f64] = &[2.033, -2.165, 1.959, -1.590, 0.6149];
+
+impl NoiseShaping {
+    fn coefficients(self) -> &'static [f64] {
+        match self {
+            NoiseShaping::None => &[],
+            NoiseShaping::FirstOrder => FIRST_ORDER,
+            NoiseShaping::Lipshitz => LIPSHITZ,
+        }
+    }
+}
+
+impl Lilac {
+    /// Converts the samples to another bit depth
+    ///
+    /// Increasing the bit depth is lossless and ignores the dither and noise shaping.
+    /// Decreasing it requantizes every sample with the given dither
+    /// and noise shaping filter, clipping to the new range.
+    pub fn convert_bit_depth(
+        &mut self,
+        bit_depth: u32,
+        dither: Dither,
+        noise_shaping: NoiseShaping,
+    ) -> Result<(), Error> {
+        if bit_depth == 0 || bit_depth > 32 {
+            return Err(Error::BitDepth(bit_depth));
+        }
+        if bit_depth == self.bit_depth {
+            return Ok(());
+        }
+
+        if bit_depth > self.bit_depth {
+            let shift = bit_depth - self.bit_depth;
+            for s in &mut self.samples {
+                *s = ((*s as i64) << shift) as i32;
+            }
+            self.bit_depth = bit_depth;
+            self.md5 = None;
+            return Ok(());
+        }
+
+        let step = (1u64 << (self.bit_depth - bit_depth)) as f64;
+        let min = -((1i64 << (bit_depth - 1)) as f64);
+        let max = ((1i64 << (bit_depth - 1)) - 1) as f64;
+
+        let coefficients = noise_shaping.coefficients();
+        let channels = self.channels.max(1) as usize;
+        let mut errors = vec![vec![0.0; coefficients.len()]; channels];
+        let mut rng = XorShift::new();
+
+        for (i, s) in self.samples.iter_mut().enumerate() {
+            let errors = &mut errors[i % channels];
+
+            let wanted = *s as f64 / step
+                - coefficients
+                    .iter()
+                    .zip(errors.iter())
+                    .map(|(c, e)| c * e)
+                    .sum::<f64>();
+            let noise = match dither {
+                Dither::None => 0.0,
+                Dither::Rpdf => rng.uniform(),
+                Dither::Tpdf => rng.uniform() + rng.uniform(),
+            };
+            let quantized = (wanted + noise).round();
+
+            if !errors.is_empty() {
+                errors.rotate_right(1);
+                errors[0] = quantized - wanted;
+            }
+            *s = quantized.max(min).min(max) as i32;
+        }
+
+        self.bit_depth = bit_depth;
+        self.md5 = None;
+        Ok(())
+    }
+}
+
+/// Small and fast PRNG, plenty for dither noise
+struct XorShift(u64);
+
+impl XorShift {
+    fn new() -> Self {
+        Self(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform value in `[-0.5, 0.5)`
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+    }
+}
+
+impl fmt::Display for Dither {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Dither::None => "none",
+            Dither::Rpdf => "rpdf",
+            Dither::Tpdf => "tpdf",
+        })
+    }
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "none" | "off" => Ok(Dither::None),
+            "rpdf" => Ok(Dither::Rpdf),
+            "tpdf" => Ok(Dither::Tpdf),
+            _ => Err(format!("unknown dither `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for NoiseShaping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NoiseShaping::None => "none",
+            NoiseShaping::FirstOrder => "first-order",
+            NoiseShaping::Lipshitz => "lipshitz",
+        })
+    }
+}
+
+impl FromStr for NoiseShaping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "none" | "off" => Ok(NoiseShaping::None),
+            "first-order" => Ok(NoiseShaping::FirstOrder),
+            "lipshitz" => Ok(NoiseShaping::Lipshitz),
+            _ => Err(format!("unknown noise shaping filter `{}`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lilac;
+
+    fn mean(samples: &[i32]) -> f64 {
+        samples.iter().map(|&s| s as f64).sum::<f64>() / samples.len() as f64
+    }
+
+    #[test]
+    fn increase_is_lossless() {
+        let samples = vec![-32768, -1, 0, 1, 32767];
+        let mut l = lilac(1, 8000, 16, samples.clone());
+        l.convert_bit_depth(24, Dither::Tpdf, NoiseShaping::Lipshitz)
+            .unwrap();
+        assert_eq!(l.samples, vec![-8388608, -256, 0, 256, 8388352]);
+
+        l.convert_bit_depth(16, Dither::None, NoiseShaping::None)
+            .unwrap();
+        assert_eq!(l.bit_depth, 16);
+        assert_eq!(l.samples, samples);
+    }
+
+    #[test]
+    fn decrease_rounds_and_clips() {
+        let mut l = lilac(1, 8000, 24, vec![256, 383, 384, -384, 8388607, -8388608]);
+        l.convert_bit_depth(16, Dither::None, NoiseShaping::None)
+            .unwrap();
+        assert_eq!(l.samples, vec![1, 1, 2, -2, 32767, -32768]);
+    }
+
+    #[test]
+    fn invalid_bit_depth() {
+        let mut l = lilac(1, 8000, 16, vec![0]);
+        assert!(matches!(
+            l.convert_bit_depth(0, Dither::None, NoiseShaping::None),
+            Err(Error::BitDepth(0))
+        ));
+        assert!(matches!(
+            l.convert_bit_depth(33, Dither::None, NoiseShaping::None),
+            Err(Error::BitDepth(33))
+        ));
+    }
+
+    #[test]
+    fn dither_keeps_low_level_detail() {
+        // A quarter of a 16 bits step disappears without dither
+        let quiet = vec![64; 20_000];
+        let mut plain = lilac(1, 8000, 24, quiet.clone());
+        plain
+            .convert_bit_depth(16, Dither::None, NoiseShaping::None)
+            .unwrap();
+        assert!(plain.samples.iter().all(|&s| s == 0));
+
+        for &dither in &[Dither::Rpdf, Dither::Tpdf] {
+            let mut l = lilac(1, 8000, 24, quiet.clone());
+            l.convert_bit_depth(16, dither, NoiseShaping::None).unwrap();
+            assert!((mean(&l.samples) - 0.25).abs() < 0.05);
+            assert!(l.samples.iter().all(|s| (-1..=2).contains(s)));
+        }
+    }
+
+    #[test]
+    fn noise_shaping_keeps_the_average() {
+        let mut l = lilac(2, 44100, 24, [64, -192].repeat(10_000));
+        l.convert_bit_depth(16, Dither::None, NoiseShaping::FirstOrder)
+            .unwrap();
+        let left: Vec<i32> = l.samples.iter().step_by(2).copied().collect();
+        let right: Vec<i32> = l.samples.iter().skip(1).step_by(2).copied().collect();
+        // First order error feedback cancels the error over time, per channel
+        assert!((mean(&left) - 0.25).abs() < 0.001);
+        assert!((mean(&right) + 0.75).abs() < 0.001);
+
+        let mut l = lilac(1, 44100, 24, vec![64; 20_000]);
+        l.convert_bit_depth(16, Dither::Tpdf, NoiseShaping::Lipshitz)
+            .unwrap();
+        assert!((mean(&l.samples) - 0.25).abs() < 0.05);
+        assert!(l.samples.iter().all(|s| s.abs() < 16));
+    }
+
+    #[test]
+    fn parse_names() {
+        for &d in &[Dither::None, Dither::Rpdf, Dither::Tpdf] {
+            assert_eq!(d.to_string().parse::<Dither>(), Ok(d));
+        }
+        for &n in &[
+            NoiseShaping::None,
+            NoiseShaping::FirstOrder,
+            NoiseShaping::Lipshitz,
+        ] {
+            assert_eq!(n.to_string().parse::<NoiseShaping>(), Ok(n));
+        }
+        assert!("triangular".parse::<Dither>().is_err());
+    }
+}
@@ -0,0 +1,455 @@
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Samples between checks for chain updates
+const UPDATE_PERIOD: usize = 512;
+
+/// Biquad filter response, following the RBJ audio EQ cookbook
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FilterKind {
+    LowShelf,
+    HighShelf,
+    Peaking,
+    LowPass,
+    HighPass,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Filter {
+    pub kind: FilterKind,
+    /// Center or corner frequency in Hz
+    pub frequency: f32,
+    /// Gain in dB, ignored by low and high pass filters
+    pub gain: f32,
+    pub q: f32,
+}
+
+/// Peak limiter with instant attack
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Limiter {
+    /// Ceiling in dBFS
+    pub threshold: f32,
+    /// Time to recover from gain reduction, in milliseconds
+    pub release: f32,
+}
+
+/// Processing applied in order: preamp, filters then limiter
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chain {
+    /// Preamp in dB
+    pub preamp: f32,
+    pub filters: Vec<Filter>,
+    pub limiter: Option<Limiter>,
+}
+
+/// Shared handle to a chain, which can be updated during playback
+#[derive(Debug, Clone, Default)]
+pub struct DspHandle {
+    chain: Arc<Mutex<Chain>>,
+    generation: Arc<AtomicUsize>,
+}
+
+/// Source adapter applying a DSP chain
+pub struct Dsp<S: Source<Item = f32>> {
+    source: S,
+    handle: DspHandle,
+    generation: usize,
+
+    channel: usize,
+    countdown: usize,
+
+    preamp: f32,
+    filters: Vec<Biquad>,
+    limiter: Option<LimiterState>,
+}
+
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    /// Transposed direct form II state for every channel
+    state: Vec<[f32; 2]>,
+}
+
+struct LimiterState {
+    threshold: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, frequency: f32, gain: f32, q: f32) -> Self {
+        Self {
+            kind,
+            frequency,
+            gain,
+            q,
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(threshold: f32, release: f32) -> Self {
+        Self { threshold, release }
+    }
+}
+
+impl DspHandle {
+    pub fn new(chain: Chain) -> Self {
+        Self {
+            chain: Arc::new(Mutex::new(chain)),
+            generation: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn chain(&self) -> Chain {
+        self.chain.lock().unwrap().clone()
+    }
+    /// Replaces the chain, picked up by sources within a few milliseconds
+    pub fn set_chain(&self, chain: Chain) {
+        *self.chain.lock().unwrap() = chain;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl<S: Source<Item = f32>> Dsp<S> {
+    pub fn new(source: S, handle: DspHandle) -> Self {
+        let mut dsp = Self {
+            source,
+            handle,
+            generation: 0,
+
+            channel: 0,
+            countdown: 0,
+
+            preamp: 1.0,
+            filters: Vec::new(),
+            limiter: None,
+        };
+        dsp.update();
+        dsp
+    }
+
+    fn update(&mut self) {
+        self.generation = self.handle.generation.load(Ordering::SeqCst);
+        let chain = self.handle.chain();
+
+        let channels = self.source.channels().max(1) as usize;
+        let sample_rate = self.source.sample_rate() as f32;
+
+        self.preamp = 10f32.powf(chain.preamp / 20.0);
+        self.filters = chain
+            .filters
+            .iter()
+            .map(|f| Biquad::new(f, sample_rate, channels))
+            .collect();
+        self.limiter = chain.limiter.map(|l| LimiterState {
+            threshold: 10f32.powf(l.threshold / 20.0),
+            release: 1.0 - (-1000.0 / (l.release * sample_rate).max(1000.0)).exp(),
+            gain: 1.0,
+        });
+    }
+}
+
+impl Biquad {
+    fn new(f: &Filter, sample_rate: f32, channels: usize) -> Self {
+        let a = 10f32.powf(f.gain / 40.0);
+        let w0 = 2.0 * PI * f.frequency.min(sample_rate / 2.0 - 1.0).max(1.0) / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * f.q.max(0.01));
+
+        let (b0, b1, b2, a0, a1, a2) = match f.kind {
+            FilterKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterKind::LowShelf => {
+                let sq = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos + sq),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - sq),
+                    (a + 1.0) + (a - 1.0) * cos + sq,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - sq,
+                )
+            }
+            FilterKind::HighShelf => {
+                let sq = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos + sq),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - sq),
+                    (a + 1.0) - (a - 1.0) * cos + sq,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - sq,
+                )
+            }
+            FilterKind::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            state: vec![[0.0; 2]; channels],
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, channel: usize, x: f32) -> f32 {
+        let s = &mut self.state[channel];
+        let y = self.b0 * x + s[0];
+        s[0] = self.b1 * x - self.a1 * y + s[1];
+        s[1] = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+impl LimiterState {
+    #[inline]
+    fn process(&mut self, x: f32) -> f32 {
+        let peak = x.abs();
+        let target = if peak > self.threshold {
+            self.threshold / peak
+        } else {
+            1.0
+        };
+
+        if target < self.gain {
+            self.gain = target;
+        } else {
+            self.gain += (target - self.gain) * self.release;
+        }
+        x * self.gain
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Dsp<S> {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let channels = self.source.channels().max(1) as usize;
+        if self.countdown == 0 {
+            self.countdown = UPDATE_PERIOD;
+            if self.channel == 0
+                && self.generation != self.handle.generation.load(Ordering::Relaxed)
+            {
+                self.update();
+            }
+        }
+        self.countdown -= 1;
+
+        let mut s = self.source.next()? * self.preamp;
+        let channel = self.channel.min(channels - 1);
+        for f in &mut self.filters {
+            s = f.process(channel, s);
+        }
+        if let Some(l) = &mut self.limiter {
+            s = l.process(s);
+        }
+
+        self.channel = (self.channel + 1) % channels;
+        Some(s)
+    }
+}
+impl<S: Source<Item = f32>> Source for Dsp<S> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const RATE: u32 = 48000;
+
+    fn run(chain: Chain, channels: u16, samples: Vec<f32>) -> Vec<f32> {
+        let source = SamplesBuffer::new(channels, RATE, samples);
+        Dsp::new(source, DspHandle::new(chain)).collect()
+    }
+
+    /// Peak amplitude once the filters have settled
+    fn response(filter: Filter, input: impl Fn(usize) -> f32) -> f32 {
+        let chain = Chain {
+            filters: vec![filter],
+            ..Chain::default()
+        };
+        let output = run(chain, 1, (0..RATE as usize).map(input).collect());
+        output[output.len() / 2..]
+            .iter()
+            .fold(0.0, |m, s| m.max(s.abs()))
+    }
+
+    fn dc(_: usize) -> f32 {
+        1.0
+    }
+    fn nyquist(i: usize) -> f32 {
+        1.0 - 2.0 * (i % 2) as f32
+    }
+    fn sine(frequency: f32) -> impl Fn(usize) -> f32 {
+        move |i| (2.0 * PI * frequency * i as f32 / RATE as f32).sin()
+    }
+
+    fn db(gain: f32) -> f32 {
+        20.0 * gain.log10()
+    }
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() < tolerance
+    }
+
+    #[test]
+    fn shelves_gain() {
+        let low = Filter::new(FilterKind::LowShelf, 200.0, 6.0, 0.707);
+        assert!(close(db(response(low, dc)), 6.0, 0.1));
+        assert!(close(db(response(low, nyquist)), 0.0, 0.1));
+
+        let high = Filter::new(FilterKind::HighShelf, 4000.0, -6.0, 0.707);
+        assert!(close(db(response(high, dc)), 0.0, 0.1));
+        assert!(close(db(response(high, nyquist)), -6.0, 0.1));
+    }
+
+    #[test]
+    fn passes_attenuate() {
+        let low = Filter::new(FilterKind::LowPass, 1000.0, 0.0, 0.707);
+        assert!(close(response(low, dc), 1.0, 0.01));
+        assert!(db(response(low, sine(10000.0))) < -35.0);
+
+        let high = Filter::new(FilterKind::HighPass, 1000.0, 0.0, 0.707);
+        assert!(response(high, dc) < 0.001);
+        assert!(db(response(high, sine(100.0))) < -35.0);
+        assert!(close(db(response(high, sine(10000.0))), 0.0, 0.1));
+    }
+
+    #[test]
+    fn peaks_at_centre() {
+        let peaking = Filter::new(FilterKind::Peaking, 1000.0, 9.0, 1.0);
+        assert!(close(db(response(peaking, sine(1000.0))), 9.0, 0.1));
+        assert!(close(db(response(peaking, dc)), 0.0, 0.1));
+        assert!(db(response(peaking, sine(5000.0))) < 3.0);
+    }
+
+    #[test]
+    fn keeps_channels_apart() {
+        let chain = Chain {
+            filters: vec![Filter::new(FilterKind::LowShelf, 200.0, 6.0, 0.707)],
+            ..Chain::default()
+        };
+        let samples = (0..2000).map(|i| (1 - i % 2) as f32).collect();
+        let output = run(chain, 2, samples);
+        assert!(output.iter().skip(1).step_by(2).all(|&s| s == 0.0));
+        assert!(close(db(output[1998]), 6.0, 0.1));
+    }
+
+    #[test]
+    fn applies_preamp() {
+        let chain = Chain {
+            preamp: -6.0,
+            ..Chain::default()
+        };
+        let input = [0.8, -0.4, 0.0, 1.0];
+        let output = run(chain, 2, input.to_vec());
+        for (o, i) in output.iter().zip(&input) {
+            assert!(close(*o, i * 0.501_187, 1e-5));
+        }
+    }
+
+    #[test]
+    fn limits_to_ceiling() {
+        let chain = Chain {
+            preamp: 12.0,
+            limiter: Some(Limiter::new(-6.0, 10.0)),
+            ..Chain::default()
+        };
+        let ceiling = 10f32.powf(-6.0 / 20.0);
+        let output = run(chain, 1, (0..4800).map(sine(440.0)).collect());
+        assert!(output.iter().all(|s| s.abs() <= ceiling + 1e-6));
+        assert!(output.iter().any(|s| close(s.abs(), ceiling, 1e-3)));
+    }
+
+    #[test]
+    fn releases_after_peaks() {
+        let chain = Chain {
+            limiter: Some(Limiter::new(-6.0, 10.0)),
+            ..Chain::default()
+        };
+        let mut samples = vec![1.0; 480];
+        samples.extend(vec![0.1; 4800]);
+        let output = run(chain, 1, samples);
+
+        // 10ms at 48kHz are 480 samples
+        assert!(output[481] < 0.06);
+        assert!(output[480 + 480] > 0.08 && output[480 + 480] < 0.09);
+        assert!(close(output[480 + 5 * 480], 0.1, 0.001));
+    }
+
+    #[test]
+    fn picks_up_chain_updates() {
+        let handle = DspHandle::new(Chain::default());
+        let source = SamplesBuffer::new(1, RATE, vec![0.5; 4 * UPDATE_PERIOD]);
+        let mut dsp = Dsp::new(source, handle.clone());
+        assert_eq!(dsp.next(), Some(0.5));
+
+        handle.set_chain(Chain {
+            preamp: -6.0,
+            ..Chain::default()
+        });
+        assert_eq!(handle.chain().preamp, -6.0);
+        let output: Vec<f32> = dsp.collect();
+        assert!(output[..UPDATE_PERIOD - 1].iter().all(|&s| s == 0.5));
+        assert!(output[UPDATE_PERIOD..]
+            .iter()
+            .all(|&s| close(s, 0.5 * 0.501_187, 1e-5)));
+    }
+}
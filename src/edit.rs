@@ -0,0 +1,266 @@
+use crate::{Dither, Error, Lilac, NoiseShaping};
+use std::{ops::Range, time::Duration};
+
+impl Lilac {
+    /// Number of frames, a frame being one sample for every channel
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+    pub fn duration(&self) -> Duration {
+        if self.sample_rate == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(self.frames() as f64 / self.sample_rate as f64)
+    }
+
+    /// Frame index closest to the given time
+    pub fn frame_at(&self, time: Duration) -> usize {
+        (time.as_secs_f64() * self.sample_rate as f64).round() as usize
+    }
+
+    /// Copy of the given frame range with the same metadata
+    ///
+    /// Track ReplayGain values are cleared since they no longer apply.
+    pub fn slice_frames(&self, range: Range<usize>) -> Result<Self, Error> {
+        if range.start > range.end || range.end > self.frames() {
+            return Err(Error::Range(range));
+        }
+
+        let channels = self.channels as usize;
+        let mut lilac = self.clone_metadata();
+        lilac.samples = self.samples[range.start * channels..range.end * channels].to_vec();
+        Ok(lilac)
+    }
+    /// Copy of the given time range with the same metadata
+    ///
+    /// The end is clamped to the duration.
+    pub fn slice(&self, start: Duration, end: Duration) -> Result<Self, Error> {
+        let frames = self.frames();
+        self.slice_frames(self.frame_at(start).min(frames)..self.frame_at(end).min(frames))
+    }
+
+    /// Appends another stream with the same format
+    pub fn append(&mut self, other: &Lilac) -> Result<(), Error> {
+        if self.channels != other.channels
+            || self.sample_rate != other.sample_rate
+            || self.bit_depth != other.bit_depth
+        {
+            return Err(Error::Incompatible {
+                channels: self.channels,
+                sample_rate: self.sample_rate,
+                bit_depth: self.bit_depth,
+            });
+        }
+
+        self.samples.extend_from_slice(&other.samples);
+        self.md5 = None;
+        self.replay_gain.track_gain = None;
+        self.replay_gain.track_peak = None;
+        Ok(())
+    }
+    /// Appends another stream, converting it to this format first
+    pub fn append_converted(&mut self, other: &Lilac) -> Result<(), Error> {
+        let mut other = other.clone();
+        other.remix(self.channels)?;
+        other.resample(self.sample_rate)?;
+        other.convert_bit_depth(self.bit_depth, Dither::Tpdf, NoiseShaping::None)?;
+        self.append(&other)
+    }
+
+    /// Changes the number of channels
+    ///
+    /// Upmixing repeats the existing channels in order
+    /// and downmixing averages them together.
+    pub fn remix(&mut self, channels: u16) -> Result<(), Error> {
+        if channels == 0 || self.channels == 0 {
+            return Err(Error::Channels(0));
+        }
+        if channels == self.channels {
+            return Ok(());
+        }
+
+        let from = self.channels as usize;
+        let to = channels as usize;
+        let samples = self
+            .samples
+            .chunks_exact(from)
+            .flat_map(|frame| {
+                (0..to).map(move |c| {
+                    if to > from {
+                        frame[c % from]
+                    } else {
+                        let (sum, n) = (c..from)
+                            .step_by(to)
+                            .fold((0, 0), |(sum, n), i| (sum + frame[i] as i64, n + 1));
+                        (sum / n) as i32
+                    }
+                })
+            })
+            .collect();
+
+        self.samples = samples;
+        self.md5 = None;
+        self.channels = channels;
+        Ok(())
+    }
+
+    /// Changes the sample rate using linear interpolation
+    pub fn resample(&mut self, sample_rate: u32) -> Result<(), Error> {
+        if sample_rate == 0 || self.sample_rate == 0 {
+            return Err(Error::SampleRate(0));
+        }
+        if sample_rate == self.sample_rate {
+            return Ok(());
+        }
+
+        let channels = self.channels as usize;
+        let frames = self.frames();
+        let out_frames = (frames as u64 * sample_rate as u64 / self.sample_rate as u64) as usize;
+        let ratio = self.sample_rate as f64 / sample_rate as f64;
+
+        let mut samples = Vec::with_capacity(out_frames * channels);
+        for i in 0..out_frames {
+            let pos = i as f64 * ratio;
+            let idx = pos as usize;
+            let next = (idx + 1).min(frames - 1);
+            let t = pos - idx as f64;
+            for c in 0..channels {
+                let a = self.samples[idx * channels + c] as f64;
+                let b = self.samples[next * channels + c] as f64;
+                samples.push((a + (b - a) * t).round() as i32);
+            }
+        }
+
+        self.samples = samples;
+        self.md5 = None;
+        self.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    fn clone_metadata(&self) -> Self {
+        let mut lilac = Self {
+            title: self.title.clone(),
+            artist: self.artist.clone(),
+            year: self.year,
+            album: self.album.clone(),
+            track: self.track,
+            disc: self.disc,
+            album_artist: self.album_artist.clone(),
+            genre: self.genre.clone(),
+            replay_gain: self.replay_gain,
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bit_depth: self.bit_depth,
+            samples: Vec::new(),
+            md5: None,
+            encoding: self.encoding,
+        };
+        lilac.replay_gain.track_gain = None;
+        lilac.replay_gain.track_peak = None;
+        lilac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lilac;
+
+    fn stereo() -> Lilac {
+        lilac(2, 4, 16, vec![0, 10, 1, 11, 2, 12, 3, 13, 4, 14, 5, 15])
+    }
+
+    #[test]
+    fn slices() {
+        let l = stereo();
+        assert_eq!(l.frames(), 6);
+        assert_eq!(l.duration(), Duration::from_millis(1500));
+        assert_eq!(l.slice_frames(1..3).unwrap().samples, vec![1, 11, 2, 12]);
+        assert_eq!(
+            l.slice(Duration::from_millis(500), Duration::from_secs(60))
+                .unwrap()
+                .samples,
+            vec![2, 12, 3, 13, 4, 14, 5, 15]
+        );
+        assert!(l.slice_frames(0..0).unwrap().samples.is_empty());
+        assert!(matches!(l.slice_frames(2..7), Err(Error::Range(_))));
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = l.slice_frames(3..2);
+        assert!(matches!(reversed, Err(Error::Range(_))));
+    }
+
+    #[test]
+    fn slices_clear_track_gain() {
+        let mut l = stereo();
+        l.replay_gain.track_gain = Some(-3.0);
+        l.replay_gain.album_gain = Some(-4.0);
+        let part = l.slice_frames(0..2).unwrap();
+        assert_eq!(part.replay_gain.track_gain, None);
+        assert_eq!(part.replay_gain.album_gain, Some(-4.0));
+    }
+
+    #[test]
+    fn concatenates() {
+        let mut l = stereo();
+        l.append(&stereo().slice_frames(0..1).unwrap()).unwrap();
+        assert_eq!(l.frames(), 7);
+        assert_eq!(l.samples[12..], [0, 10]);
+
+        let mono = lilac(1, 4, 16, vec![7]);
+        assert!(matches!(
+            l.append(&mono),
+            Err(Error::Incompatible { channels: 2, .. })
+        ));
+        l.append_converted(&mono).unwrap();
+        assert_eq!(l.samples[14..], [7, 7]);
+    }
+
+    #[test]
+    fn append_converted_resamples_and_converts() {
+        let mut l = lilac(1, 8, 24, vec![0]);
+        l.append_converted(&lilac(2, 4, 16, vec![1, 3, 1, 3]))
+            .unwrap();
+        assert_eq!(l.frames(), 5);
+        assert!(l.samples[1..].iter().all(|&s| s == 512));
+    }
+
+    #[test]
+    fn remixes() {
+        let mut l = stereo();
+        l.remix(1).unwrap();
+        assert_eq!(l.samples, vec![5, 6, 7, 8, 9, 10]);
+
+        let mut l = lilac(1, 4, 16, vec![1, 2]);
+        l.remix(3).unwrap();
+        assert_eq!(l.samples, vec![1, 1, 1, 2, 2, 2]);
+
+        // Downmixing 4 channels to 2 averages every other channel
+        let mut l = lilac(4, 4, 16, vec![0, 10, 2, 20]);
+        l.remix(2).unwrap();
+        assert_eq!(l.samples, vec![1, 15]);
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        assert!(matches!(stereo().remix(0), Err(Error::Channels(0))));
+        assert!(matches!(
+            lilac(0, 4, 16, vec![1, 2]).remix(1),
+            Err(Error::Channels(0))
+        ));
+        assert!(matches!(stereo().resample(0), Err(Error::SampleRate(0))));
+        assert!(matches!(
+            lilac(1, 0, 16, vec![1]).resample(4),
+            Err(Error::SampleRate(0))
+        ));
+        assert_eq!(lilac(1, 0, 16, vec![1]).duration(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn resamples() {
+        let mut l = lilac(1, 2, 16, vec![0, 10, 20, 30]);
+        l.resample(4).unwrap();
+        assert_eq!(l.samples, vec![0, 5, 10, 15, 20, 25, 30, 30]);
+        l.resample(2).unwrap();
+        assert_eq!(l.samples, vec![0, 10, 20, 30]);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::Lilac;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+/// ReplayGain loudness information
+///
+/// Gains are expressed in dB and peaks as linear amplitudes
+/// where 1.0 is digital full scale.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+/// Which ReplayGain value to apply during playback
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum GainMode {
+    Off,
+    Track,
+    Album,
+}
+
+impl ReplayGain {
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none()
+            && self.track_peak.is_none()
+            && self.album_gain.is_none()
+            && self.album_peak.is_none()
+    }
+
+    /// Reads a `REPLAYGAIN_*` tag, returning `false` if the key isn't one
+    ///
+    /// Keys are matched case-insensitively and values
+    /// may carry a trailing `dB` unit.
+    pub fn read_tag(&mut self, key: &str, value: &str) -> bool {
+        let field = match key.to_ascii_uppercase().as_ref() {
+            "REPLAYGAIN_TRACK_GAIN" => &mut self.track_gain,
+            "REPLAYGAIN_TRACK_PEAK" => &mut self.track_peak,
+            "REPLAYGAIN_ALBUM_GAIN" => &mut self.album_gain,
+            "REPLAYGAIN_ALBUM_PEAK" => &mut self.album_peak,
+            _ => return false,
+        };
+
+        let value = value.trim();
+        let value = value
+            .strip_suffix("dB")
+            .or_else(|| value.strip_suffix("db"))
+            .unwrap_or(value);
+        if let Ok(v) = value.trim().parse() {
+            *field = Some(v);
+        }
+        true
+    }
+
+    /// Linear amplitude factor for the given mode and preamp in dB
+    ///
+    /// Album mode falls back to track values and vice versa, and untagged
+    /// streams only get the preamp. The factor is lowered when needed so the peak
+    /// never exceeds full scale, using the `measured` peak without a peak value.
+    /// The off mode ignores the preamp too.
+    pub fn factor<F: FnOnce() -> f32>(&self, mode: GainMode, preamp: f32, measured: F) -> f32 {
+        let (gain, peak) = match mode {
+            GainMode::Off => return 1.0,
+            GainMode::Track => (
+                self.track_gain.or(self.album_gain),
+                self.track_peak.or(self.album_peak),
+            ),
+            GainMode::Album => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+        };
+
+        let factor = 10f32.powf((gain.unwrap_or(0.0) + preamp) / 20.0);
+        if factor <= 1.0 {
+            return factor;
+        }
+        match peak.unwrap_or_else(measured) {
+            p if p > 0.0 => factor.min(1.0 / p),
+            _ => factor,
+        }
+    }
+}
+
+impl PartialEq for ReplayGain {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits() == other.bits()
+    }
+}
+impl Eq for ReplayGain {}
+impl Hash for ReplayGain {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
+}
+impl ReplayGain {
+    fn bits(&self) -> [Option<u32>; 4] {
+        [
+            self.track_gain.map(f32::to_bits),
+            self.track_peak.map(f32::to_bits),
+            self.album_gain.map(f32::to_bits),
+            self.album_peak.map(f32::to_bits),
+        ]
+    }
+}
+
+impl GainMode {
+    /// Cycles through off, track and album
+    pub fn next(self) -> Self {
+        match self {
+            GainMode::Off => GainMode::Track,
+            GainMode::Track => GainMode::Album,
+            GainMode::Album => GainMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for GainMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GainMode::Off => "off",
+            GainMode::Track => "track",
+            GainMode::Album => "album",
+        })
+    }
+}
+
+impl FromStr for GainMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "off" | "none" => Ok(GainMode::Off),
+            "track" => Ok(GainMode::Track),
+            "album" => Ok(GainMode::Album),
+            _ => Err(format!("unknown gain mode `{}`", s)),
+        }
+    }
+}
+
+impl Lilac {
+    /// Linear playback volume factor from the ReplayGain tags
+    ///
+    /// Without a peak tag the peak is measured from the samples.
+    pub fn gain(&self, mode: GainMode, preamp: f32) -> f32 {
+        self.replay_gain.factor(mode, preamp, || self.peak())
+    }
+
+    /// Highest absolute sample as a linear amplitude, 1.0 being full scale
+    pub fn peak(&self) -> f32 {
+        let full_scale = 2f32.powi(self.bit_depth as i32 - 1);
+        let peak = self.samples.iter().map(|s| s.unsigned_abs()).max();
+        peak.unwrap_or(0) as f32 / full_scale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lilac;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn reads_tags() {
+        let mut rg = ReplayGain::default();
+        assert!(rg.read_tag("replaygain_track_gain", " -6.5 dB"));
+        assert!(rg.read_tag("REPLAYGAIN_TRACK_PEAK", "0.5"));
+        assert!(rg.read_tag("REPLAYGAIN_ALBUM_GAIN", "nonsense"));
+        assert!(!rg.read_tag("TITLE", "1"));
+        assert_eq!(rg.track_gain, Some(-6.5));
+        assert_eq!(rg.track_peak, Some(0.5));
+        assert_eq!(rg.album_gain, None);
+        assert!(!rg.is_empty());
+    }
+
+    #[test]
+    fn modes_fall_back() {
+        let rg = ReplayGain {
+            track_gain: Some(-6.0),
+            album_gain: Some(-12.0),
+            ..ReplayGain::default()
+        };
+        let never = || panic!("no measurement needed when attenuating");
+        assert!(close(rg.factor(GainMode::Track, 0.0, never), 0.501_187));
+        assert!(close(rg.factor(GainMode::Album, 0.0, never), 0.251_189));
+        assert!(close(rg.factor(GainMode::Off, 6.0, never), 1.0));
+
+        let track_only = ReplayGain {
+            track_gain: Some(-6.0),
+            ..ReplayGain::default()
+        };
+        assert!(close(
+            track_only.factor(GainMode::Album, 0.0, never),
+            0.501_187
+        ));
+    }
+
+    #[test]
+    fn prevents_clipping() {
+        let rg = ReplayGain {
+            track_gain: Some(6.0),
+            track_peak: Some(0.8),
+            ..ReplayGain::default()
+        };
+        assert!(close(rg.factor(GainMode::Track, 0.0, || 1.0), 1.25));
+        assert!(close(rg.factor(GainMode::Track, -12.0, || 1.0), 0.501_187));
+    }
+
+    #[test]
+    fn preamp_without_tags() {
+        let rg = ReplayGain::default();
+        assert!(close(rg.factor(GainMode::Track, -6.0, || 1.0), 0.501_187));
+        // Boosting an untagged stream is limited by its measured peak
+        assert!(close(rg.factor(GainMode::Track, 6.0, || 0.25), 1.995_262));
+        assert!(close(rg.factor(GainMode::Track, 6.0, || 0.8), 1.25));
+    }
+
+    #[test]
+    fn measures_peak() {
+        let l = lilac(2, 8000, 16, vec![0, 16384, -32768, 100]);
+        assert!(close(l.peak(), 1.0));
+        assert!(close(l.gain(GainMode::Track, 6.0), 1.0));
+
+        let l = lilac(1, 8000, 16, vec![0, -8192]);
+        assert!(close(l.peak(), 0.25));
+        assert!(close(l.gain(GainMode::Album, 20.0), 4.0));
+        assert!(close(lilac(1, 8000, 16, vec![]).peak(), 0.0));
+    }
+}
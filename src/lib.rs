@@ -2,18 +2,53 @@ use rodio::Source;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    fs::File,
+    fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Read, Write},
     path::Path,
     time::Duration,
 };
 
+mod binary;
+mod checksum;
+mod depth;
+mod dsp;
+mod edit;
+mod gain;
+mod peaks;
+mod silence;
+
+pub use binary::{Concealment, Encoding, Report};
+pub use checksum::Verification;
+pub use depth::{Dither, NoiseShaping};
+pub use dsp::{Chain, Dsp, DspHandle, Filter, FilterKind, Limiter};
+pub use gain::{GainMode, ReplayGain};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
     #[error("json error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("unsupported bit depth: {0}")]
+    BitDepth(u32),
+    #[error("unsupported channel count: {0}")]
+    Channels(u16),
+    #[error("unsupported sample rate: {0}")]
+    SampleRate(u32),
+    #[error("frame range out of bounds: {0:?}")]
+    Range(std::ops::Range<usize>),
+    #[error("checksum mismatch, the stream is corrupt")]
+    Checksum,
+    #[error("invalid or damaged binary header")]
+    Header,
+    #[error("damaged audio block at frame {0}")]
+    Damaged(usize),
+    #[error("incompatible stream, expected {channels} channels at {sample_rate} Hz and {bit_depth} bits")]
+    Incompatible {
+        channels: u16,
+        sample_rate: u32,
+        bit_depth: u32,
+    },
 
     #[cfg(feature = "mp3")]
     #[error("mp3 error: {0}")]
@@ -43,27 +78,103 @@ pub struct Lilac {
     pub year: Option<i32>,
     pub album: Option<String>,
     pub track: Option<u32>,
+    pub disc: Option<u32>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    #[serde(default, skip_serializing_if = "ReplayGain::is_empty")]
+    pub replay_gain: ReplayGain,
 
     pub channels: u16,
     pub sample_rate: u32,
     pub bit_depth: u32,
 
     samples: Vec<i32>,
+    /// Checksum read with the file, see `verify`
+    #[serde(default, skip_serializing)]
+    md5: Option<String>,
+    /// Encoding read with the file and used when writing
+    #[serde(skip)]
+    encoding: Encoding,
 }
 impl Lilac {
+    /// Reads either encoding, failing on any damage
     pub fn read<R: Read>(reader: R) -> Result<Self, Error> {
-        serde_json::from_reader(reader).map_err(Into::into)
+        let mut reader = BufReader::new(reader);
+        match Encoding::detect(&mut reader)? {
+            Encoding::Json => serde_json::from_reader::<_, Self>(reader)?.validate(),
+            Encoding::Binary => binary::read(reader, None).map(|(lilac, _)| lilac),
+        }
     }
     pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         Self::read(BufReader::new(File::open(path)?))
     }
+    /// Reads either encoding, concealing damaged audio blocks of binary files
+    ///
+    /// The checksum is left as read, so `verify` reports concealed files as corrupt.
+    /// JSON files have no blocks and are read like `read` does.
+    pub fn read_lenient<R: Read>(
+        reader: R,
+        concealment: Concealment,
+    ) -> Result<(Self, Report), Error> {
+        let mut reader = BufReader::new(reader);
+        match Encoding::detect(&mut reader)? {
+            Encoding::Json => Ok((
+                serde_json::from_reader::<_, Self>(reader)?.validate()?,
+                Report::default(),
+            )),
+            Encoding::Binary => binary::read(reader, Some(concealment)),
+        }
+    }
+    pub fn read_file_lenient<P: AsRef<Path>>(
+        path: P,
+        concealment: Concealment,
+    ) -> Result<(Self, Report), Error> {
+        Self::read_lenient(BufReader::new(File::open(path)?), concealment)
+    }
 
+    /// Writes in the encoding the file was read with, JSON by default
     pub fn write<W: Write>(&self, writer: W) -> Result<(), Error> {
-        serde_json::to_writer_pretty(writer, self).map_err(Into::into)
+        match self.encoding {
+            Encoding::Json => {
+                serde_json::to_writer_pretty(writer, &self.checksummed()).map_err(Into::into)
+            }
+            Encoding::Binary => binary::write(self, writer),
+        }
     }
     pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
         self.write(BufWriter::new(File::create(path)?))
     }
+    /// Rewrites only the tags of a binary file, in place
+    ///
+    /// Binary headers are padded so that tag edits usually fit in their space.
+    /// Returns false and leaves the file untouched when it isn't a binary file
+    /// of the same stream or the tags don't fit, in which case it has to be written whole.
+    /// The header is small and written at once, but unlike a whole rewrite
+    /// the write isn't atomic.
+    pub fn write_tags<P: AsRef<Path>>(&self, path: P) -> Result<bool, Error> {
+        binary::write_header(self, OpenOptions::new().read(true).write(true).open(path)?)
+    }
+
+    /// Rejects streams declaring properties that can't be played or edited
+    fn validate(self) -> Result<Self, Error> {
+        if self.channels == 0 {
+            return Err(Error::Channels(self.channels));
+        }
+        if self.sample_rate == 0 {
+            return Err(Error::SampleRate(self.sample_rate));
+        }
+        if self.bit_depth == 0 || self.bit_depth > 32 {
+            return Err(Error::BitDepth(self.bit_depth));
+        }
+        Ok(self)
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
 
     pub fn title(&self) -> &str {
         self.title.as_ref().map(AsRef::as_ref).unwrap_or("Unknown")
@@ -79,21 +190,19 @@ impl Lilac {
         let min = (2u32.pow(self.bit_depth - 1)) as f32;
         let max = (2u32.pow(self.bit_depth - 1) - 1) as f32;
 
-        let samples_len = self.samples.len();
+        let duration = self.duration();
 
         LilacSource {
             channels: self.channels,
             sample_rate: self.sample_rate,
 
             samples: self.samples.into_iter().map(move |s| match s.cmp(&0) {
-                Ordering::Less => (s as f32 / min),
+                Ordering::Less => s as f32 / min,
                 Ordering::Equal => 0.0,
-                Ordering::Greater => (s as f32 / max),
+                Ordering::Greater => s as f32 / max,
             }),
 
-            duration: Duration::from_millis(
-                samples_len as u64 / self.channels as u64 / (self.sample_rate / 1000) as u64,
-            ),
+            duration,
         }
     }
 }
@@ -135,7 +244,7 @@ impl<T: Iterator<Item = f32>> Source for LilacSource<T> {
 
 #[cfg(feature = "mp3")]
 mod mp3 {
-    use crate::{Error, Lilac};
+    use crate::{Encoding, Error, Lilac, ReplayGain};
     use id3::{ErrorKind, Tag};
     use minimp3::Decoder;
     use std::{
@@ -146,20 +255,48 @@ mod mp3 {
 
     impl Lilac {
         pub fn from_mp3<R: Read + Seek>(mut reader: R) -> Result<Self, Error> {
-            let (title, artist, year, album, track) = match Tag::read_from(&mut reader) {
-                Ok(tag) => {
-                    let title = tag.title().map(ToOwned::to_owned);
-                    let artist = tag.artist().map(ToOwned::to_owned);
-                    let year = tag.year();
-                    let album = tag.album().map(ToOwned::to_owned);
-                    let track = tag.track();
-                    (title, artist, year, album, track)
-                }
-                Err(e) => match e.kind {
-                    ErrorKind::NoTag => (None, None, None, None, None),
-                    _ => return Err(e.into()),
-                },
-            };
+            let (title, artist, year, album, track, disc, album_artist, genre, replay_gain) =
+                match Tag::read_from(&mut reader) {
+                    Ok(tag) => {
+                        let title = tag.title().map(ToOwned::to_owned);
+                        let artist = tag.artist().map(ToOwned::to_owned);
+                        let year = tag.year();
+                        let album = tag.album().map(ToOwned::to_owned);
+                        let track = tag.track();
+                        let disc = tag.disc();
+                        let album_artist = tag.album_artist().map(ToOwned::to_owned);
+                        let genre = tag.genre().map(ToOwned::to_owned);
+                        let mut replay_gain = ReplayGain::default();
+                        for t in tag.extended_texts() {
+                            replay_gain.read_tag(&t.description, &t.value);
+                        }
+                        (
+                            title,
+                            artist,
+                            year,
+                            album,
+                            track,
+                            disc,
+                            album_artist,
+                            genre,
+                            replay_gain,
+                        )
+                    }
+                    Err(e) => match e.kind {
+                        ErrorKind::NoTag => (
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                            ReplayGain::default(),
+                        ),
+                        _ => return Err(e.into()),
+                    },
+                };
 
             reader.seek(SeekFrom::Start(0))?;
             let mut reader = Decoder::new(reader);
@@ -186,10 +323,16 @@ mod mp3 {
                 year,
                 album,
                 track,
+                disc,
+                album_artist,
+                genre,
+                replay_gain,
                 channels,
                 sample_rate,
                 bit_depth: 16,
                 samples,
+                md5: None,
+                encoding: Encoding::Json,
             })
         }
 
@@ -201,7 +344,7 @@ mod mp3 {
 
 #[cfg(feature = "flac")]
 mod flac {
-    use crate::{Error, Lilac};
+    use crate::{Encoding, Error, Lilac, ReplayGain};
     use claxon::FlacReader;
     use std::{
         fs::File,
@@ -225,12 +368,31 @@ mod flac {
                 }
             };
             let album = reader.get_tag("ALBUM").next().map(ToOwned::to_owned);
-            let track = match reader.get_tag("TRACKNUMBER").next() {
-                Some(tn) => match tn.parse() {
-                    Ok(tn) => Some(tn),
-                    Err(_) => None,
-                },
-                None => None,
+            let track = reader
+                .get_tag("TRACKNUMBER")
+                .next()
+                .and_then(|tn| tn.parse().ok());
+            let disc = reader
+                .get_tag("DISCNUMBER")
+                .next()
+                .and_then(|dn| dn.parse().ok());
+            let album_artist = reader.get_tag("ALBUMARTIST").next().map(ToOwned::to_owned);
+            let genre = reader.get_tag("GENRE").next().map(ToOwned::to_owned);
+            let mut replay_gain = ReplayGain::default();
+            for (k, v) in reader.tags() {
+                replay_gain.read_tag(k, v);
+            }
+
+            let samples: Vec<i32> = reader.samples().collect::<Result<_, _>>()?;
+            // An all zero signature means the encoder didn't compute one
+            let md5 = match info.md5sum {
+                expected if expected == [0; 16] => None,
+                expected => {
+                    if crate::checksum::md5(&samples, info.bits_per_sample).0 != expected {
+                        return Err(Error::Checksum);
+                    }
+                    Some(format!("{:x}", md5::Digest(expected)))
+                }
             };
 
             Ok(Lilac {
@@ -239,12 +401,18 @@ mod flac {
                 year: None,
                 album,
                 track,
+                disc,
+                album_artist,
+                genre,
+                replay_gain,
 
                 channels: info.channels as u16,
                 sample_rate: info.sample_rate,
                 bit_depth: info.bits_per_sample,
 
-                samples: reader.samples().collect::<Result<_, _>>()?,
+                samples,
+                md5,
+                encoding: Encoding::Json,
             })
         }
 
@@ -256,7 +424,7 @@ mod flac {
 
 #[cfg(feature = "ogg")]
 mod ogg {
-    use crate::{Error, Lilac};
+    use crate::{Encoding, Error, Lilac, ReplayGain};
     use lewton::inside_ogg::OggStreamReader;
     use std::{
         fs::File,
@@ -272,6 +440,10 @@ mod ogg {
             let mut artists = Vec::new();
             let mut album = None;
             let mut track = None;
+            let mut disc = None;
+            let mut album_artist = None;
+            let mut genre = None;
+            let mut replay_gain = ReplayGain::default();
             for (k, v) in &reader.comment_hdr.comment_list {
                 let uk = k.to_ascii_uppercase();
                 if uk == "TITLE" && title.is_none() {
@@ -284,6 +456,16 @@ mod ogg {
                     if let Ok(tn) = v.parse() {
                         track = Some(tn);
                     }
+                } else if uk == "DISCNUMBER" && disc.is_none() {
+                    if let Ok(dn) = v.parse() {
+                        disc = Some(dn);
+                    }
+                } else if uk == "ALBUMARTIST" && album_artist.is_none() {
+                    album_artist = Some(v.clone());
+                } else if uk == "GENRE" && genre.is_none() {
+                    genre = Some(v.clone());
+                } else {
+                    replay_gain.read_tag(k, v);
                 }
             }
             let artist = if !artists.is_empty() {
@@ -303,12 +485,18 @@ mod ogg {
                 year: None,
                 album,
                 track,
+                disc,
+                album_artist,
+                genre,
+                replay_gain,
 
                 channels: reader.ident_hdr.audio_channels as u16,
                 sample_rate: reader.ident_hdr.audio_sample_rate,
                 bit_depth: 16,
 
                 samples,
+                md5: None,
+                encoding: Encoding::Json,
             })
         }
 
@@ -320,7 +508,7 @@ mod ogg {
 
 #[cfg(feature = "wav")]
 mod wav {
-    use crate::{Error, Lilac};
+    use crate::{Encoding, Error, Lilac, ReplayGain};
     use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
     use std::{
         fs::File,
@@ -341,10 +529,16 @@ mod wav {
                 year: None,
                 album: None,
                 track: None,
+                disc: None,
+                album_artist: None,
+                genre: None,
+                replay_gain: ReplayGain::default(),
                 channels: spec.channels,
                 sample_rate: spec.sample_rate,
                 bit_depth: spec.bits_per_sample as u32,
                 samples,
+                md5: None,
+                encoding: Encoding::Json,
             })
         }
 
@@ -373,3 +567,67 @@ mod wav {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Untagged stream with the given format and samples
+    pub(crate) fn lilac(
+        channels: u16,
+        sample_rate: u32,
+        bit_depth: u32,
+        samples: Vec<i32>,
+    ) -> Lilac {
+        Lilac {
+            title: None,
+            artist: None,
+            year: None,
+            album: None,
+            track: None,
+            disc: None,
+            album_artist: None,
+            genre: None,
+            replay_gain: ReplayGain::default(),
+            channels,
+            sample_rate,
+            bit_depth,
+            samples,
+            md5: None,
+            encoding: Encoding::Json,
+        }
+    }
+
+    fn json(channels: u16, sample_rate: u32, bit_depth: u32) -> String {
+        format!(
+            r#"{{"title":null,"artist":null,"year":null,"album":null,"track":null,
+            "channels":{},"sampleRate":{},"bitDepth":{},"samples":[0,0]}}"#,
+            channels, sample_rate, bit_depth
+        )
+    }
+
+    #[test]
+    fn reads_json_without_new_fields() {
+        let l = Lilac::read(json(2, 8000, 16).as_bytes()).unwrap();
+        assert_eq!(l.frames(), 1);
+        assert_eq!(l.disc, None);
+        assert_eq!(l.encoding(), Encoding::Json);
+        assert_eq!(l.verify(), Verification::Missing);
+    }
+
+    #[test]
+    fn rejects_invalid_json_streams() {
+        assert!(matches!(
+            Lilac::read(json(0, 8000, 16).as_bytes()),
+            Err(Error::Channels(0))
+        ));
+        assert!(matches!(
+            Lilac::read(json(2, 0, 16).as_bytes()),
+            Err(Error::SampleRate(0))
+        ));
+        assert!(matches!(
+            Lilac::read_lenient(json(2, 8000, 33).as_bytes(), Concealment::Silence),
+            Err(Error::BitDepth(33))
+        ));
+    }
+}
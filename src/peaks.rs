@@ -0,0 +1,62 @@
+use crate::Lilac;
+
+impl Lilac {
+    /// Lowest and highest sample in evenly sized frame buckets, between -1 and 1
+    ///
+    /// Channels are merged and empty buckets are `(0.0, 0.0)`,
+    /// which happens when there are fewer frames than buckets.
+    pub fn peaks(&self, buckets: usize) -> Vec<(f32, f32)> {
+        let channels = self.channels.max(1) as usize;
+        let frames = self.frames();
+        let full_scale = 2f32.powi(self.bit_depth as i32 - 1);
+
+        (0..buckets)
+            .map(|b| {
+                let start = b * frames / buckets;
+                let end = (b + 1) * frames / buckets;
+                let (min, max) = self.samples[start * channels..end * channels]
+                    .iter()
+                    .fold((0, 0), |(min, max), &s| (s.min(min), s.max(max)));
+                (
+                    (min as f32 / full_scale).max(-1.0),
+                    (max as f32 / full_scale).min(1.0),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::lilac;
+
+    #[test]
+    fn buckets_min_max() {
+        let l = lilac(1, 10, 8, vec![-64, 32, 0, 127, -128, 0]);
+        assert_eq!(
+            l.peaks(3),
+            [(-0.5, 0.25), (0.0, 127.0 / 128.0), (-1.0, 0.0)]
+        );
+        assert_eq!(l.peaks(2), [(-0.5, 0.25), (-1.0, 127.0 / 128.0)]);
+        assert_eq!(l.peaks(1), [(-1.0, 127.0 / 128.0)]);
+    }
+
+    #[test]
+    fn merges_channels() {
+        let l = lilac(2, 10, 16, vec![16384, -8192, 0, 0]);
+        assert_eq!(l.peaks(2), [(-0.25, 0.5), (0.0, 0.0)]);
+    }
+
+    #[test]
+    fn leaves_empty_buckets() {
+        let l = lilac(1, 10, 8, vec![64, -64]);
+        assert_eq!(
+            l.peaks(4),
+            [(0.0, 0.0), (0.0, 0.5), (0.0, 0.0), (-0.5, 0.0)]
+        );
+        assert!(lilac(1, 10, 8, Vec::new())
+            .peaks(2)
+            .iter()
+            .all(|&p| p == (0.0, 0.0)));
+    }
+}
@@ -0,0 +1,185 @@
+use crate::Lilac;
+use std::{ops::Range, time::Duration};
+
+/// Length of the analysis windows
+const WINDOW: Duration = Duration::from_millis(10);
+
+impl Lilac {
+    /// Frame ranges quieter than the threshold for at least the minimum duration
+    ///
+    /// The threshold is in dBFS and compared against the RMS level
+    /// of every channel over 10 ms windows.
+    pub fn silences(&self, threshold: f32, min_duration: Duration) -> Vec<Range<usize>> {
+        let min_frames = self.frame_at(min_duration);
+        let mut silences = Vec::new();
+        let mut start = None;
+
+        let windows = self.silent_windows(threshold);
+        let end = self.frames();
+        for (range, silent) in windows.into_iter().chain(Some((end..end, false))) {
+            match (start, silent) {
+                (None, true) => start = Some(range.start),
+                (Some(s), false) => {
+                    if range.start - s >= min_frames.max(1) {
+                        silences.push(s..range.start);
+                    }
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+
+        silences
+    }
+
+    /// Removes leading and trailing silence
+    ///
+    /// The threshold is in dBFS, see `silences`.
+    pub fn trim_silence(&mut self, threshold: f32) {
+        let windows = self.silent_windows(threshold);
+        let start = windows
+            .iter()
+            .find(|(_, silent)| !silent)
+            .map(|(r, _)| r.start);
+        let end = windows
+            .iter()
+            .rev()
+            .find(|(_, silent)| !silent)
+            .map(|(r, _)| r.end);
+
+        let channels = self.channels as usize;
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                self.samples.truncate(end * channels);
+                self.samples.drain(..start * channels);
+            }
+            _ => self.samples.clear(),
+        }
+        self.md5 = None;
+        self.replay_gain.track_gain = None;
+        self.replay_gain.track_peak = None;
+    }
+
+    /// Splits the stream into the sounding parts between silences
+    ///
+    /// Leading and trailing silence is removed from every part, whatever its length.
+    /// Every part keeps the metadata of the whole stream,
+    /// except for track ReplayGain values.
+    pub fn split_on_silence(&self, threshold: f32, min_duration: Duration) -> Vec<Lilac> {
+        let mut cuts = vec![0];
+        for s in self.silences(threshold, min_duration) {
+            cuts.push(s.start);
+            cuts.push(s.end);
+        }
+        cuts.push(self.frames());
+
+        cuts.chunks_exact(2)
+            .filter_map(|c| self.slice_frames(c[0]..c[1]).ok())
+            .map(|mut part| {
+                part.trim_silence(threshold);
+                part
+            })
+            .filter(|part| !part.samples.is_empty())
+            .collect()
+    }
+
+    fn silent_windows(&self, threshold: f32) -> Vec<(Range<usize>, bool)> {
+        let channels = self.channels.max(1) as usize;
+        let window = self.frame_at(WINDOW).max(1);
+        let full_scale = 2f64.powi(self.bit_depth as i32 - 1);
+        let limit = full_scale * 10f64.powf(threshold as f64 / 20.0);
+
+        self.samples
+            .chunks(window * channels)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let start = i * window;
+                let end = start + chunk.len() / channels;
+                let silent = (0..channels).all(|c| {
+                    let (sum, n) = chunk
+                        .iter()
+                        .skip(c)
+                        .step_by(channels)
+                        .fold((0.0, 0), |(sum, n), &s| (sum + (s as f64).powi(2), n + 1));
+                    n == 0 || (sum / n as f64).sqrt() <= limit
+                });
+                (start..end, silent)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lilac;
+
+    /// 1 kHz mono stream alternating loud and silent stretches, given in milliseconds
+    fn stream(stretches: &[(u64, bool)]) -> Lilac {
+        let mut samples = Vec::new();
+        for &(ms, loud) in stretches {
+            let level = if loud { 10_000 } else { 3 };
+            samples.extend((0..ms).map(|i| if i % 2 == 0 { level } else { -level }));
+        }
+        lilac(1, 1000, 16, samples)
+    }
+
+    #[test]
+    fn finds_long_silences() {
+        let l = stream(&[
+            (100, true),
+            (500, false),
+            (100, true),
+            (50, false),
+            (100, true),
+        ]);
+        assert_eq!(
+            l.silences(-60.0, Duration::from_millis(200)),
+            vec![100..600]
+        );
+        assert_eq!(
+            l.silences(-60.0, Duration::from_millis(10)),
+            vec![100..600, 700..750]
+        );
+        // Everything is silent at a high enough threshold
+        assert_eq!(l.silences(0.0, Duration::from_millis(10)), vec![0..850]);
+    }
+
+    #[test]
+    fn trims_both_ends() {
+        let mut l = stream(&[
+            (30, false),
+            (100, true),
+            (20, false),
+            (100, true),
+            (40, false),
+        ]);
+        l.replay_gain.track_peak = Some(0.3);
+        l.trim_silence(-60.0);
+        assert_eq!(l.frames(), 220);
+        assert_eq!(l.replay_gain.track_peak, None);
+
+        let mut silent = stream(&[(100, false)]);
+        silent.trim_silence(-60.0);
+        assert_eq!(silent.frames(), 0);
+    }
+
+    #[test]
+    fn splits_between_silences() {
+        let l = stream(&[
+            (20, false),
+            (100, true),
+            (300, false),
+            (200, true),
+            (50, false),
+            (100, true),
+            (300, false),
+        ]);
+        let parts = l.split_on_silence(-60.0, Duration::from_millis(100));
+        let frames: Vec<usize> = parts.iter().map(Lilac::frames).collect();
+        assert_eq!(frames, vec![100, 350]);
+        assert!(stream(&[(500, false)])
+            .split_on_silence(-60.0, Duration::from_millis(100))
+            .is_empty());
+    }
+}